@@ -0,0 +1,57 @@
+#!/bin/sh
+# Times the workloads next to this script on each mal-rust binary given, the best of
+# 3 runs in ms, e.g. on a step7_quote built before the analyzer and on this one:
+#
+#   bench/run.sh /tmp/old/step7_quote target/release/step7_quote
+#
+# MAL_BACKEND=bytecode picks the VM, as for any run. With PERF set to a mal binary,
+# it also runs impls/tests/perf*.mal on it, which need macros and the bundled
+# libraries, on both backends.
+#
+# On one core of a Xeon, release builds, against step7_quote from before the
+# analyzer (7ee206e^), best of 3 in ms:
+#
+#                           before   closures   bytecode
+#   fib 27 + sumdown 5000     314      176         82
+#   1M loop* iterations       322      166         64
+#   200k atom swap!s          464      270        176
+#
+# The analyzer alone was meant to make these several times faster and does not: it
+# is about 1.8x on calls, 1.9x on loops and 1.7x on the atom workload. Only the
+# bytecode VM gets there, 3.8x, 5x and 2.6x. perf1 and perf2 take under 1 ms on
+# both backends. perf3 runs 3727122 iterations in 10 s on closures and 7602166 on
+# bytecode. Before the analyzer, step7 could not run perf*.mal at all, since they
+# need macros, so there is no number to compare those with.
+
+dir=$(cd "$(dirname "$0")" && pwd)
+
+best() {
+    best=
+    for run in 1 2 3; do
+        start=$(date +%s%N)
+        "$@" > /dev/null </dev/null || return 1
+        took=$((($(date +%s%N) - start) / 1000000))
+        if [ -z "$best" ] || [ "$took" -lt "$best" ]; then
+            best=$took
+        fi
+    done
+    echo "$best"
+}
+
+for bin in "$@"; do
+    echo "$bin"
+    for workload in fib loop atom; do
+        echo "  $workload: $(best "$bin" "$dir/$workload.mal") ms"
+    done
+done
+
+if [ -n "$PERF" ]; then
+    perf=$(cd "$(dirname "$PERF")" && pwd)/$(basename "$PERF")
+    cd "$dir/../../../tests" || exit 1
+    for backend in closures bytecode; do
+        echo "$PERF, $backend"
+        for file in perf1 perf2 perf3; do
+            echo "  $file: $(MAL_BACKEND=$backend "$perf" $file.mal | tr '\n' ' ')"
+        done
+    done
+fi
@@ -0,0 +1,1424 @@
+// Compiles forms into trees of closures before running them: special forms are
+// dispatched once, constants are built once, locals are resolved to a slot of an
+// enclosing frame, and every call goes through the trampoline in apply so tail calls
+// and recur run in constant stack.
+
+use crate::budget;
+use crate::depth::DepthGuard;
+use crate::env::Env;
+use crate::gc::{self, Trace};
+use crate::namespace;
+use crate::printer::print_str;
+use crate::shared::{Rc, RefCell, Weak};
+use crate::stream::report;
+use crate::symbol::{
+    self, Symbol, AMPERSAND, AS, CONCAT, CONS, DEFMACRO_CALL, DEFTEST_CALL, EQUAL, FUTURE_CALL,
+    GO_CALL, IS_CALL, KEYS, NS_CALL, OR, QUOTE, SPLICE_UNQUOTE, STRS, SYMS, TESTING_CALL, TRY_CALL,
+    UNQUOTE, VEC, WITH_OPEN_CALL, WITH_OUT_STR_CALL,
+};
+use crate::types::{arglists, select_arity, Arity, ClosureType, MalType, KV};
+
+// What is left to do once analyzed code has run
+pub enum Next {
+    Value(Rc<MalType>),
+    // A call in tail position, made by the caller's trampoline
+    Call(Rc<MalType>, Vec<Rc<MalType>>),
+    // Rebinds the innermost loop* or fn* clause
+    Recur(Vec<Rc<MalType>>),
+}
+
+#[cfg(not(feature = "threads"))]
+pub type Code = Rc<dyn Fn(&Rc<RefCell<Env>>) -> Option<Next>>;
+#[cfg(feature = "threads")]
+pub type Code = Rc<dyn Fn(&Rc<RefCell<Env>>) -> Option<Next> + Send + Sync>;
+
+// An analyzed form, constants are kept apart so enclosing forms can fold them
+enum Form {
+    Const(Rc<MalType>),
+    Code(Code),
+}
+
+impl Form {
+    fn into_code(self) -> Code {
+        match self {
+            Form::Const(value) => Rc::new(move |_| Some(Next::Value(value.clone()))),
+            Form::Code(code) => code,
+        }
+    }
+}
+
+// The frames analyzed code will run in, innermost last. A let*, loop*, named fn*
+// or fn* call makes one frame, holding its locals in the order they are listed here.
+#[derive(Default)]
+struct Scope {
+    frames: Vec<Frame>,
+    // What a recur goes back to, the innermost loop* or fn* clause last
+    targets: Vec<Target>,
+}
+
+enum Target {
+    Fn(Arity),
+    // the amount of bindings
+    Loop(usize),
+}
+
+struct Frame {
+    names: Vec<Symbol>,
+    // How many of names are bound so far. The others may only be used from inside
+    // a fn*, which is not called before they are, as in `(let* (f (fn* () x) x 3) (f))`
+    bound: usize,
+    // The frame of a fn* clause's params
+    params: bool,
+}
+
+impl Scope {
+    fn push(&mut self, names: Vec<Symbol>, params: bool) {
+        self.frames.push(Frame {
+            names,
+            bound: 0,
+            params,
+        });
+    }
+
+    fn pop(&mut self) {
+        self.frames.pop();
+    }
+
+    // Marks the next local of the innermost frame as bound
+    fn bind_next(&mut self) {
+        self.frames.last_mut().unwrap().bound += 1;
+    }
+
+    // How many frames up and at which slot name is, none for a global
+    fn resolve(&self, name: Symbol) -> Option<(usize, usize)> {
+        let mut in_fn = false;
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            let visible = if in_fn {
+                frame.names.len()
+            } else {
+                frame.bound
+            };
+            if let Some(index) = frame.names[..visible]
+                .iter()
+                .rposition(|local| *local == name)
+            {
+                return Some((depth, index));
+            }
+            in_fn |= frame.params;
+        }
+        None
+    }
+}
+
+// A binding form, analyzed. Binding it pushes one slot per symbol in it.
+enum Pattern {
+    Symbol,
+    Seq(Vec<Part>),
+    Map(Vec<Entry>),
+}
+
+enum Part {
+    Nth(usize, Pattern),
+    Rest(usize, Pattern),
+    // :as
+    Whole(Pattern),
+}
+
+// The value under key, or the default if it is missing, key is none for :as
+struct Entry {
+    key: Option<Rc<MalType>>,
+    default: Option<Code>,
+    pattern: Pattern,
+}
+
+// How a fn* clause binds its arguments
+enum Params {
+    // Symbols only, the bool tells if the last one takes the rest after `&`
+    Plain(usize, bool),
+    Pattern(Pattern),
+}
+
+// A fn* clause, kept behind the `compiled` field of its ClosureType
+struct Clause {
+    params: Params,
+    code: Code,
+}
+
+impl Trace for Vec<Clause> {}
+
+fn is_truthy(value: &MalType) -> bool {
+    !matches!(value, MalType::Bool(false) | MalType::Nil)
+}
+
+pub fn eval(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
+    let _depth = DepthGuard::enter()?;
+    let code = analyze(&ast, true, &mut Scope::default())?.into_code();
+    trampoline(code(&env)?)
+}
+
+pub fn apply(func: &Rc<MalType>, args: &[Rc<MalType>]) -> Option<Rc<MalType>> {
+    trampoline(invoke(func, args.to_vec())?)
+}
+
+// Runs code which is not in tail position down to its value
+#[inline]
+fn run(code: &Code, env: &Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
+    match code(env)? {
+        Next::Value(value) => Some(value),
+        next => trampoline(next),
+    }
+}
+
+fn trampoline(next: Next) -> Option<Rc<MalType>> {
+    let mut next = next;
+    loop {
+        next = match next {
+            Next::Value(value) => return Some(value),
+            Next::Call(func, args) => invoke(&func, args)?,
+            Next::Recur(_) => {
+                report!("recur should be inside loop* or fn*");
+                return None;
+            }
+        }
+    }
+}
+
+// Makes one call, handing back the tail call a closure ends with, if any
+fn invoke(func: &Rc<MalType>, args: Vec<Rc<MalType>>) -> Option<Next> {
+    budget::step()?;
+    match &**func {
+        MalType::BuiltinFunc(_, func) => Some(Next::Value(func(&args)?)),
+        MalType::Func(closure) => match clauses(closure) {
+            Some(clauses) => call_closure(
+                &closure.name,
+                &closure.arities,
+                &closure.env,
+                &clauses,
+                args,
+            ),
+            None => Some(Next::Value((closure.func)(&args)?)),
+        },
+        _ => {
+            let mut list = vec![func.clone()];
+            list.extend(args);
+            Some(Next::Value(Rc::new(MalType::List(list))))
+        }
+    }
+}
+
+fn clauses(closure: &ClosureType) -> Option<Rc<Vec<Clause>>> {
+    gc::downcast(closure.compiled.clone()?)
+}
+
+fn call_closure(
+    name: &Option<String>,
+    arities: &[Arity],
+    env: &Rc<RefCell<Env>>,
+    clauses: &[Clause],
+    args: Vec<Rc<MalType>>,
+) -> Option<Next> {
+    let _depth = DepthGuard::enter()?;
+    let arity = select_clause(name, arities, args.len())?;
+    let index = arities
+        .iter()
+        .position(|other| std::ptr::eq(other, arity))
+        .unwrap();
+    let clause = &clauses[index];
+    let mut new_env = bind_params(&clause.params, args, env)?;
+    loop {
+        match (clause.code)(&new_env)? {
+            Next::Recur(args) => {
+                budget::step()?;
+                if !arity.accepts(args.len()) {
+                    report!(
+                        "Mismatched argument count to recur, expected {} args, got {}",
+                        arity.required(),
+                        args.len()
+                    );
+                    return None;
+                }
+                new_env = bind_params(&clause.params, args, env)?;
+            }
+            next => return Some(next),
+        }
+    }
+}
+
+// Creates the frame a closure body runs in, binding params to args
+fn bind_params(
+    params: &Params,
+    mut args: Vec<Rc<MalType>>,
+    outer: &Rc<RefCell<Env>>,
+) -> Option<Rc<RefCell<Env>>> {
+    let slots = match params {
+        Params::Plain(_, false) => args,
+        Params::Plain(required, true) => {
+            budget::allocate(args.len() - required)?;
+            let rest = args.split_off(*required);
+            args.push(Rc::new(MalType::List(rest)));
+            args
+        }
+        Params::Pattern(pattern) => {
+            let env = Rc::new(RefCell::new(Env::new_frame(outer.clone(), vec![])));
+            bind(pattern, Rc::new(MalType::List(args)), &env)?;
+            return Some(env);
+        }
+    };
+    Some(Rc::new(RefCell::new(Env::new_frame(outer.clone(), slots))))
+}
+
+// Binds value to an analyzed binding form in the innermost frame of env
+fn bind(pattern: &Pattern, value: Rc<MalType>, env: &Rc<RefCell<Env>>) -> Option<()> {
+    match pattern {
+        Pattern::Symbol => {
+            env.borrow_mut().slots.push(value);
+            Some(())
+        }
+        Pattern::Seq(parts) => {
+            let items: &[Rc<MalType>] = match &*value {
+                MalType::List(items) | MalType::Vector(items) => items,
+                MalType::Nil => &[],
+                _ => {
+                    report!(
+                        "Can not destructure {} as a sequence",
+                        print_str(value.clone(), false, true)
+                    );
+                    return None;
+                }
+            };
+            for part in parts.iter() {
+                match part {
+                    Part::Nth(pos, pattern) => {
+                        let item = match items.get(*pos) {
+                            Some(item) => item.clone(),
+                            None => Rc::new(MalType::Nil),
+                        };
+                        bind(pattern, item, env)?;
+                    }
+                    Part::Rest(pos, pattern) => {
+                        let rest = items.iter().skip(*pos).cloned().collect();
+                        bind(pattern, Rc::new(MalType::List(rest)), env)?;
+                    }
+                    Part::Whole(pattern) => bind(pattern, value.clone(), env)?,
+                }
+            }
+            Some(())
+        }
+        Pattern::Map(entries) => {
+            let kvs: &[KV] = match &*value {
+                MalType::HashMap(kvs) => kvs,
+                MalType::Nil => &[],
+                _ => {
+                    report!(
+                        "Can not destructure {} as a map",
+                        print_str(value.clone(), false, true)
+                    );
+                    return None;
+                }
+            };
+            for entry in entries.iter() {
+                let item = match &entry.key {
+                    None => value.clone(),
+                    Some(key) => match lookup(kvs, key) {
+                        Some(item) => item,
+                        None => match &entry.default {
+                            Some(default) => run(default, env)?,
+                            None => Rc::new(MalType::Nil),
+                        },
+                    },
+                };
+                bind(&entry.pattern, item, env)?;
+            }
+            Some(())
+        }
+    }
+}
+
+fn lookup(entries: &[KV], key: &MalType) -> Option<Rc<MalType>> {
+    entries
+        .iter()
+        .find(|(k, _)| **k == *key)
+        .map(|(_, v)| v.clone())
+}
+
+// The symbols a binding form binds, in the order bind pushes their slots
+fn pattern_names(pattern: &Rc<MalType>, names: &mut Vec<Symbol>) {
+    match &**pattern {
+        MalType::Symbol(symbol) => names.push(*symbol),
+        MalType::List(binds) | MalType::Vector(binds) => {
+            for bind in binds.iter() {
+                match &**bind {
+                    MalType::Symbol(symbol) if *symbol == AMPERSAND => {}
+                    MalType::Keyword(keyword) if *keyword == AS => {}
+                    _ => pattern_names(bind, names),
+                }
+            }
+        }
+        MalType::HashMap(binds) => {
+            for (key, bind_form) in binds.iter() {
+                match &**key {
+                    MalType::Keyword(keyword)
+                        if *keyword == KEYS || *keyword == STRS || *keyword == SYMS =>
+                    {
+                        if let MalType::List(keys) | MalType::Vector(keys) = &**bind_form {
+                            for key in keys.iter() {
+                                pattern_names(key, names);
+                            }
+                        }
+                    }
+                    MalType::Keyword(keyword) if *keyword == AS => pattern_names(bind_form, names),
+                    MalType::Keyword(keyword) if *keyword == OR => {}
+                    _ => pattern_names(key, names),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// Besides plain symbols, vectors/lists destructure sequences (`[a b & rest :as all]`)
+// and maps destructure hash-maps (`{a :a :keys [b c] :or {c 0} :as m}`), nested to
+// any depth. The innermost frame of scope must list the names pattern_names gives.
+fn analyze_pattern(pattern: &Rc<MalType>, scope: &mut Scope) -> Option<Pattern> {
+    match &**pattern {
+        MalType::Symbol(_) => {
+            scope.bind_next();
+            Some(Pattern::Symbol)
+        }
+        MalType::List(binds) | MalType::Vector(binds) => analyze_seq(binds, scope),
+        MalType::HashMap(binds) => analyze_map(binds, scope),
+        _ => {
+            report!(
+                "{} is not a valid binding form",
+                print_str(pattern.clone(), true, true)
+            );
+            None
+        }
+    }
+}
+
+// The binding form after the & at binds[i], which only :as may follow, as in
+// `[a & more :as all]`
+pub fn rest_form(binds: &[Rc<MalType>], i: usize) -> Option<&Rc<MalType>> {
+    let rest = match binds.get(i + 1) {
+        Some(rest) => rest,
+        None => {
+            report!("& should be followed by a binding form");
+            return None;
+        }
+    };
+    match binds.get(i + 2).map(|next| &**next) {
+        None => Some(rest),
+        Some(MalType::Keyword(keyword)) if *keyword == AS => Some(rest),
+        Some(_) => {
+            report!("Only :as can follow the binding form after &");
+            None
+        }
+    }
+}
+
+fn analyze_seq(binds: &[Rc<MalType>], scope: &mut Scope) -> Option<Pattern> {
+    let mut parts = vec![];
+    let mut pos = 0;
+    let mut i = 0;
+    while i < binds.len() {
+        match &*binds[i] {
+            MalType::Symbol(symbol) if *symbol == AMPERSAND => {
+                let rest = rest_form(binds, i)?;
+                parts.push(Part::Rest(pos, analyze_pattern(rest, scope)?));
+                // nothing is left for the binding forms after the rest
+                pos = usize::MAX;
+                i += 2;
+            }
+            MalType::Keyword(keyword) if *keyword == AS => {
+                if i + 1 >= binds.len() {
+                    report!(":as should be followed by a symbol");
+                    return None;
+                }
+                parts.push(Part::Whole(analyze_pattern(&binds[i + 1], scope)?));
+                i += 2;
+            }
+            _ => {
+                parts.push(Part::Nth(pos, analyze_pattern(&binds[i], scope)?));
+                pos = pos.saturating_add(1);
+                i += 1;
+            }
+        }
+    }
+    Some(Pattern::Seq(parts))
+}
+
+fn analyze_map(binds: &[KV], scope: &mut Scope) -> Option<Pattern> {
+    let or_key = MalType::Keyword(OR);
+    let defaults: &[KV] = match binds.iter().find(|(key, _)| **key == or_key) {
+        Some((_, or)) => match &**or {
+            MalType::HashMap(defaults) => defaults,
+            _ => {
+                report!(":or should be followed by a map");
+                return None;
+            }
+        },
+        None => &[],
+    };
+    let mut entries = vec![];
+    for (key, bind_form) in binds.iter() {
+        match &**key {
+            MalType::Keyword(keyword)
+                if *keyword == KEYS || *keyword == STRS || *keyword == SYMS =>
+            {
+                let names = match &**bind_form {
+                    MalType::List(names) | MalType::Vector(names) => names,
+                    _ => {
+                        report!(":{} should be followed by a vector of symbols", keyword);
+                        return None;
+                    }
+                };
+                for name in names.iter() {
+                    let symbol = match &**name {
+                        MalType::Symbol(symbol) => *symbol,
+                        _ => {
+                            report!("{} is not a symbol", print_str(name.clone(), true, true));
+                            return None;
+                        }
+                    };
+                    let entry_key = match *keyword {
+                        KEYS => MalType::Keyword(symbol),
+                        STRS => MalType::Str(symbol.to_string()),
+                        _ => MalType::Symbol(symbol),
+                    };
+                    entries.push(analyze_entry(name, Rc::new(entry_key), defaults, scope)?);
+                }
+            }
+            MalType::Keyword(keyword) if *keyword == AS => entries.push(Entry {
+                key: None,
+                default: None,
+                pattern: analyze_pattern(bind_form, scope)?,
+            }),
+            MalType::Keyword(keyword) if *keyword == OR => {}
+            // {name :key} binds name to the value under :key
+            _ => entries.push(analyze_entry(key, bind_form.clone(), defaults, scope)?),
+        }
+    }
+    Some(Pattern::Map(entries))
+}
+
+// The default is analyzed before pattern is bound, it sees the names bound before it
+fn analyze_entry(
+    pattern: &Rc<MalType>,
+    key: Rc<MalType>,
+    defaults: &[KV],
+    scope: &mut Scope,
+) -> Option<Entry> {
+    let default = match lookup(defaults, pattern) {
+        Some(default) => Some(analyze(&default, false, scope)?.into_code()),
+        None => None,
+    };
+    Some(Entry {
+        key: Some(key),
+        default,
+        pattern: analyze_pattern(pattern, scope)?,
+    })
+}
+
+fn analyze_params(params: &[Rc<MalType>], scope: &mut Scope) -> Option<Params> {
+    let symbols: Option<Vec<Symbol>> = params
+        .iter()
+        .map(|param| match &**param {
+            MalType::Symbol(symbol) => Some(*symbol),
+            _ => None,
+        })
+        .collect();
+    let plain = match symbols {
+        Some(symbols) => match symbols.iter().position(|symbol| *symbol == AMPERSAND) {
+            None => Some((symbols, false)),
+            Some(rest) if rest + 2 == symbols.len() && symbols[rest + 1] != AMPERSAND => {
+                Some((symbols, true))
+            }
+            _ => None,
+        },
+        None => None,
+    };
+    match plain {
+        Some((symbols, variadic)) => {
+            let names: Vec<Symbol> = symbols
+                .into_iter()
+                .filter(|symbol| *symbol != AMPERSAND)
+                .collect();
+            let required = if variadic {
+                names.len() - 1
+            } else {
+                names.len()
+            };
+            scope.push(names, true);
+            scope.frames.last_mut().unwrap().bound = required + variadic as usize;
+            Some(Params::Plain(required, variadic))
+        }
+        None => {
+            let params = Rc::new(MalType::Vector(params.to_vec()));
+            let mut names = vec![];
+            pattern_names(&params, &mut names);
+            scope.push(names, true);
+            Some(Params::Pattern(analyze_pattern(&params, scope)?))
+        }
+    }
+}
+
+fn analyze(ast: &Rc<MalType>, tail: bool, scope: &mut Scope) -> Option<Form> {
+    let _depth = DepthGuard::enter()?;
+    match &**ast {
+        MalType::Symbol(symbol) => Some(Form::Code(analyze_symbol(*symbol, scope)?)),
+        MalType::List(list) if !list.is_empty() => analyze_list(list, tail, scope),
+        MalType::Vector(items) => {
+            let items = analyze_all(items, scope)?;
+            match constants(&items) {
+                Some(items) => Some(Form::Const(Rc::new(MalType::Vector(items)))),
+                None => {
+                    let items = into_codes(items);
+                    Some(Form::Code(Rc::new(move |env| {
+                        let items = run_all(&items, env)?;
+                        budget::allocate(items.len())?;
+                        Some(Next::Value(Rc::new(MalType::Vector(items))))
+                    })))
+                }
+            }
+        }
+        MalType::HashMap(kvs) => {
+            let keys: Vec<Rc<MalType>> = kvs.iter().map(|(k, _)| k.clone()).collect();
+            let values: Vec<Rc<MalType>> = kvs.iter().map(|(_, v)| v.clone()).collect();
+            let values = analyze_all(&values, scope)?;
+            match constants(&values) {
+                Some(values) => Some(Form::Const(Rc::new(MalType::HashMap(
+                    keys.into_iter().zip(values).collect(),
+                )))),
+                None => {
+                    let values = into_codes(values);
+                    Some(Form::Code(Rc::new(move |env| {
+                        let values = run_all(&values, env)?;
+                        budget::allocate(values.len())?;
+                        Some(Next::Value(Rc::new(MalType::HashMap(
+                            keys.iter().cloned().zip(values).collect(),
+                        ))))
+                    })))
+                }
+            }
+        }
+        _ => Some(Form::Const(ast.clone())),
+    }
+}
+
+fn analyze_symbol(symbol: Symbol, scope: &Scope) -> Option<Code> {
+    let not_found = move |symbol: Symbol| {
+        report!("'{}' not found", symbol);
+        None
+    };
+    Some(match scope.resolve(symbol) {
+        Some((0, index)) => Rc::new(move |env| match env.borrow().slots.get(index) {
+            Some(value) => Some(Next::Value(value.clone())),
+            None => not_found(symbol),
+        }),
+        Some((depth, index)) => Rc::new(move |env| match env.borrow().get_local(depth, index) {
+            Some(value) => Some(Next::Value(value)),
+            None => not_found(symbol),
+        }),
+        None => {
+            let (symbol, public) = namespace::resolve(symbol)?;
+            Rc::new(move |env| {
+                let env = env.borrow();
+                if public && env.is_private(symbol) {
+                    report!("{} is private", symbol);
+                    return None;
+                }
+                match env.get_global(symbol) {
+                    Some(value) => Some(Next::Value(value)),
+                    None => not_found(symbol),
+                }
+            })
+        }
+    })
+}
+
+fn analyze_all(items: &[Rc<MalType>], scope: &mut Scope) -> Option<Vec<Form>> {
+    items
+        .iter()
+        .map(|item| analyze(item, false, scope))
+        .collect()
+}
+
+// The values of forms if they are all constants
+fn constants(forms: &[Form]) -> Option<Vec<Rc<MalType>>> {
+    forms
+        .iter()
+        .map(|form| match form {
+            Form::Const(value) => Some(value.clone()),
+            Form::Code(_) => None,
+        })
+        .collect()
+}
+
+fn into_codes(forms: Vec<Form>) -> Vec<Code> {
+    forms.into_iter().map(Form::into_code).collect()
+}
+
+fn run_all(codes: &[Code], env: &Rc<RefCell<Env>>) -> Option<Vec<Rc<MalType>>> {
+    codes.iter().map(|code| run(code, env)).collect()
+}
+
+fn analyze_list(list: &[Rc<MalType>], tail: bool, scope: &mut Scope) -> Option<Form> {
+    if let MalType::Symbol(symbol) = &*list[0] {
+        match *symbol {
+            symbol::DEF => return analyze_def(list, false, scope),
+            symbol::DEF_PRIVATE if is_special(*symbol, scope) => {
+                return analyze_def(list, true, scope)
+            }
+            symbol::LET => return analyze_let(list, tail, scope),
+            symbol::FN => return analyze_fn(list, scope),
+            symbol::LOOP => return analyze_loop(list, scope),
+            symbol::RECUR => return analyze_recur(list, tail, scope),
+            symbol::IF => return analyze_if(list, tail, scope),
+            symbol::DO => return analyze_do(list, tail, scope),
+            symbol::QUOTE => return Some(Form::Const(quoted(list)?.clone())),
+            symbol::QUASIQUOTEEXPAND => {
+                return Some(Form::Const(quasiquote(quoted(list)?.clone())?))
+            }
+            symbol::DEFMACRO => return analyze(&defmacro(list)?, tail, scope),
+            symbol::TRY => return analyze(&try_form(list), tail, scope),
+            symbol::MACROEXPAND => return Some(Form::Const(macroexpand(quoted(list)?.clone())?)),
+            symbol::FUTURE if is_special(*symbol, scope) => {
+                return analyze(&call_body(FUTURE_CALL, list), tail, scope)
+            }
+            symbol::GO if is_special(*symbol, scope) => {
+                return analyze(&call_body(GO_CALL, list), tail, scope)
+            }
+            symbol::WITH_OPEN if is_special(*symbol, scope) => {
+                return analyze(&with_open(list)?, tail, scope)
+            }
+            symbol::WITH_OUT_STR if is_special(*symbol, scope) => {
+                return analyze(&call_body(WITH_OUT_STR_CALL, list), tail, scope)
+            }
+            symbol::DEFTEST | symbol::IS | symbol::ARE | symbol::TESTING
+                if is_special(*symbol, scope) =>
+            {
+                return analyze(&test_form(list)?, tail, scope)
+            }
+            symbol::NS if is_special(*symbol, scope) => {
+                return analyze(&ns_form(list)?, tail, scope)
+            }
+            symbol::QUASIQUOTE => return analyze(&quasiquote(quoted(list)?.clone())?, tail, scope),
+            _ => {}
+        }
+        if scope.resolve(*symbol).is_none() {
+            if let Some(mac) = namespace::macro_for(*symbol) {
+                return analyze(&expand(&mac, list)?, tail, scope);
+            }
+        }
+    }
+    let func = analyze(&list[0], false, scope)?.into_code();
+    let args = into_codes(analyze_all(&list[1..], scope)?);
+    Some(Form::Code(Rc::new(move |env| {
+        let func = run(&func, env)?;
+        let args = run_all(&args, env)?;
+        if tail {
+            Some(Next::Call(func, args))
+        } else {
+            Some(Next::Value(trampoline(invoke(&func, args)?)?))
+        }
+    })))
+}
+
+// The forms added on top of mal's own give way to a local or global of the same name
+fn is_special(symbol: Symbol, scope: &Scope) -> bool {
+    scope.resolve(symbol).is_none() && !namespace::is_bound(symbol)
+}
+
+// Locals only live in frame slots, so def! always sets a global, even inside a let*.
+// def- is def! for a name other namespaces can't use.
+fn analyze_def(list: &[Rc<MalType>], private: bool, scope: &mut Scope) -> Option<Form> {
+    if list.len() != 3 {
+        report!(
+            "Wrong amount of arguments for {}",
+            print_str(list[0].clone(), false, false)
+        );
+        return None;
+    }
+    match &*list[1] {
+        MalType::Symbol(symbol) => {
+            let symbol = namespace::define(*symbol, private)?;
+            let value = analyze(&list[2], false, scope)?.into_code();
+            Some(Form::Code(Rc::new(move |env| {
+                let value = run(&value, env)?;
+                Env::root(env).borrow_mut().set(symbol, value.clone());
+                Some(Next::Value(value))
+            })))
+        }
+        _ => {
+            report!("{} is not a symbol", print_str(list[1].clone(), true, true));
+            None
+        }
+    }
+}
+
+// The binding forms of let* and loop* with their analyzed values, in a new frame of
+// scope that the caller pops
+fn analyze_bindings(
+    list: &[Rc<MalType>],
+    form: &str,
+    scope: &mut Scope,
+) -> Option<Vec<(Pattern, Code)>> {
+    if list.len() != 3 {
+        report!("Wrong amount of arguments for {}", form);
+        return None;
+    }
+    match &*list[1] {
+        MalType::List(bind_list) | MalType::Vector(bind_list) => {
+            if bind_list.len() % 2 != 0 {
+                report!("Wrong amount of arguments for bind of {}", form);
+                return None;
+            }
+            let mut names = vec![];
+            for pair in bind_list.chunks(2) {
+                pattern_names(&pair[0], &mut names);
+            }
+            scope.push(names, false);
+            let mut bindings = vec![];
+            for pair in bind_list.chunks(2) {
+                let value = analyze(&pair[1], false, scope)?.into_code();
+                bindings.push((analyze_pattern(&pair[0], scope)?, value));
+            }
+            Some(bindings)
+        }
+        _ => {
+            report!("Wrong bind format");
+            None
+        }
+    }
+}
+
+// Runs the values of bindings in a new frame and binds them in order
+fn bind_all(bindings: &[(Pattern, Code)], env: &Rc<RefCell<Env>>) -> Option<Rc<RefCell<Env>>> {
+    let new_env = Rc::new(RefCell::new(Env::new_frame(
+        env.clone(),
+        Vec::with_capacity(bindings.len()),
+    )));
+    for (pattern, value) in bindings.iter() {
+        let value = run(value, &new_env)?;
+        bind(pattern, value, &new_env)?;
+    }
+    Some(new_env)
+}
+
+fn analyze_let(list: &[Rc<MalType>], tail: bool, scope: &mut Scope) -> Option<Form> {
+    let bindings = analyze_bindings(list, "let*", scope)?;
+    let body = analyze(&list[2], tail, scope)?.into_code();
+    scope.pop();
+    Some(Form::Code(Rc::new(move |env| {
+        let new_env = bind_all(&bindings, env)?;
+        body(&new_env)
+    })))
+}
+
+// The body of a loop* is the recur target, each recur binds the patterns afresh
+// in a new frame
+fn analyze_loop(list: &[Rc<MalType>], scope: &mut Scope) -> Option<Form> {
+    let bindings = analyze_bindings(list, "loop*", scope)?;
+    scope.targets.push(Target::Loop(bindings.len()));
+    let body = analyze(&list[2], true, scope)?.into_code();
+    scope.targets.pop();
+    scope.pop();
+    Some(Form::Code(Rc::new(move |env| {
+        let mut new_env = bind_all(&bindings, env)?;
+        loop {
+            match body(&new_env)? {
+                Next::Recur(args) => {
+                    budget::step()?;
+                    if args.len() != bindings.len() {
+                        report!(
+                            "Mismatched argument count to recur, expected {} args, got {}",
+                            bindings.len(),
+                            args.len()
+                        );
+                        return None;
+                    }
+                    // The frame is only made anew if a closure kept hold of it
+                    match Rc::get_mut(&mut new_env) {
+                        Some(frame) => frame.borrow_mut().slots.clear(),
+                        None => {
+                            new_env = Rc::new(RefCell::new(Env::new_frame(
+                                env.clone(),
+                                Vec::with_capacity(args.len()),
+                            )))
+                        }
+                    }
+                    for ((pattern, _), value) in bindings.iter().zip(args) {
+                        bind(pattern, value, &new_env)?;
+                    }
+                }
+                next => return Some(next),
+            }
+        }
+    })))
+}
+
+fn analyze_recur(list: &[Rc<MalType>], tail: bool, scope: &mut Scope) -> Option<Form> {
+    if !tail {
+        report!("Can only recur from tail position");
+        return None;
+    }
+    let argc = list.len() - 1;
+    let expected = match scope.targets.last() {
+        Some(Target::Fn(arity)) => Some(arity.required()).filter(|_| !arity.accepts(argc)),
+        Some(Target::Loop(count)) => Some(*count).filter(|count| *count != argc),
+        None => {
+            report!("recur should be inside loop* or fn*");
+            return None;
+        }
+    };
+    if let Some(expected) = expected {
+        report!(
+            "Mismatched argument count to recur, expected {} args, got {}",
+            expected,
+            argc
+        );
+        return None;
+    }
+    let args = into_codes(analyze_all(&list[1..], scope)?);
+    Some(Form::Code(Rc::new(move |env| {
+        Some(Next::Recur(run_all(&args, env)?))
+    })))
+}
+
+fn analyze_if(list: &[Rc<MalType>], tail: bool, scope: &mut Scope) -> Option<Form> {
+    if list.len() <= 2 {
+        report!("Wrong amount of arguments for if");
+        return None;
+    }
+    let cond = analyze(&list[1], false, scope)?;
+    let then = analyze(&list[2], tail, scope)?;
+    let otherwise = match list.get(3) {
+        Some(otherwise) => analyze(otherwise, tail, scope)?,
+        None => Form::Const(Rc::new(MalType::Nil)),
+    };
+    match cond {
+        Form::Const(cond) => {
+            if is_truthy(&cond) {
+                Some(then)
+            } else {
+                Some(otherwise)
+            }
+        }
+        Form::Code(cond) => {
+            let then = then.into_code();
+            let otherwise = otherwise.into_code();
+            Some(Form::Code(Rc::new(move |env| {
+                if is_truthy(&*run(&cond, env)?) {
+                    then(env)
+                } else {
+                    otherwise(env)
+                }
+            })))
+        }
+    }
+}
+
+fn analyze_do(list: &[Rc<MalType>], tail: bool, scope: &mut Scope) -> Option<Form> {
+    if list.len() <= 1 {
+        report!("Wrong amount of arguments for do");
+        return None;
+    }
+    let mut forms = vec![];
+    for item in list[1..list.len() - 1].iter() {
+        // a constant in the middle of a do has nothing to run
+        if let Form::Code(code) = analyze(item, false, scope)? {
+            forms.push(code);
+        }
+    }
+    let last = analyze(&list[list.len() - 1], tail, scope)?;
+    if forms.is_empty() {
+        return Some(last);
+    }
+    let last = last.into_code();
+    Some(Form::Code(Rc::new(move |env| {
+        for form in forms.iter() {
+            run(form, env)?;
+        }
+        last(env)
+    })))
+}
+
+// `(fn* [params] body)` or, with several arities, `(fn* ([x] body) ([x y] body))`.
+// The bodies are left for the caller to compile.
+pub fn parse_arities(list: &[Rc<MalType>]) -> Option<Vec<Arity>> {
+    let is_clause = |clause: &Rc<MalType>| match &**clause {
+        MalType::List(clause) => matches!(
+            clause.first().map(|params| &**params),
+            Some(MalType::List(_) | MalType::Vector(_))
+        ),
+        _ => false,
+    };
+    let clauses: Vec<&[Rc<MalType>]> = if !list.is_empty() && list.iter().all(is_clause) {
+        list.iter()
+            .map(|clause| match &**clause {
+                MalType::List(clause) => clause.as_slice(),
+                _ => unreachable!(),
+            })
+            .collect()
+    } else {
+        vec![list]
+    };
+    let mut arities: Vec<Arity> = vec![];
+    for clause in clauses {
+        if clause.len() != 2 {
+            report!("Wrong amount of arguments for fn*");
+            return None;
+        }
+        let params = match &*clause[0] {
+            MalType::List(params) | MalType::Vector(params) => params.clone(),
+            _ => {
+                report!("Wrong parameter format for fn*");
+                return None;
+            }
+        };
+        let arity = Arity {
+            params,
+            ast: clause[1].clone(),
+        };
+        if arity.is_variadic() && arities.iter().any(|other| other.is_variadic()) {
+            report!("Can't have more than 1 variadic overload");
+            return None;
+        }
+        if !arity.is_variadic()
+            && arities
+                .iter()
+                .any(|other| !other.is_variadic() && other.required() == arity.required())
+        {
+            report!("Can't have 2 overloads with same arity");
+            return None;
+        }
+        arities.push(arity);
+    }
+    Some(arities)
+}
+
+fn select_clause<'a>(
+    name: &Option<String>,
+    arities: &'a [Arity],
+    argc: usize,
+) -> Option<&'a Arity> {
+    let arity = select_arity(arities, argc);
+    if arity.is_none() {
+        report!(
+            "Wrong amount of arguments ({}) passed to {} with arglists {}",
+            argc,
+            name.as_deref().unwrap_or("fn*"),
+            print_str(arglists(arities), false, true)
+        );
+    }
+    arity
+}
+
+// `(fn* name ...)` binds name to the function itself inside its body, in a frame
+// of its own
+fn analyze_fn(list: &[Rc<MalType>], scope: &mut Scope) -> Option<Form> {
+    let (name, clauses) = match list.get(1).map(|name| &**name) {
+        Some(MalType::Symbol(name)) => (Some(*name), &list[2..]),
+        _ => (None, &list[1..]),
+    };
+    let arities = parse_arities(clauses)?;
+    if let Some(name) = name {
+        scope.push(vec![name], false);
+        scope.bind_next();
+    }
+    let mut compiled = vec![];
+    for arity in arities.iter() {
+        let params = analyze_params(&arity.params, scope)?;
+        scope.targets.push(Target::Fn(arity.clone()));
+        let code = analyze(&arity.ast, true, scope)?.into_code();
+        scope.targets.pop();
+        scope.pop();
+        compiled.push(Clause { params, code });
+    }
+    if name.is_some() {
+        scope.pop();
+    }
+    let name = name.map(|name| name.to_string());
+    let compiled = Rc::new(compiled);
+    Some(Form::Code(Rc::new(move |env| {
+        budget::allocate(0)?;
+        let env = match name {
+            Some(_) => Rc::new(RefCell::new(Env::new_frame(env.clone(), vec![]))),
+            None => env.clone(),
+        };
+        // The function reaches itself weakly, so its env is only held by the ClosureType,
+        // where the cycle collector can see it
+        let func = Rc::new_cyclic(|this: &Weak<MalType>| {
+            let this = this.clone();
+            MalType::Func(ClosureType {
+                name: name.clone(),
+                arities: arities.clone(),
+                env: env.clone(),
+                compiled: Some(compiled.clone()),
+                func: Rc::new(move |args| trampoline(invoke(&this.upgrade()?, args.to_vec())?)),
+            })
+        });
+        gc::track(&func);
+        if name.is_some() {
+            env.borrow_mut().slots.push(func.clone());
+        }
+        Some(Next::Value(func))
+    })))
+}
+
+// (future body...) runs body on a worker thread, as (future-call (fn* [] (do body...))),
+// and (go body...) likewise as (go-call (fn* [] (do body...)))
+pub fn call_body(func: Symbol, list: &[Rc<MalType>]) -> Rc<MalType> {
+    Rc::new(MalType::List(vec![
+        Rc::new(MalType::Symbol(func)),
+        Rc::new(MalType::List(vec![
+            Rc::new(MalType::Symbol(symbol::FN)),
+            Rc::new(MalType::Vector(vec![])),
+            do_body(&list[1..]),
+        ])),
+    ]))
+}
+
+// (do body...), which is (do nil) when there is no body
+fn do_body(body: &[Rc<MalType>]) -> Rc<MalType> {
+    let mut form = vec![Rc::new(MalType::Symbol(symbol::DO))];
+    form.extend(body.iter().cloned());
+    if body.is_empty() {
+        form.push(Rc::new(MalType::Nil));
+    }
+    Rc::new(MalType::List(form))
+}
+
+// The form of quote, quasiquote, quasiquoteexpand or macroexpand
+pub fn quoted(list: &[Rc<MalType>]) -> Option<&Rc<MalType>> {
+    let form = list.get(1);
+    if form.is_none() {
+        report!(
+            "{} expects a form",
+            print_str(list[0].clone(), false, false)
+        );
+    }
+    form
+}
+
+// (defmacro! name f) as (def! name (defmacro-call f)), which makes a macro of f
+pub fn defmacro(list: &[Rc<MalType>]) -> Option<Rc<MalType>> {
+    if list.len() != 3 {
+        report!("Wrong amount of arguments for defmacro!");
+        return None;
+    }
+    Some(Rc::new(MalType::List(vec![
+        Rc::new(MalType::Symbol(symbol::DEF)),
+        list[1].clone(),
+        Rc::new(MalType::List(vec![
+            Rc::new(MalType::Symbol(DEFMACRO_CALL)),
+            list[2].clone(),
+        ])),
+    ])))
+}
+
+// (try* body... (catch* e handler...)) as
+// (try-call (fn* [] (do body...)) (fn* [e] (do handler...))), without the second
+// function if there is no catch*
+pub fn try_form(list: &[Rc<MalType>]) -> Rc<MalType> {
+    let thunk = |params: Vec<Rc<MalType>>, body: &[Rc<MalType>]| {
+        let mut body = body.to_vec();
+        body.insert(0, Rc::new(MalType::Symbol(symbol::DO)));
+        Rc::new(MalType::List(vec![
+            Rc::new(MalType::Symbol(symbol::FN)),
+            Rc::new(MalType::Vector(params)),
+            Rc::new(MalType::List(body)),
+        ]))
+    };
+    let catch = match list.last().map(|last| &**last) {
+        Some(MalType::List(catch))
+            if list.len() > 1
+                && catch.len() >= 2
+                && matches!(&*catch[0], MalType::Symbol(symbol::CATCH)) =>
+        {
+            Some(catch)
+        }
+        _ => None,
+    };
+    let mut call = vec![Rc::new(MalType::Symbol(TRY_CALL))];
+    match catch {
+        Some(catch) => {
+            call.push(thunk(vec![], &list[1..list.len() - 1]));
+            call.push(thunk(vec![catch[1].clone()], &catch[2..]));
+        }
+        None => call.push(thunk(vec![], &list[1..])),
+    }
+    Rc::new(MalType::List(call))
+}
+
+// Macros are expanded when the forms calling them are analyzed or compiled, so one
+// has to be defined by an earlier top-level form than those using it
+pub fn expand(mac: &MalType, list: &[Rc<MalType>]) -> Option<Rc<MalType>> {
+    match mac {
+        MalType::Macro(func) => apply(func, &list[1..]),
+        _ => Some(Rc::new(MalType::List(list.to_vec()))),
+    }
+}
+
+// ast expanded until it is no longer a call of a macro
+pub fn macroexpand(ast: Rc<MalType>) -> Option<Rc<MalType>> {
+    let mut ast = ast;
+    loop {
+        let mac = match &*ast {
+            MalType::List(list) => match list.first().map(|head| &**head) {
+                Some(MalType::Symbol(symbol)) => namespace::macro_for(*symbol),
+                _ => None,
+            },
+            _ => None,
+        };
+        ast = match (mac, &*ast) {
+            (Some(mac), MalType::List(list)) => expand(&mac, list)?,
+            _ => return Some(ast),
+        };
+    }
+}
+
+// (with-open [name (open ...) ...] body...) closes the files it opens however body
+// ends, as nested (with-open-call (open ...) (fn* [name] (do body...)))
+pub fn with_open(list: &[Rc<MalType>]) -> Option<Rc<MalType>> {
+    let bindings = match list.get(1).map(|bindings| &**bindings) {
+        Some(MalType::Vector(bindings)) if bindings.len() % 2 == 0 => bindings,
+        _ => {
+            report!("with-open expects a vector of bindings");
+            return None;
+        }
+    };
+    let mut form = do_body(&list[2..]);
+    for binding in bindings.chunks(2).rev() {
+        form = Rc::new(MalType::List(vec![
+            Rc::new(MalType::Symbol(WITH_OPEN_CALL)),
+            binding[1].clone(),
+            Rc::new(MalType::List(vec![
+                Rc::new(MalType::Symbol(symbol::FN)),
+                Rc::new(MalType::Vector(vec![binding[0].clone()])),
+                form,
+            ])),
+        ]));
+    }
+    Some(form)
+}
+
+// The clojure.test style forms, as calls of builtins taking functions:
+//   (deftest name body...) as (def! name (deftest-call 'name (fn* [] (do body...))))
+//   (testing "what" body...) as (testing-call "what" (fn* [] (do body...)))
+//   (is form message) as (is-call 'form (fn* [] form) message), the function gives
+//     [a b ...] for (= a b ...) so the values compared can be shown
+//   (are [x y] form 1 2 3 4) as (do (is form-with-x-1-y-2) (is form-with-x-3-y-4))
+pub fn test_form(list: &[Rc<MalType>]) -> Option<Rc<MalType>> {
+    let symbol = |symbol| Rc::new(MalType::Symbol(symbol));
+    let form = |items: Vec<Rc<MalType>>| Rc::new(MalType::List(items));
+    let quote = |quoted: &Rc<MalType>| form(vec![symbol(QUOTE), quoted.clone()]);
+    let thunk = |body: &[Rc<MalType>]| {
+        let mut body = body.to_vec();
+        body.insert(0, symbol(symbol::DO));
+        form(vec![
+            symbol(symbol::FN),
+            Rc::new(MalType::Vector(vec![])),
+            form(body),
+        ])
+    };
+    let usage = |expects| {
+        report!(
+            "{} expects {}",
+            print_str(list[0].clone(), false, false),
+            expects
+        );
+        None
+    };
+    match &*list[0] {
+        MalType::Symbol(symbol::DEFTEST) => match list.get(1).map(|name| &**name) {
+            Some(MalType::Symbol(_)) => Some(form(vec![
+                symbol(symbol::DEF),
+                list[1].clone(),
+                form(vec![
+                    symbol(DEFTEST_CALL),
+                    quote(&list[1]),
+                    thunk(&list[2..]),
+                ]),
+            ])),
+            _ => usage("a name"),
+        },
+        MalType::Symbol(symbol::TESTING) => match list.get(1) {
+            Some(what) => Some(form(vec![
+                symbol(TESTING_CALL),
+                what.clone(),
+                thunk(&list[2..]),
+            ])),
+            None => usage("a description"),
+        },
+        MalType::Symbol(symbol::IS) => {
+            let asserted = match list.get(1) {
+                Some(asserted) => asserted,
+                None => return usage("a form"),
+            };
+            let body = match &**asserted {
+                MalType::List(items)
+                    if matches!(
+                        items.first().map(|head| &**head),
+                        Some(MalType::Symbol(EQUAL))
+                    ) =>
+                {
+                    Rc::new(MalType::Vector(items[1..].to_vec()))
+                }
+                _ => asserted.clone(),
+            };
+            let mut call = vec![symbol(IS_CALL), quote(asserted), thunk(&[body])];
+            call.extend(list.get(2).cloned());
+            Some(form(call))
+        }
+        _ => {
+            // are
+            let (names, asserted) = match (list.get(1).map(|names| &**names), list.get(2)) {
+                (Some(MalType::Vector(names)), Some(asserted)) if !names.is_empty() => {
+                    (names, asserted)
+                }
+                _ => return usage("a vector of names and a form"),
+            };
+            let mut body = vec![symbol(symbol::DO)];
+            for values in list[3..].chunks(names.len()) {
+                let bindings: Vec<(&Rc<MalType>, &Rc<MalType>)> =
+                    names.iter().zip(values.iter()).collect();
+                // expanded here, in case is is shadowed where are is used
+                body.push(test_form(&[
+                    symbol(symbol::IS),
+                    substitute(asserted, &bindings),
+                ])?);
+            }
+            Some(form(body))
+        }
+    }
+}
+
+// (ns name (:require spec...)...) as (ns-call 'name '(:require spec...)...)
+pub fn ns_form(list: &[Rc<MalType>]) -> Option<Rc<MalType>> {
+    if !matches!(list.get(1).map(|name| &**name), Some(MalType::Symbol(_))) {
+        report!("ns expects a name");
+        return None;
+    }
+    let mut call = vec![Rc::new(MalType::Symbol(NS_CALL))];
+    call.extend(list[1..].iter().map(|arg| {
+        Rc::new(MalType::List(vec![
+            Rc::new(MalType::Symbol(QUOTE)),
+            arg.clone(),
+        ]))
+    }));
+    Some(Rc::new(MalType::List(call)))
+}
+
+// ast with the symbols among bindings replaced by what they are bound to
+fn substitute(ast: &Rc<MalType>, bindings: &[(&Rc<MalType>, &Rc<MalType>)]) -> Rc<MalType> {
+    let all = |items: &[Rc<MalType>]| -> Vec<Rc<MalType>> {
+        items
+            .iter()
+            .map(|item| substitute(item, bindings))
+            .collect()
+    };
+    match &**ast {
+        MalType::Symbol(_) => match bindings.iter().find(|(name, _)| **name == *ast) {
+            Some((_, value)) => (*value).clone(),
+            None => ast.clone(),
+        },
+        MalType::List(items) => Rc::new(MalType::List(all(items))),
+        MalType::Vector(items) => Rc::new(MalType::Vector(all(items))),
+        MalType::HashMap(entries) => Rc::new(MalType::HashMap(
+            entries
+                .iter()
+                .map(|(key, value)| (substitute(key, bindings), substitute(value, bindings)))
+                .collect(),
+        )),
+        _ => ast.clone(),
+    }
+}
+
+pub fn quasiquote(ast: Rc<MalType>) -> Option<Rc<MalType>> {
+    match &*ast {
+        MalType::List(list) => {
+            if list.is_empty() {
+                return Some(ast.clone());
+            }
+            if let MalType::Symbol(sym) = &*list[0] {
+                if *sym == UNQUOTE {
+                    if list.len() >= 2 {
+                        return Some(list[1].clone());
+                    } else {
+                        return Some(Rc::new(MalType::Nil));
+                    }
+                }
+            }
+            let mut result = Rc::new(MalType::List(vec![]));
+            for item in list.iter().rev() {
+                if let MalType::List(inner_list) = &**item {
+                    if !inner_list.is_empty() {
+                        if let MalType::Symbol(sym) = &*inner_list[0] {
+                            if *sym == SPLICE_UNQUOTE {
+                                if inner_list.len() >= 2 {
+                                    result = Rc::new(MalType::List(vec![
+                                        Rc::new(MalType::Symbol(CONCAT)),
+                                        inner_list[1].clone(),
+                                        result,
+                                    ]));
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                }
+                let quasiquote_ret = quasiquote(item.clone());
+                result = Rc::new(MalType::List(vec![
+                    Rc::new(MalType::Symbol(CONS)),
+                    quasiquote_ret?,
+                    result,
+                ]));
+            }
+            Some(result)
+        }
+        MalType::Vector(list) => {
+            if list.is_empty() {
+                return Some(Rc::new(MalType::List(vec![
+                    Rc::new(MalType::Symbol(VEC)),
+                    Rc::new(MalType::List(list.clone())),
+                ])));
+            }
+            let mut result = Rc::new(MalType::List(vec![]));
+            for item in list.iter().rev() {
+                if let MalType::List(inner_list) = &**item {
+                    if !inner_list.is_empty() {
+                        if let MalType::Symbol(sym) = &*inner_list[0] {
+                            if *sym == SPLICE_UNQUOTE {
+                                if inner_list.len() >= 2 {
+                                    result = Rc::new(MalType::List(vec![
+                                        Rc::new(MalType::Symbol(CONCAT)),
+                                        inner_list[1].clone(),
+                                        result,
+                                    ]));
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                }
+                let quasiquote_ret = quasiquote(item.clone());
+                result = Rc::new(MalType::List(vec![
+                    Rc::new(MalType::Symbol(CONS)),
+                    quasiquote_ret?,
+                    result,
+                ]));
+            }
+            Some(Rc::new(MalType::List(vec![
+                Rc::new(MalType::Symbol(VEC)),
+                result,
+            ])))
+        }
+        MalType::Symbol(_) | MalType::HashMap(_) => Some(Rc::new(MalType::List(vec![
+            Rc::new(MalType::Symbol(QUOTE)),
+            ast.clone(),
+        ]))),
+        _ => Some(ast),
+    }
+}
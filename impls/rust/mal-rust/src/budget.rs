@@ -0,0 +1,261 @@
+// Limits on how much one evaluation may do, for running code that can't be trusted
+// to stop by itself. Steps are calls and recur iterations, allocations count one per
+// value made plus one per item of a collection or 8 bytes of a string. Once a limit
+// is hit everything left of the evaluation fails, futures and go blocks included.
+// The same goes for an evaluation once its interpreter is interrupted, as the REPL
+// does on Ctrl-C.
+
+use crate::shared::Rc;
+use crate::stream::report;
+use std::cell::{self, Cell};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::LocalKey;
+use std::time::{Duration, Instant};
+
+// Steps or allocations a thread takes from the meter at a time, the deadline is
+// checked whenever it runs out
+const CHUNK: u64 = 256;
+
+// How long waits block at a time before looking whether they were interrupted
+const POLL: Duration = Duration::from_millis(50);
+
+// States of an Interrupt, it is reported once by whatever notices it first
+const RUNNING: u8 = 0;
+const INTERRUPTED: u8 = 1;
+const REPORTED: u8 = 2;
+
+// Stops the evaluation of one interpreter from any thread, see Interpreter::interrupter
+#[derive(Clone, Default)]
+pub struct Interrupt(Arc<AtomicU8>);
+
+impl Interrupt {
+    pub fn interrupt(&self) {
+        self.0.store(INTERRUPTED, Ordering::Relaxed);
+    }
+
+    pub fn clear(&self) {
+        self.0.store(RUNNING, Ordering::Relaxed);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.0.load(Ordering::Relaxed) != RUNNING
+    }
+
+    fn report(&self) -> Option<()> {
+        let first =
+            self.0
+                .compare_exchange(INTERRUPTED, REPORTED, Ordering::Relaxed, Ordering::Relaxed);
+        if first.is_ok() {
+            report!("Interrupted");
+        }
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Budget {
+    pub steps: Option<u64>,
+    pub allocations: Option<u64>,
+    pub timeout: Option<Duration>,
+}
+
+// Why an evaluation was stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exhausted {
+    Steps,
+    Allocations,
+    Deadline,
+}
+
+// What is left of a budget, shared by the threads working for the evaluation, and
+// whether it was interrupted
+pub struct Meter {
+    steps: AtomicU64,
+    allocations: AtomicU64,
+    deadline: Option<Instant>,
+    exhausted: Mutex<Option<Exhausted>>,
+    interrupt: Interrupt,
+}
+
+impl Meter {
+    pub fn new(budget: &Budget, interrupt: Interrupt) -> Self {
+        Self {
+            steps: AtomicU64::new(budget.steps.unwrap_or(u64::MAX)),
+            allocations: AtomicU64::new(budget.allocations.unwrap_or(u64::MAX)),
+            deadline: budget.timeout.map(|timeout| Instant::now() + timeout),
+            exhausted: Mutex::new(None),
+            interrupt,
+        }
+    }
+
+    pub fn exhausted(&self) -> Option<Exhausted> {
+        *self
+            .exhausted
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Only the first limit hit is reported
+    fn exhaust(&self, why: Exhausted) -> Option<()> {
+        let mut exhausted = self
+            .exhausted
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if exhausted.is_none() {
+            *exhausted = Some(why);
+            match why {
+                Exhausted::Steps => report!("Step budget exhausted"),
+                Exhausted::Allocations => report!("Allocation budget exhausted"),
+                Exhausted::Deadline => report!("Deadline exceeded"),
+            }
+        }
+        None
+    }
+
+    fn check(&self) -> Option<()> {
+        if self.interrupt.is_interrupted() {
+            return self.interrupt.report();
+        }
+        if self.exhausted().is_some() {
+            return None;
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => self.exhaust(Exhausted::Deadline),
+            _ => Some(()),
+        }
+    }
+
+    fn counter(&self, kind: Exhausted) -> &AtomicU64 {
+        match kind {
+            Exhausted::Allocations => &self.allocations,
+            _ => &self.steps,
+        }
+    }
+}
+
+thread_local! {
+    static METER: cell::RefCell<Option<Rc<Meter>>> = const { cell::RefCell::new(None) };
+    // What this thread may spend before going back to the meter
+    static STEPS: Cell<u64> = const { Cell::new(u64::MAX) };
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(u64::MAX) };
+}
+
+// Without a limit a thread still goes back to the meter every CHUNK steps, to look
+// whether the evaluation was interrupted
+#[inline]
+pub fn step() -> Option<()> {
+    spend(&STEPS, 1, Exhausted::Steps)
+}
+
+#[inline]
+pub fn allocate(size: usize) -> Option<()> {
+    spend(&ALLOCATIONS, size as u64 + 1, Exhausted::Allocations)
+}
+
+#[inline]
+fn spend(local: &'static LocalKey<Cell<u64>>, amount: u64, kind: Exhausted) -> Option<()> {
+    local.with(|left| {
+        if left.get() >= amount {
+            left.set(left.get() - amount);
+            Some(())
+        } else {
+            refill(left, amount, kind)
+        }
+    })
+}
+
+#[cold]
+fn refill(left: &Cell<u64>, amount: u64, kind: Exhausted) -> Option<()> {
+    let meter = match current() {
+        Some(meter) => meter,
+        None => {
+            left.set(u64::MAX);
+            return Some(());
+        }
+    };
+    meter.check()?;
+    let counter = meter.counter(kind);
+    give_back(counter, left.replace(0));
+    let wanted = amount.saturating_add(CHUNK);
+    let mut taken = 0;
+    let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |available| {
+        taken = available.min(wanted);
+        Some(available - taken)
+    });
+    if taken < amount {
+        give_back(counter, taken);
+        return meter.exhaust(kind);
+    }
+    left.set(taken - amount);
+    Some(())
+}
+
+fn give_back(counter: &AtomicU64, amount: u64) {
+    let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |available| {
+        Some(available.saturating_add(amount))
+    });
+}
+
+pub fn current() -> Option<Rc<Meter>> {
+    METER.with(|meter| meter.borrow().clone())
+}
+
+// How long a wait until deadline may block before looking again, None once the
+// deadline of the evaluation or deadline itself has passed or it was interrupted
+pub fn wait_slice(deadline: Option<Instant>) -> Option<Duration> {
+    let meter = current();
+    if let Some(meter) = &meter {
+        if meter.interrupt.is_interrupted() {
+            return None;
+        }
+    }
+    let budget = meter.and_then(|meter| meter.deadline);
+    let deadline = match (deadline, budget) {
+        (Some(deadline), Some(budget)) => Some(deadline.min(budget)),
+        (deadline, budget) => deadline.or(budget),
+    };
+    match deadline {
+        Some(deadline) => deadline
+            .checked_duration_since(Instant::now())
+            .filter(|left| !left.is_zero())
+            .map(|left| left.min(POLL)),
+        None => Some(POLL),
+    }
+}
+
+// Fails if the evaluation ran out of anything or was interrupted, for callers that
+// waited on something and can't tell what cut them short
+pub fn check() -> Option<()> {
+    match current() {
+        Some(meter) => meter.check(),
+        None => Some(()),
+    }
+}
+
+// Meters the current thread with meter while it is alive
+pub struct Metered(Option<Rc<Meter>>);
+
+impl Metered {
+    pub fn enter(meter: Option<Rc<Meter>>) -> Self {
+        Metered(switch(meter))
+    }
+}
+
+impl Drop for Metered {
+    fn drop(&mut self) {
+        switch(self.0.take());
+    }
+}
+
+// Hands back what is left to the old meter, the new one is gone to on the next spend
+fn switch(meter: Option<Rc<Meter>>) -> Option<Rc<Meter>> {
+    let old = METER.with(|current| current.replace(meter));
+    if let Some(old) = &old {
+        give_back(&old.steps, STEPS.with(|left| left.get()));
+        give_back(&old.allocations, ALLOCATIONS.with(|left| left.get()));
+    }
+    STEPS.with(|left| left.set(0));
+    ALLOCATIONS.with(|left| left.set(0));
+    old
+}
@@ -0,0 +1,113 @@
+// What builtins may reach outside the interpreter, for embedding it where the mal code
+// can't be trusted. Builtins needing something that wasn't granted are left out of
+// the namespace, those working on files refuse paths outside the roots given.
+
+use crate::stream::report;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities {
+    // Directories whose files may be read, or written, at any depth
+    pub read: Vec<PathBuf>,
+    pub write: Vec<PathBuf>,
+    pub stdout: bool,
+    pub stdin: bool,
+    pub env: bool,
+    pub time: bool,
+    pub exec: bool,
+}
+
+impl Capabilities {
+    // What the steps run with
+    pub fn all() -> Self {
+        Self {
+            read: vec![PathBuf::from("/")],
+            write: vec![PathBuf::from("/")],
+            stdout: true,
+            stdin: true,
+            env: true,
+            time: true,
+            exec: true,
+        }
+    }
+
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn may_read(&self, path: &Path) -> bool {
+        within(&self.read, path)
+    }
+
+    pub fn check_read(&self, path: &str) -> Option<()> {
+        if self.may_read(Path::new(path)) {
+            Some(())
+        } else {
+            report!("Permission denied reading {}", path);
+            None
+        }
+    }
+
+    pub fn check_write(&self, path: &str) -> Option<()> {
+        if within(&self.write, Path::new(path)) {
+            Some(())
+        } else {
+            report!("Permission denied writing {}", path);
+            None
+        }
+    }
+}
+
+// A comma separated list like `stdout,env,read=.,write=/tmp`, with read= and write=
+// given once for each root
+impl FromStr for Capabilities {
+    type Err = String;
+
+    fn from_str(list: &str) -> Result<Self, Self::Err> {
+        let mut capabilities = Self::none();
+        for name in list.split(',').filter(|name| !name.is_empty()) {
+            match name.split_once('=') {
+                Some(("read", root)) => capabilities.read.push(PathBuf::from(root)),
+                Some(("write", root)) => capabilities.write.push(PathBuf::from(root)),
+                None if name == "stdout" => capabilities.stdout = true,
+                None if name == "stdin" => capabilities.stdin = true,
+                None if name == "env" => capabilities.env = true,
+                None if name == "time" => capabilities.time = true,
+                None if name == "exec" => capabilities.exec = true,
+                _ => return Err(format!("Unknown capability {}", name)),
+            }
+        }
+        Ok(capabilities)
+    }
+}
+
+fn within(roots: &[PathBuf], path: &Path) -> bool {
+    let resolved = match resolve(path) {
+        Some(resolved) => resolved,
+        None => return false,
+    };
+    roots.iter().any(|root| match root.canonicalize() {
+        Ok(root) => resolved.starts_with(root),
+        Err(_) => false,
+    })
+}
+
+// Links and .. are resolved first so they can't lead out of the roots. Paths that
+// don't exist yet are resolved through the part of them that does, None if what
+// is left has a .. in it.
+fn resolve(path: &Path) -> Option<PathBuf> {
+    let mut existing = path;
+    let mut missing = vec![];
+    loop {
+        if let Ok(mut resolved) = existing.canonicalize() {
+            resolved.extend(missing.iter().rev());
+            return Some(resolved);
+        }
+        missing.push(existing.file_name()?);
+        existing = match existing.parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        };
+    }
+}
@@ -0,0 +1,315 @@
+// Channels in the style of core.async, for handing values between go blocks, futures
+// and Rust code. Channels need the `threads` feature to be of much use: without it go
+// blocks and futures run to the end when they are made and nothing else runs while
+// a channel operation waits, so operations that can't complete right away fail
+// instead, inside a go block as well.
+
+use crate::budget;
+use crate::shared::Rc;
+use crate::types::MalType;
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+struct State {
+    items: VecDeque<Rc<MalType>>,
+    closed: bool,
+    // Selects waiting to take from the channel, with the index of their op on it. A
+    // put that finds no room hands its value over to one of them.
+    takers: Vec<(Rc<Taker>, usize)>,
+}
+
+pub struct Channel {
+    pub capacity: usize,
+    state: Mutex<State>,
+}
+
+// One side of a select
+pub enum Op<'a> {
+    Take(&'a Channel),
+    Put(&'a Channel, Rc<MalType>),
+}
+
+// A select waiting with takes among its ops. Once, either a put hands it a value or
+// it completes one of its ops itself, so no value handed over is lost.
+struct Taker {
+    claim: Mutex<Claim>,
+}
+
+enum Claim {
+    Waiting,
+    // What a put handed over, for the op at the index
+    Handed(usize, Rc<MalType>),
+    // Completing an op of its own
+    Withdrawn,
+}
+
+impl Taker {
+    // Hands value over for the op at index, unless another put was first or the
+    // select is completing an op of its own
+    fn hand(&self, index: usize, value: &Rc<MalType>) -> bool {
+        let mut claim = lock(&self.claim);
+        if !matches!(*claim, Claim::Waiting) {
+            return false;
+        }
+        *claim = Claim::Handed(index, value.clone());
+        true
+    }
+
+    // Keeps puts from handing values over, what one handed over already if any
+    fn withdraw(&self) -> Result<(), (usize, Rc<MalType>)> {
+        let mut claim = lock(&self.claim);
+        match &*claim {
+            Claim::Handed(index, value) => Err((*index, value.clone())),
+            _ => {
+                *claim = Claim::Withdrawn;
+                Ok(())
+            }
+        }
+    }
+
+    fn handed(&self) -> Option<(usize, Rc<MalType>)> {
+        match &*lock(&self.claim) {
+            Claim::Handed(index, value) => Some((*index, value.clone())),
+            _ => None,
+        }
+    }
+
+    // Back to waiting after a withdraw that didn't complete anything
+    fn rewait(&self) {
+        let mut claim = lock(&self.claim);
+        if matches!(*claim, Claim::Withdrawn) {
+            *claim = Claim::Waiting;
+        }
+    }
+}
+
+// Bumped on every change to any channel, for waiting on more than one of them
+struct Signal {
+    generation: Mutex<u64>,
+    changed: Condvar,
+}
+
+static SIGNAL: Signal = Signal {
+    generation: Mutex::new(0),
+    changed: Condvar::new(),
+};
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn generation() -> u64 {
+    *lock(&SIGNAL.generation)
+}
+
+fn changed() {
+    *lock(&SIGNAL.generation) += 1;
+    SIGNAL.changed.notify_all();
+}
+
+// Waits for a change after seen, None once deadline has passed
+fn wait(seen: u64, deadline: Option<Instant>) -> Option<()> {
+    if cfg!(not(feature = "threads")) {
+        return None;
+    }
+    let mut generation = lock(&SIGNAL.generation);
+    while *generation == seen {
+        let slice = budget::wait_slice(deadline)?;
+        generation = SIGNAL
+            .changed
+            .wait_timeout(generation, slice)
+            .unwrap_or_else(PoisonError::into_inner)
+            .0;
+    }
+    Some(())
+}
+
+// Registers a taker on the channels of its takes while alive
+struct Waiting<'a> {
+    taker: Rc<Taker>,
+    channels: Vec<&'a Channel>,
+}
+
+impl<'a> Waiting<'a> {
+    fn new(ops: &[Op<'a>]) -> Self {
+        let taker = Rc::new(Taker {
+            claim: Mutex::new(Claim::Waiting),
+        });
+        let mut channels = vec![];
+        for (index, op) in ops.iter().enumerate() {
+            if let Op::Take(channel) = op {
+                lock(&channel.state).takers.push((taker.clone(), index));
+                channels.push(*channel);
+            }
+        }
+        changed();
+        Waiting { taker, channels }
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        for channel in self.channels.iter() {
+            lock(&channel.state)
+                .takers
+                .retain(|(taker, _)| !Rc::ptr_eq(taker, &self.taker));
+        }
+    }
+}
+
+impl Channel {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(State {
+                items: VecDeque::new(),
+                closed: false,
+                takers: vec![],
+            }),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        lock(&self.state).closed
+    }
+
+    // Puts fail from now on, takes get what is left and then nil
+    pub fn close(&self) {
+        lock(&self.state).closed = true;
+        changed();
+    }
+
+    // false if the channel is closed, None if deadline passed first
+    pub fn put(&self, value: Rc<MalType>, deadline: Option<Instant>) -> Option<bool> {
+        let (_, done) = select(&[Op::Put(self, value)], deadline, false)?;
+        Some(matches!(*done, MalType::Bool(true)))
+    }
+
+    // nil once the channel is closed and empty, None if deadline passed first
+    pub fn take(&self, deadline: Option<Instant>) -> Option<Rc<MalType>> {
+        let (_, value) = select(&[Op::Take(self)], deadline, false)?;
+        Some(value)
+    }
+}
+
+// How trying an op went: it completed with a value, or a put handed the select a
+// value for another op first
+enum Tried {
+    Done(Rc<MalType>),
+    Handed(usize, Rc<MalType>),
+}
+
+// Lets the select of me complete an op, unless a put handed it a value already
+fn withdraw(me: Option<&Rc<Taker>>) -> Result<(), Tried> {
+    match me.map(|me| me.withdraw()) {
+        Some(Err((index, value))) => Err(Tried::Handed(index, value)),
+        _ => Ok(()),
+    }
+}
+
+impl Op<'_> {
+    // me is the taker of the select trying the op, if it waits already
+    fn try_complete(&self, me: Option<&Rc<Taker>>) -> Option<Tried> {
+        match self {
+            Op::Take(channel) => {
+                let mut state = lock(&channel.state);
+                if state.items.is_empty() && !state.closed {
+                    return None;
+                }
+                if let Err(handed) = withdraw(me) {
+                    return Some(handed);
+                }
+                match state.items.pop_front() {
+                    Some(value) => {
+                        drop(state);
+                        changed();
+                        Some(Tried::Done(value))
+                    }
+                    None => Some(Tried::Done(Rc::new(MalType::Nil))),
+                }
+            }
+            Op::Put(channel, value) => {
+                let mut state = lock(&channel.state);
+                let done = Some(Tried::Done(Rc::new(MalType::Bool(true))));
+                if state.closed {
+                    if let Err(handed) = withdraw(me) {
+                        return Some(handed);
+                    }
+                    return Some(Tried::Done(Rc::new(MalType::Bool(false))));
+                }
+                if state.items.len() < channel.capacity {
+                    if let Err(handed) = withdraw(me) {
+                        return Some(handed);
+                    }
+                    state.items.push_back(value.clone());
+                    drop(state);
+                    changed();
+                    return done;
+                }
+                // No room, so only a taker claimed here takes the value
+                let takers: Vec<(Rc<Taker>, usize)> = state
+                    .takers
+                    .iter()
+                    .filter(|(taker, _)| !me.is_some_and(|me| Rc::ptr_eq(taker, me)))
+                    .cloned()
+                    .collect();
+                if takers.is_empty() {
+                    return None;
+                }
+                if let Err(handed) = withdraw(me) {
+                    return Some(handed);
+                }
+                for (taker, index) in takers {
+                    if taker.hand(index, value) {
+                        drop(state);
+                        changed();
+                        return done;
+                    }
+                }
+                if let Some(me) = me {
+                    me.rewait();
+                }
+                None
+            }
+        }
+    }
+}
+
+// Completes the first op that can, in order, waiting for one until deadline unless
+// just_try. Gives the index of the op with what it took, or whether it put.
+pub fn select(
+    ops: &[Op],
+    deadline: Option<Instant>,
+    just_try: bool,
+) -> Option<(usize, Rc<MalType>)> {
+    let mut waiting: Option<Waiting> = None;
+    loop {
+        let seen = generation();
+        let me = waiting.as_ref().map(|waiting| &waiting.taker);
+        if let Some(handed) = me.and_then(|me| me.handed()) {
+            return Some(handed);
+        }
+        for (index, op) in ops.iter().enumerate() {
+            match op.try_complete(me) {
+                Some(Tried::Done(value)) => return Some((index, value)),
+                Some(Tried::Handed(index, value)) => return Some((index, value)),
+                None => {}
+            }
+        }
+        if just_try {
+            return None;
+        }
+        if waiting.is_none() {
+            waiting = Some(Waiting::new(ops));
+            continue;
+        }
+        if wait(seen, deadline).is_none() {
+            // A put may have handed a value over just before
+            return match withdraw(me) {
+                Err(Tried::Handed(index, value)) => Some((index, value)),
+                _ => None,
+            };
+        }
+    }
+}
@@ -0,0 +1,900 @@
+// Compiles forms to bytecode for the VM in vm.rs. Locals live in stack slots counted
+// from the base of their frame, and since a bound local never changes, closures copy
+// the values they capture into upvalues when they are made.
+
+use crate::analyzer::{
+    call_body, defmacro, expand, macroexpand, ns_form, parse_arities, quasiquote, quoted,
+    rest_form, test_form, try_form, with_open,
+};
+use crate::depth::DepthGuard;
+use crate::namespace;
+use crate::printer::print_str;
+use crate::shared::Rc;
+use crate::stream::report;
+use crate::symbol::{
+    self, Symbol, AMPERSAND, AS, FUTURE_CALL, GO_CALL, KEYS, OR, STRS, SYMS, WITH_OUT_STR_CALL,
+};
+use crate::types::{Arity, MalType, KV};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Op {
+    // Pushes a constant from the pool
+    Const(u32),
+    Local(u32),
+    Upvalue(u32),
+    // Pushes the value of the global named by a constant, the bool tells if it is of
+    // another namespace and has to be public
+    Global(u32, bool),
+    // Sets the global named by a constant to the top value, leaving it there
+    Def(u32),
+    // Pushes the function being run, for named fn*s
+    SelfFn,
+    Pop,
+    // Drops n values under the top one, when a let* or loop* goes out of scope
+    Slide(u32),
+    Jump(u32),
+    JumpIfFalse(u32),
+    Call(u32),
+    TailCall(u32),
+    Return,
+    // First op of every fn* clause: packs the arguments into the parameter slots
+    Args(u32, Pack),
+    // Moves n values into the parameter slots and starts the clause over
+    RecurFn(u32),
+    // Moves n values into the loop* slots from slot and jumps back to the loop
+    RecurLoop(u32, u32, u32),
+    // Makes a closure of a lambda in the chunk
+    Closure(u32),
+    Vector(u32),
+    // Builds a hash-map of n key/value pairs
+    Map(u32),
+    // Destructuring, on the value on top of the stack
+    SeqCheck,
+    MapCheck,
+    Nth(u32),
+    NthRest(u32),
+    // Replaces a map by the value under a constant key, or nil
+    Get(u32),
+    // Same, but jumps if the key is there and pushes nothing if it is not
+    GetOr(u32, u32),
+    // Boxes hold the let* locals a closure made before them may use, see let_form
+    NewBox,
+    SetBox(u32),
+    Unbox,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Pack {
+    Fixed,
+    // The arguments after the required ones go in one list
+    Rest,
+    // All arguments go in one list, for params with :as or odd shapes
+    All,
+}
+
+#[derive(Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub consts: Vec<Rc<MalType>>,
+    pub lambdas: Vec<Rc<Lambda>>,
+}
+
+// Where a closure gets an upvalue from in the frame that makes it
+#[derive(Clone, Copy)]
+pub enum Capture {
+    Local(u32),
+    Upvalue(u32),
+    SelfFn,
+}
+
+// A compiled fn*, one chunk per clause
+pub struct Lambda {
+    pub name: Option<String>,
+    pub arities: Vec<Arity>,
+    pub chunks: Vec<Rc<Chunk>>,
+    pub captures: Vec<Capture>,
+}
+
+// Where a name is found, and whether it is boxed
+enum Access {
+    Local(u32, bool),
+    Upvalue(u32, bool),
+    SelfFn,
+}
+
+#[derive(Clone, Copy)]
+enum Target {
+    Fn(usize),
+    // slot of the first binding, amount of bindings, start of the body
+    Loop(usize, usize, usize),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Slot {
+    Value,
+    Boxed,
+    // A box not filled in yet, only closures may refer to it
+    Pending,
+}
+
+// The state of one fn* (or the top level) being compiled
+#[derive(Default)]
+struct Scope {
+    name: Option<Symbol>,
+    captures: Vec<(Symbol, Capture, bool)>,
+    // For the clause being compiled: named and hidden locals with their slots
+    locals: Vec<(Option<Symbol>, usize, Slot)>,
+    height: usize,
+    targets: Vec<Target>,
+    chunk: Chunk,
+    arities: Vec<Arity>,
+}
+
+// Where an expression is: whether it should return from the frame and whether it
+// may recur to the innermost loop* or fn*
+#[derive(Clone, Copy)]
+struct Position {
+    tail: bool,
+    recur: bool,
+}
+
+const VALUE: Position = Position {
+    tail: false,
+    recur: false,
+};
+
+pub fn compile(ast: &Rc<MalType>) -> Option<Rc<Chunk>> {
+    let mut compiler = Compiler {
+        scopes: vec![Scope::default()],
+    };
+    compiler.expr(
+        ast,
+        Position {
+            tail: true,
+            recur: false,
+        },
+    )?;
+    let scope = compiler.scopes.pop().unwrap();
+    Some(Rc::new(scope.chunk))
+}
+
+struct Compiler {
+    scopes: Vec<Scope>,
+}
+
+impl Compiler {
+    fn scope(&mut self) -> &mut Scope {
+        self.scopes.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let scope = self.scope();
+        scope.height = match op {
+            Op::Const(_)
+            | Op::NewBox
+            | Op::Local(_)
+            | Op::Upvalue(_)
+            | Op::Global(..)
+            | Op::SelfFn
+            | Op::Closure(_) => scope.height + 1,
+            Op::Pop | Op::JumpIfFalse(_) | Op::GetOr(_, _) | Op::SetBox(_) => scope.height - 1,
+            Op::Slide(n) | Op::Call(n) => scope.height - n as usize,
+            Op::Vector(n) => scope.height + 1 - n as usize,
+            Op::Map(n) => scope.height + 1 - 2 * n as usize,
+            _ => scope.height,
+        };
+        scope.chunk.code.push(op);
+        scope.chunk.code.len() - 1
+    }
+
+    fn here(&mut self) -> u32 {
+        self.scope().chunk.code.len() as u32
+    }
+
+    // Points the jump at index to the next op
+    fn patch(&mut self, index: usize) {
+        let target = self.here();
+        let code = &mut self.scope().chunk.code;
+        code[index] = match code[index] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            Op::GetOr(key, _) => Op::GetOr(key, target),
+            op => op,
+        };
+    }
+
+    fn constant(&mut self, value: Rc<MalType>) -> u32 {
+        let consts = &mut self.scope().chunk.consts;
+        consts.push(value);
+        consts.len() as u32 - 1
+    }
+
+    fn push_const(&mut self, value: Rc<MalType>, pos: Position) -> Option<()> {
+        let index = self.constant(value);
+        self.emit(Op::Const(index));
+        self.finish(pos);
+        Some(())
+    }
+
+    // A value left in tail position is returned
+    fn finish(&mut self, pos: Position) {
+        if pos.tail {
+            self.emit(Op::Return);
+        }
+    }
+
+    fn declare(&mut self, name: Option<Symbol>, slot: usize) {
+        self.scope().locals.push((name, slot, Slot::Value));
+    }
+
+    fn resolve(&mut self, depth: usize, name: Symbol) -> Option<Access> {
+        let nested = depth + 1 < self.scopes.len();
+        let scope = &mut self.scopes[depth];
+        if let Some((_, slot, kind)) = scope
+            .locals
+            .iter()
+            .rev()
+            .find(|(local, _, kind)| *local == Some(name) && (nested || *kind != Slot::Pending))
+        {
+            return Some(Access::Local(*slot as u32, *kind != Slot::Value));
+        }
+        if scope.name == Some(name) {
+            return Some(Access::SelfFn);
+        }
+        if let Some(index) = scope
+            .captures
+            .iter()
+            .position(|(capture, _, _)| *capture == name)
+        {
+            return Some(Access::Upvalue(index as u32, scope.captures[index].2));
+        }
+        if depth == 0 {
+            return None;
+        }
+        let (capture, boxed) = match self.resolve(depth - 1, name)? {
+            Access::Local(slot, boxed) => (Capture::Local(slot), boxed),
+            Access::Upvalue(index, boxed) => (Capture::Upvalue(index), boxed),
+            Access::SelfFn => (Capture::SelfFn, false),
+        };
+        let captures = &mut self.scopes[depth].captures;
+        captures.push((name, capture, boxed));
+        Some(Access::Upvalue(captures.len() as u32 - 1, boxed))
+    }
+
+    fn expr(&mut self, ast: &Rc<MalType>, pos: Position) -> Option<()> {
+        let _depth = DepthGuard::enter()?;
+        match &**ast {
+            MalType::Symbol(symbol) => {
+                let depth = self.scopes.len() - 1;
+                let boxed = match self.resolve(depth, *symbol) {
+                    Some(Access::Local(slot, boxed)) => {
+                        self.emit(Op::Local(slot));
+                        boxed
+                    }
+                    Some(Access::Upvalue(index, boxed)) => {
+                        self.emit(Op::Upvalue(index));
+                        boxed
+                    }
+                    Some(Access::SelfFn) => {
+                        self.emit(Op::SelfFn);
+                        false
+                    }
+                    None => {
+                        let (global, public) = namespace::resolve(*symbol)?;
+                        let index = self.constant(Rc::new(MalType::Symbol(global)));
+                        self.emit(Op::Global(index, public));
+                        false
+                    }
+                };
+                if boxed {
+                    self.emit(Op::Unbox);
+                }
+                self.finish(pos);
+                Some(())
+            }
+            MalType::List(list) if !list.is_empty() => self.list(list, pos),
+            MalType::Vector(items) if !is_constant(ast) => {
+                for item in items.iter() {
+                    self.expr(item, VALUE)?;
+                }
+                self.emit(Op::Vector(items.len() as u32));
+                self.finish(pos);
+                Some(())
+            }
+            MalType::HashMap(kvs) if !is_constant(ast) => {
+                for (key, value) in kvs.iter() {
+                    self.push_const(key.clone(), VALUE)?;
+                    self.expr(value, VALUE)?;
+                }
+                self.emit(Op::Map(kvs.len() as u32));
+                self.finish(pos);
+                Some(())
+            }
+            _ => self.push_const(ast.clone(), pos),
+        }
+    }
+
+    fn list(&mut self, list: &[Rc<MalType>], pos: Position) -> Option<()> {
+        if let MalType::Symbol(symbol) = &*list[0] {
+            match *symbol {
+                symbol::DEF => return self.def(list, false, pos),
+                symbol::DEF_PRIVATE if self.is_special(*symbol) => {
+                    return self.def(list, true, pos)
+                }
+                symbol::LET => return self.let_form(list, pos),
+                symbol::FN => return self.fn_form(list, pos),
+                symbol::LOOP => return self.loop_form(list, pos),
+                symbol::RECUR => return self.recur(list, pos),
+                symbol::IF => return self.if_form(list, pos),
+                symbol::DO => return self.do_form(list, pos),
+                symbol::QUOTE => return self.push_const(quoted(list)?.clone(), pos),
+                symbol::QUASIQUOTEEXPAND => {
+                    return self.push_const(quasiquote(quoted(list)?.clone())?, pos)
+                }
+                symbol::DEFMACRO => return self.expr(&defmacro(list)?, pos),
+                symbol::TRY => return self.expr(&try_form(list), pos),
+                symbol::MACROEXPAND => {
+                    return self.push_const(macroexpand(quoted(list)?.clone())?, pos)
+                }
+                symbol::FUTURE if self.is_special(*symbol) => {
+                    return self.expr(&call_body(FUTURE_CALL, list), pos)
+                }
+                symbol::GO if self.is_special(*symbol) => {
+                    return self.expr(&call_body(GO_CALL, list), pos)
+                }
+                symbol::WITH_OPEN if self.is_special(*symbol) => {
+                    return self.expr(&with_open(list)?, pos)
+                }
+                symbol::WITH_OUT_STR if self.is_special(*symbol) => {
+                    return self.expr(&call_body(WITH_OUT_STR_CALL, list), pos)
+                }
+                symbol::DEFTEST | symbol::IS | symbol::ARE | symbol::TESTING
+                    if self.is_special(*symbol) =>
+                {
+                    return self.expr(&test_form(list)?, pos)
+                }
+                symbol::NS if self.is_special(*symbol) => return self.expr(&ns_form(list)?, pos),
+                symbol::QUASIQUOTE => return self.expr(&quasiquote(quoted(list)?.clone())?, pos),
+                _ => {}
+            }
+            let depth = self.scopes.len() - 1;
+            if self.resolve(depth, *symbol).is_none() {
+                if let Some(mac) = namespace::macro_for(*symbol) {
+                    return self.expr(&expand(&mac, list)?, pos);
+                }
+            }
+        }
+        for item in list.iter() {
+            self.expr(item, VALUE)?;
+        }
+        let argc = list.len() as u32 - 1;
+        if pos.tail {
+            self.emit(Op::TailCall(argc));
+        } else {
+            self.emit(Op::Call(argc));
+        }
+        Some(())
+    }
+
+    // The forms added on top of mal's own give way to a local or global of the same name
+    fn is_special(&mut self, symbol: Symbol) -> bool {
+        let depth = self.scopes.len() - 1;
+        self.resolve(depth, symbol).is_none() && !namespace::is_bound(symbol)
+    }
+
+    // Locals only live in slots, so def! always sets a global, even inside a let*
+    fn def(&mut self, list: &[Rc<MalType>], private: bool, pos: Position) -> Option<()> {
+        if list.len() != 3 {
+            report!(
+                "Wrong amount of arguments for {}",
+                print_str(list[0].clone(), false, false)
+            );
+            return None;
+        }
+        if let MalType::Symbol(name) = &*list[1] {
+            let global = namespace::define(*name, private)?;
+            self.expr(&list[2], VALUE)?;
+            let index = self.constant(Rc::new(MalType::Symbol(global)));
+            self.emit(Op::Def(index));
+            self.finish(pos);
+            Some(())
+        } else {
+            report!("{} is not a symbol", print_str(list[1].clone(), true, true));
+            None
+        }
+    }
+
+    fn bindings(&self, list: &[Rc<MalType>], form: &str) -> Option<Vec<Rc<MalType>>> {
+        if list.len() != 3 {
+            report!("Wrong amount of arguments for {}", form);
+            return None;
+        }
+        match &*list[1] {
+            MalType::List(bind_list) | MalType::Vector(bind_list) => {
+                if bind_list.len() % 2 != 0 {
+                    report!("Wrong amount of arguments for bind of {}", form);
+                    return None;
+                }
+                Some(bind_list.clone())
+            }
+            _ => {
+                report!("Wrong bind format");
+                None
+            }
+        }
+    }
+
+    // A fn* in a let* may use names bound after it, as in
+    // `(let* (f (fn* () x) x 3) (f))`, and sees the last value they are bound to.
+    // Those names get a box made before the fn*, which the closure captures and every
+    // binding of the name fills in later.
+    fn let_form(&mut self, list: &[Rc<MalType>], pos: Position) -> Option<()> {
+        let bind_list = self.bindings(list, "let*")?;
+        let pairs: Vec<_> = bind_list.chunks(2).collect();
+        let locals = self.scope().locals.len();
+        let height = self.scope().height;
+        let mut boxes = vec![];
+        for (i, pair) in pairs.iter().enumerate() {
+            if let MalType::Symbol(name) = &*pair[0] {
+                let last = pairs
+                    .iter()
+                    .rposition(|pair| matches!(&*pair[0], MalType::Symbol(other) if other == name));
+                if last == Some(i) && pairs[..=i].iter().any(|pair| used_by_fn(&pair[1], *name)) {
+                    self.emit(Op::NewBox);
+                    let slot = self.scope().height - 1;
+                    self.scope().locals.push((Some(*name), slot, Slot::Pending));
+                    boxes.push((*name, slot));
+                }
+            }
+        }
+        for pair in pairs.iter() {
+            self.expr(&pair[1], VALUE)?;
+            let boxed = match &*pair[0] {
+                MalType::Symbol(name) => boxes.iter().find(|(boxed, _)| boxed == name),
+                _ => None,
+            };
+            match boxed {
+                Some((_, slot)) => {
+                    self.emit(Op::SetBox(*slot as u32));
+                    let local = self
+                        .scope()
+                        .locals
+                        .iter_mut()
+                        .find(|(_, other, _)| other == slot)
+                        .unwrap();
+                    local.2 = Slot::Boxed;
+                }
+                None => self.bind(&pair[0])?,
+            }
+        }
+        self.expr(&list[2], pos)?;
+        self.end_scope(locals, height, pos);
+        Some(())
+    }
+
+    fn end_scope(&mut self, locals: usize, height: usize, pos: Position) {
+        if !pos.tail {
+            let bound = self.scope().height - 1 - height;
+            if bound > 0 {
+                self.emit(Op::Slide(bound as u32));
+            }
+        }
+        let scope = self.scope();
+        scope.locals.truncate(locals);
+        scope.height = height + 1;
+    }
+
+    // The first values are bound as in let*, then copied next to each other where
+    // each recur replaces them and the body destructures them again
+    fn loop_form(&mut self, list: &[Rc<MalType>], pos: Position) -> Option<()> {
+        let bind_list = self.bindings(list, "loop*")?;
+        let locals = self.scope().locals.len();
+        let height = self.scope().height;
+        let mut values = vec![];
+        for pair in bind_list.chunks(2) {
+            self.expr(&pair[1], VALUE)?;
+            values.push(self.scope().height - 1);
+            self.bind(&pair[0])?;
+        }
+        let slot = self.scope().height;
+        for (value, pair) in values.into_iter().zip(bind_list.chunks(2)) {
+            self.emit(Op::Local(value as u32));
+            let name = match &*pair[0] {
+                MalType::Symbol(symbol) => Some(*symbol),
+                _ => None,
+            };
+            let slot = self.scope().height - 1;
+            self.declare(name, slot);
+        }
+        let start = self.here() as usize;
+        for (i, pair) in bind_list.chunks(2).enumerate() {
+            if !matches!(&*pair[0], MalType::Symbol(_)) {
+                self.emit(Op::Local((slot + i) as u32));
+                self.bind(&pair[0])?;
+            }
+        }
+        let target = Target::Loop(slot, bind_list.len() / 2, start);
+        self.scope().targets.push(target);
+        let body = self.expr(
+            &list[2],
+            Position {
+                tail: pos.tail,
+                recur: true,
+            },
+        );
+        self.scope().targets.pop();
+        body?;
+        self.end_scope(locals, height, pos);
+        Some(())
+    }
+
+    fn recur(&mut self, list: &[Rc<MalType>], pos: Position) -> Option<()> {
+        let target = match self.scope().targets.last() {
+            Some(target) if pos.recur => *target,
+            None if pos.tail => {
+                report!("recur should be inside loop* or fn*");
+                return None;
+            }
+            _ => {
+                report!("Can only recur from tail position");
+                return None;
+            }
+        };
+        let args = &list[1..];
+        let expected = match target {
+            Target::Fn(index) => {
+                let arity = &self.scope().arities[index];
+                if arity.accepts(args.len()) {
+                    None
+                } else {
+                    Some(arity.required())
+                }
+            }
+            Target::Loop(_, count, _) => Some(count).filter(|count| *count != args.len()),
+        };
+        if let Some(expected) = expected {
+            report!(
+                "Mismatched argument count to recur, expected {} args, got {}",
+                expected,
+                args.len()
+            );
+            return None;
+        }
+        for arg in args.iter() {
+            self.expr(arg, VALUE)?;
+        }
+        match target {
+            Target::Fn(_) => self.emit(Op::RecurFn(args.len() as u32)),
+            Target::Loop(slot, count, start) => {
+                self.emit(Op::RecurLoop(slot as u32, count as u32, start as u32))
+            }
+        };
+        // Whatever follows in a non-tail loop* expects the value recur never leaves
+        let scope = self.scope();
+        scope.height = scope.height + 1 - args.len();
+        Some(())
+    }
+
+    fn if_form(&mut self, list: &[Rc<MalType>], pos: Position) -> Option<()> {
+        if list.len() <= 2 {
+            report!("Wrong amount of arguments for if");
+            return None;
+        }
+        let height = self.scope().height;
+        self.expr(&list[1], VALUE)?;
+        let otherwise = self.emit(Op::JumpIfFalse(0));
+        self.expr(&list[2], pos)?;
+        let end = if pos.tail {
+            None
+        } else {
+            Some(self.emit(Op::Jump(0)))
+        };
+        self.patch(otherwise);
+        self.scope().height = height;
+        match list.get(3) {
+            Some(form) => self.expr(form, pos)?,
+            None => self.push_const(Rc::new(MalType::Nil), pos)?,
+        }
+        if let Some(end) = end {
+            self.patch(end);
+        }
+        self.scope().height = height + 1;
+        Some(())
+    }
+
+    fn do_form(&mut self, list: &[Rc<MalType>], pos: Position) -> Option<()> {
+        if list.len() <= 1 {
+            report!("Wrong amount of arguments for do");
+            return None;
+        }
+        for item in list[1..list.len() - 1].iter() {
+            self.expr(item, VALUE)?;
+            self.emit(Op::Pop);
+        }
+        self.expr(&list[list.len() - 1], pos)
+    }
+
+    fn fn_form(&mut self, list: &[Rc<MalType>], pos: Position) -> Option<()> {
+        let (name, clauses) = match list.get(1).map(|name| &**name) {
+            Some(MalType::Symbol(name)) => (Some(*name), &list[2..]),
+            _ => (None, &list[1..]),
+        };
+        let arities = parse_arities(clauses)?;
+        self.scopes.push(Scope {
+            name,
+            arities: arities.clone(),
+            ..Scope::default()
+        });
+        let mut chunks = vec![];
+        for (index, arity) in arities.iter().enumerate() {
+            let chunk = self.clause(arity, index);
+            match chunk {
+                Some(chunk) => chunks.push(Rc::new(chunk)),
+                None => {
+                    self.scopes.pop();
+                    return None;
+                }
+            }
+        }
+        let scope = self.scopes.pop().unwrap();
+        let lambda = Lambda {
+            name: name.map(|name| name.to_string()),
+            arities,
+            chunks,
+            captures: scope
+                .captures
+                .into_iter()
+                .map(|(_, capture, _)| capture)
+                .collect(),
+        };
+        let lambdas = &mut self.scope().chunk.lambdas;
+        lambdas.push(Rc::new(lambda));
+        let index = lambdas.len() as u32 - 1;
+        self.emit(Op::Closure(index));
+        self.finish(pos);
+        Some(())
+    }
+
+    fn clause(&mut self, arity: &Arity, index: usize) -> Option<Chunk> {
+        let scope = self.scope();
+        scope.locals.clear();
+        scope.targets = vec![Target::Fn(index)];
+        let params = &arity.params;
+        let required = arity.required() as u32;
+        match positional(params) {
+            Some(patterns) => {
+                let pack = if arity.is_variadic() {
+                    Pack::Rest
+                } else {
+                    Pack::Fixed
+                };
+                self.emit(Op::Args(required, pack));
+                self.scope().height = patterns.len();
+                for (slot, pattern) in patterns.iter().enumerate() {
+                    match &***pattern {
+                        MalType::Symbol(symbol) => self.declare(Some(*symbol), slot),
+                        _ => self.declare(None, slot),
+                    }
+                }
+                for (slot, pattern) in patterns.iter().enumerate() {
+                    if !matches!(&***pattern, MalType::Symbol(_)) {
+                        self.emit(Op::Local(slot as u32));
+                        self.bind(pattern)?;
+                    }
+                }
+            }
+            None => {
+                self.emit(Op::Args(required, Pack::All));
+                self.scope().height = 1;
+                self.bind(&Rc::new(MalType::Vector(params.clone())))?;
+            }
+        }
+        self.expr(
+            &arity.ast,
+            Position {
+                tail: true,
+                recur: true,
+            },
+        )?;
+        Some(std::mem::take(&mut self.scope().chunk))
+    }
+
+    // Binds the binding form to the value on top of the stack, which stays in its slot
+    fn bind(&mut self, pattern: &Rc<MalType>) -> Option<()> {
+        let slot = self.scope().height - 1;
+        match &**pattern {
+            MalType::Symbol(symbol) => {
+                self.declare(Some(*symbol), slot);
+                Some(())
+            }
+            MalType::List(binds) | MalType::Vector(binds) => {
+                self.emit(Op::SeqCheck);
+                self.declare(None, slot);
+                self.bind_seq(binds, slot as u32)
+            }
+            MalType::HashMap(binds) => {
+                self.emit(Op::MapCheck);
+                self.declare(None, slot);
+                self.bind_map(binds, slot as u32)
+            }
+            _ => {
+                report!(
+                    "{} is not a valid binding form",
+                    print_str(pattern.clone(), true, true)
+                );
+                None
+            }
+        }
+    }
+
+    fn bind_seq(&mut self, binds: &[Rc<MalType>], slot: u32) -> Option<()> {
+        let mut pos = 0;
+        let mut i = 0;
+        while i < binds.len() {
+            match &*binds[i] {
+                MalType::Symbol(symbol) if *symbol == AMPERSAND => {
+                    let rest = rest_form(binds, i)?;
+                    self.emit(Op::Local(slot));
+                    self.emit(Op::NthRest(pos));
+                    self.bind(rest)?;
+                    i += 2;
+                }
+                MalType::Keyword(keyword) if *keyword == AS => {
+                    if i + 1 >= binds.len() {
+                        report!(":as should be followed by a symbol");
+                        return None;
+                    }
+                    self.emit(Op::Local(slot));
+                    self.bind(&binds[i + 1])?;
+                    i += 2;
+                }
+                _ => {
+                    self.emit(Op::Local(slot));
+                    self.emit(Op::Nth(pos));
+                    self.bind(&binds[i])?;
+                    pos += 1;
+                    i += 1;
+                }
+            }
+        }
+        Some(())
+    }
+
+    fn bind_map(&mut self, binds: &[KV], slot: u32) -> Option<()> {
+        let or_key = MalType::Keyword(OR);
+        let defaults: &[KV] = match binds.iter().find(|(key, _)| **key == or_key) {
+            Some((_, or)) => match &**or {
+                MalType::HashMap(defaults) => defaults,
+                _ => {
+                    report!(":or should be followed by a map");
+                    return None;
+                }
+            },
+            None => &[],
+        };
+        for (key, bind_form) in binds.iter() {
+            match &**key {
+                MalType::Keyword(keyword)
+                    if *keyword == KEYS || *keyword == STRS || *keyword == SYMS =>
+                {
+                    let names = match &**bind_form {
+                        MalType::List(names) | MalType::Vector(names) => names,
+                        _ => {
+                            report!(":{} should be followed by a vector of symbols", keyword);
+                            return None;
+                        }
+                    };
+                    for name in names.iter() {
+                        let symbol = match &**name {
+                            MalType::Symbol(symbol) => *symbol,
+                            _ => {
+                                report!("{} is not a symbol", print_str(name.clone(), true, true));
+                                return None;
+                            }
+                        };
+                        let entry_key = match *keyword {
+                            KEYS => MalType::Keyword(symbol),
+                            STRS => MalType::Str(symbol.to_string()),
+                            _ => MalType::Symbol(symbol),
+                        };
+                        self.bind_entry(name, Rc::new(entry_key), defaults, slot)?;
+                    }
+                }
+                MalType::Keyword(keyword) if *keyword == AS => {
+                    self.emit(Op::Local(slot));
+                    self.bind(bind_form)?;
+                }
+                MalType::Keyword(keyword) if *keyword == OR => {}
+                // {name :key} binds name to the value under :key
+                _ => self.bind_entry(key, bind_form.clone(), defaults, slot)?,
+            }
+        }
+        Some(())
+    }
+
+    fn bind_entry(
+        &mut self,
+        pattern: &Rc<MalType>,
+        key: Rc<MalType>,
+        defaults: &[KV],
+        slot: u32,
+    ) -> Option<()> {
+        let key = self.constant(key);
+        self.emit(Op::Local(slot));
+        match defaults.iter().find(|(name, _)| **name == **pattern) {
+            Some((_, default)) => {
+                let found = self.emit(Op::GetOr(key, 0));
+                self.expr(default, VALUE)?;
+                self.patch(found);
+            }
+            None => {
+                self.emit(Op::Get(key));
+            }
+        }
+        self.bind(pattern)
+    }
+}
+
+// The binding forms of params that can be bound one argument per slot, `[a [b c] & d]`
+fn positional(params: &[Rc<MalType>]) -> Option<Vec<&Rc<MalType>>> {
+    let mut patterns = vec![];
+    let mut i = 0;
+    while i < params.len() {
+        match &*params[i] {
+            MalType::Symbol(symbol) if *symbol == AMPERSAND => {
+                if i + 2 != params.len() {
+                    return None;
+                }
+                patterns.push(&params[i + 1]);
+                i += 2;
+            }
+            MalType::Keyword(keyword) if *keyword == AS => return None,
+            _ => {
+                patterns.push(&params[i]);
+                i += 1;
+            }
+        }
+    }
+    Some(patterns)
+}
+
+// Whether name shows up inside a fn* in ast, shadowed or not
+fn used_by_fn(ast: &Rc<MalType>, name: Symbol) -> bool {
+    match &**ast {
+        MalType::List(list) => match list.first().map(|first| &**first) {
+            Some(MalType::Symbol(symbol)) if *symbol == symbol::FN => mentions(ast, name),
+            // what a macro expands to isn't known yet, it may make a closure
+            Some(MalType::Symbol(symbol)) if namespace::macro_for(*symbol).is_some() => {
+                mentions(ast, name)
+            }
+            _ => list.iter().any(|item| used_by_fn(item, name)),
+        },
+        MalType::Vector(items) => items.iter().any(|item| used_by_fn(item, name)),
+        MalType::HashMap(kvs) => kvs.iter().any(|(_, value)| used_by_fn(value, name)),
+        _ => false,
+    }
+}
+
+fn mentions(ast: &Rc<MalType>, name: Symbol) -> bool {
+    match &**ast {
+        MalType::Symbol(symbol) => *symbol == name,
+        MalType::List(items) | MalType::Vector(items) => {
+            items.iter().any(|item| mentions(item, name))
+        }
+        MalType::HashMap(kvs) => kvs
+            .iter()
+            .any(|(key, value)| mentions(key, name) || mentions(value, name)),
+        _ => false,
+    }
+}
+
+// Vectors and maps with nothing to evaluate inside are compiled as constants
+fn is_constant(ast: &Rc<MalType>) -> bool {
+    match &**ast {
+        MalType::Symbol(_) => false,
+        MalType::List(list) => list.is_empty(),
+        MalType::Vector(items) => items.iter().all(is_constant),
+        MalType::HashMap(kvs) => kvs.iter().all(|(_, value)| is_constant(value)),
+        _ => true,
+    }
+}
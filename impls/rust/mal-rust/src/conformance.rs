@@ -0,0 +1,385 @@
+// Runs the tests/step*.mal conformance files in-process, the way runtest.py runs them
+// against the REPL. Each form is evaluated on its own, then what it printed followed
+// by its printed value has to match the ;/ and ;=> lines after it.
+
+use crate::interpreter::Interpreter;
+use crate::reader::read_str;
+use crate::shared::Rc;
+use crate::types::MalType;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::thread;
+
+// Where a case is in its file, the later sections are what a step may leave out
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Section {
+    Required,
+    Deferrable,
+    Optional,
+}
+
+pub struct Case {
+    pub line: usize,
+    pub form: String,
+    // A regex for what the form prints, a line for each ;/
+    pub out: String,
+    // What it evaluates to printed, None when it isn't looked at
+    pub ret: Option<String>,
+    pub section: Section,
+    // Failing it doesn't fail the file
+    pub soft: bool,
+}
+
+pub struct Failure {
+    pub case: Case,
+    pub expected: String,
+    pub got: String,
+}
+
+// Cases passed and failed in each section, in the order of Section
+#[derive(Default)]
+pub struct Report {
+    pub passed: [usize; 3],
+    pub failed: [usize; 3],
+    pub soft_failed: [usize; 3],
+    pub failures: Vec<Failure>,
+}
+
+impl Report {
+    pub fn is_success(&self) -> bool {
+        self.failed.iter().all(|failed| *failed == 0)
+    }
+}
+
+// The settings of ;>>> lines are Python, `soft=True` and the like
+fn setting(line: &str, name: &str) -> Option<bool> {
+    line.split([',', ';'])
+        .filter_map(|setting| setting.split_once('='))
+        .find(|(key, _)| key.trim() == name)
+        .map(|(_, value)| value.trim() == "True")
+}
+
+pub fn parse(source: &str) -> Result<Vec<Case>, String> {
+    let mut cases = vec![];
+    let mut section = Section::Required;
+    let mut soft = false;
+    let mut lines = source.split('\n').enumerate().peekable();
+    while let Some((index, line)) = lines.next() {
+        if line.trim().is_empty() || line.starts_with(";;") {
+            continue;
+        }
+        if let Some(settings) = line.strip_prefix(";>>> ") {
+            soft = setting(settings, "soft").unwrap_or(soft);
+            if setting(settings, "deferrable") == Some(true) && section == Section::Required {
+                section = Section::Deferrable;
+            }
+            if setting(settings, "optional") == Some(true) {
+                section = Section::Optional;
+            }
+            continue;
+        }
+        if line.starts_with(';') {
+            return Err(format!(
+                "Unexpected comment at line {}: {}",
+                index + 1,
+                line
+            ));
+        }
+        let mut out = String::new();
+        let mut ret = None;
+        while let Some((_, next)) = lines.peek() {
+            if let Some(value) = next.strip_prefix(";=>") {
+                ret = Some(value.to_string());
+                lines.next();
+                break;
+            } else if let Some(printed) = next.strip_prefix(";/") {
+                out.push_str(printed);
+                out.push('\n');
+                lines.next();
+            } else {
+                break;
+            }
+        }
+        // Without a value the output doesn't end in a line break
+        if ret.is_none() && out.ends_with('\n') {
+            out.pop();
+        }
+        cases.push(Case {
+            line: index + 1,
+            form: line.to_string(),
+            out,
+            ret,
+            section,
+            soft,
+        });
+    }
+    Ok(cases)
+}
+
+// Runs body with what it writes to stdout and stderr, at the file descriptor level
+// so the errors builtins print are caught as well
+fn capture<T>(body: impl FnOnce() -> T) -> io::Result<(T, String)> {
+    let (mut reader, writer) = io::pipe()?;
+    let reading = thread::spawn(move || {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).map(|_| bytes)
+    });
+    io::stdout().flush()?;
+    io::stderr().flush()?;
+    let saved = unsafe { [libc::dup(1), libc::dup(2)] };
+    if saved.iter().any(|fd| *fd < 0) {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe {
+        libc::dup2(writer.as_raw_fd(), 1);
+        libc::dup2(writer.as_raw_fd(), 2);
+    }
+    drop(writer);
+    let result = body();
+    io::stdout().flush().ok();
+    io::stderr().flush().ok();
+    unsafe {
+        libc::dup2(saved[0], 1);
+        libc::dup2(saved[1], 2);
+        libc::close(saved[0]);
+        libc::close(saved[1]);
+    }
+    let bytes = reading
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("reading the output failed")))?;
+    Ok((result, String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+// What the REPL shows for the form, after echoing it
+fn rep(interpreter: &Interpreter, form: &str) -> String {
+    let printed = match read_str(form) {
+        Ok((_, ast)) => interpreter.eval(ast).and_then(|mal| interpreter.print(mal)),
+        _ => Some(String::from("EOF")),
+    };
+    printed.unwrap_or_else(|| String::from("Error"))
+}
+
+pub fn run(cases: Vec<Case>, interpreter: &Interpreter) -> io::Result<Report> {
+    let mut report = Report::default();
+    for case in cases {
+        let (printed, out) = capture(|| rep(interpreter, &case.form))?;
+        let section = case.section as usize;
+        let ret = match &case.ret {
+            Some(ret) => ret.as_str(),
+            None if case.out.is_empty() => {
+                report.passed[section] += 1;
+                continue;
+            }
+            None => "",
+        };
+        let got = format!("{}\n{}{}", case.form, out, printed);
+        let expected = format!(".*\n{}{}", case.out, escape(ret));
+        if Regex::new(&expected).is_some_and(|regex| regex.is_match(&got)) {
+            report.passed[section] += 1;
+            continue;
+        }
+        if case.soft {
+            report.soft_failed[section] += 1;
+        } else {
+            report.failed[section] += 1;
+        }
+        report.failures.push(Failure {
+            case,
+            expected,
+            got,
+        });
+    }
+    Ok(report)
+}
+
+// Runs a test file on a fresh interpreter from the tests directory it is in, or else
+// the directory it is in, as the files load others through ../tests/ and ../lib/
+pub fn run_file(path: &Path) -> Result<Report, String> {
+    let source = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let cases = parse(&source)?;
+    let interpreter = Interpreter::from_env()?;
+    interpreter.set("*ARGV*", Rc::new(MalType::List(vec![])));
+    let path = fs::canonicalize(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let dir = path.parent().map(|dir| {
+        dir.ancestors()
+            .find(|dir| dir.file_name().is_some_and(|name| name == "tests"))
+            .unwrap_or(dir)
+    });
+    let cwd = std::env::current_dir().map_err(|err| err.to_string())?;
+    if let Some(dir) = dir {
+        std::env::set_current_dir(dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+    }
+    let report = run(cases, &interpreter);
+    std::env::set_current_dir(cwd).ok();
+    report.map_err(|err| err.to_string())
+}
+
+// The subset of Python regexes the test files use: literals, ., [classes], (groups
+// with|alternatives), the * + ? quantifiers and \ escapes. . matches line breaks too.
+enum Node {
+    Char(char),
+    Any,
+    Class(Vec<(char, char)>, bool),
+    Group(Vec<Vec<Node>>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+pub struct Regex(Vec<Node>);
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if "\\.^$*+?()[]{}|".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl Regex {
+    // None for what isn't in the subset
+    pub fn new(pattern: &str) -> Option<Self> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut pos = 0;
+        let alternatives = parse_alternatives(&chars, &mut pos)?;
+        if pos < chars.len() {
+            return None;
+        }
+        Some(Regex(vec![Node::Group(alternatives)]))
+    }
+
+    // Whether it matches anywhere in text
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        (0..=text.len()).any(|start| match_here(&self.0, &text, start, &|_| true))
+    }
+}
+
+fn parse_alternatives(chars: &[char], pos: &mut usize) -> Option<Vec<Vec<Node>>> {
+    let mut alternatives = vec![parse_sequence(chars, pos)?];
+    while chars.get(*pos) == Some(&'|') {
+        *pos += 1;
+        alternatives.push(parse_sequence(chars, pos)?);
+    }
+    Some(alternatives)
+}
+
+fn parse_sequence(chars: &[char], pos: &mut usize) -> Option<Vec<Node>> {
+    let mut nodes = vec![];
+    while let Some(&c) = chars.get(*pos) {
+        *pos += 1;
+        let node = match c {
+            '|' | ')' => {
+                *pos -= 1;
+                break;
+            }
+            '.' => Node::Any,
+            '\\' => {
+                let escaped = *chars.get(*pos)?;
+                *pos += 1;
+                if escaped.is_ascii_alphanumeric() {
+                    // Classes like \d and backreferences
+                    return None;
+                }
+                Node::Char(escaped)
+            }
+            '(' => {
+                if chars.get(*pos) == Some(&'?') {
+                    return None;
+                }
+                let alternatives = parse_alternatives(chars, pos)?;
+                if chars.get(*pos) != Some(&')') {
+                    return None;
+                }
+                *pos += 1;
+                Node::Group(alternatives)
+            }
+            '[' => parse_class(chars, pos)?,
+            '*' | '+' | '?' => {
+                let (min, max) = match c {
+                    '*' => (0, None),
+                    '+' => (1, None),
+                    _ => (0, Some(1)),
+                };
+                let node = nodes.pop()?;
+                Node::Repeat(Box::new(node), min, max)
+            }
+            '^' | '$' | '{' => return None,
+            c => Node::Char(c),
+        };
+        nodes.push(node);
+    }
+    Some(nodes)
+}
+
+fn parse_class(chars: &[char], pos: &mut usize) -> Option<Node> {
+    let negated = chars.get(*pos) == Some(&'^');
+    if negated {
+        *pos += 1;
+    }
+    let mut ranges = vec![];
+    loop {
+        let mut c = *chars.get(*pos)?;
+        *pos += 1;
+        match c {
+            ']' if !ranges.is_empty() => return Some(Node::Class(ranges, negated)),
+            '\\' => {
+                c = *chars.get(*pos)?;
+                *pos += 1;
+            }
+            _ => {}
+        }
+        if chars.get(*pos) == Some(&'-') && chars.get(*pos + 1).is_some_and(|end| *end != ']') {
+            ranges.push((c, chars[*pos + 1]));
+            *pos += 2;
+        } else {
+            ranges.push((c, c));
+        }
+    }
+}
+
+// Backtracking, rest is handed where each way of matching nodes ends
+fn match_here(nodes: &[Node], text: &[char], pos: usize, rest: &dyn Fn(usize) -> bool) -> bool {
+    match nodes.split_first() {
+        None => rest(pos),
+        Some((node, nodes)) => {
+            match_node(node, text, pos, &|pos| match_here(nodes, text, pos, rest))
+        }
+    }
+}
+
+fn match_node(node: &Node, text: &[char], pos: usize, rest: &dyn Fn(usize) -> bool) -> bool {
+    match node {
+        Node::Char(c) => text.get(pos) == Some(c) && rest(pos + 1),
+        Node::Any => pos < text.len() && rest(pos + 1),
+        Node::Class(ranges, negated) => {
+            text.get(pos)
+                .is_some_and(|c| ranges.iter().any(|(low, high)| low <= c && c <= high) != *negated)
+                && rest(pos + 1)
+        }
+        Node::Group(alternatives) => alternatives
+            .iter()
+            .any(|alternative| match_here(alternative, text, pos, rest)),
+        Node::Repeat(node, min, max) => repeat(node, *min, *max, 0, text, pos, rest),
+    }
+}
+
+// Greedy, as many times as possible first
+fn repeat(
+    node: &Node,
+    min: usize,
+    max: Option<usize>,
+    count: usize,
+    text: &[char],
+    pos: usize,
+    rest: &dyn Fn(usize) -> bool,
+) -> bool {
+    let more = max.is_none_or(|max| count < max)
+        && match_node(node, text, pos, &|next| {
+            next > pos && repeat(node, min, max, count + 1, text, next, rest)
+        });
+    more || (count >= min && rest(pos))
+}
@@ -1,145 +1,447 @@
-use crate::printer::print_str;
+use crate::budget;
+use crate::capability::Capabilities;
+use crate::channel::{select, Channel, Op};
+use crate::exception;
+use crate::file::{FileHandle, Mode};
+use crate::gc;
+use crate::printer::{print_str, try_print_str};
+use crate::process::{self, Command};
+use crate::promise::{self, Promise, State};
 use crate::reader::read_str;
-use crate::types::MalType;
-use std::cell::RefCell;
-use std::fs::File;
-use std::io::prelude::*;
+use crate::shared::{Rc, RefCell};
+use crate::stream::{self, report, Streams};
+use crate::symbol::{Symbol, ARGLISTS, EQUAL};
+use crate::testing::{self, Suite};
+use crate::types::{arglists, FuncType, MalType, KV};
+use std::cell::Cell;
+use std::fs::{self, OpenOptions};
+use std::io::{self, prelude::*};
 use std::path::Path;
-use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+fn is_function(value: &MalType) -> bool {
+    matches!(value, MalType::BuiltinFunc(..) | MalType::Func(_))
+}
+
+fn call(func: &MalType, args: &[Rc<MalType>]) -> Option<Rc<MalType>> {
+    match func {
+        MalType::BuiltinFunc(_, func) => func(args),
+        MalType::Func(closure) => (closure.func)(args),
+        _ => None,
+    }
+}
+
+// Runs func on a worker, delivering what it returns to the future
+fn future_call(func: Rc<MalType>, args: Vec<Rc<MalType>>) -> Option<Rc<Promise>> {
+    let future = Rc::new(Promise::new(true));
+    let delivered = future.clone();
+    promise::spawn(move || {
+        delivered.deliver(call(&func, &args));
+    })?;
+    Some(future)
+}
+
+// kvs with the keys and values of pairs added, replacing the values of the keys
+// already in it
+fn assoc(kvs: &[KV], pairs: &[Rc<MalType>]) -> Option<Vec<KV>> {
+    if !pairs.len().is_multiple_of(2) {
+        report!("Odd number of arguments for a map");
+        return None;
+    }
+    let mut kvs = kvs.to_vec();
+    for pair in pairs.chunks(2) {
+        match kvs.iter_mut().find(|(key, _)| *key == pair[0]) {
+            Some((_, value)) => *value = pair[1].clone(),
+            None => kvs.push((pair[0].clone(), pair[1].clone())),
+        }
+    }
+    Some(kvs)
+}
+
+type Predicate = fn(&MalType) -> bool;
+
+fn is_truthy(value: &MalType) -> bool {
+    !matches!(value, MalType::Bool(false) | MalType::Nil)
+}
+
+fn path_arg<'a>(name: &str, args: &'a [Rc<MalType>]) -> Option<&'a str> {
+    match args.first().map(|path| &**path) {
+        Some(MalType::Str(path)) => Some(path),
+        _ => {
+            report!("{} expects a path", name);
+            None
+        }
+    }
+}
+
+// IO errors are reported along with the path they happened on
+fn io_error(path: &str, err: io::Error) -> Option<Rc<MalType>> {
+    report!("{}: {}", path, err);
+    None
+}
+
+// (sh "program" "arg" ... :in "input" :env {"NAME" "value"} :dir "path")
+fn command_arg(name: &str, args: &[Rc<MalType>]) -> Option<Command> {
+    let usage = || {
+        report!("{} expects a program, its arguments and options", name);
+        None
+    };
+    let mut strings = vec![];
+    let mut rest = args;
+    while let Some(MalType::Str(arg)) = rest.first().map(|arg| &**arg) {
+        strings.push(arg.clone());
+        rest = &rest[1..];
+    }
+    if strings.is_empty() {
+        return usage();
+    }
+    let mut command = Command {
+        program: strings.remove(0),
+        args: strings,
+        input: None,
+        env: vec![],
+        dir: None,
+    };
+    for option in rest.chunks(2) {
+        match (&*option[0], option.get(1).map(|value| &**value)) {
+            (MalType::Keyword(keyword), Some(MalType::Str(input))) if keyword.as_str() == "in" => {
+                command.input = Some(input.clone())
+            }
+            (MalType::Keyword(keyword), Some(MalType::Str(dir))) if keyword.as_str() == "dir" => {
+                command.dir = Some(dir.clone())
+            }
+            (MalType::Keyword(keyword), Some(MalType::HashMap(vars)))
+                if keyword.as_str() == "env" =>
+            {
+                for (name, value) in vars.iter() {
+                    match (&**name, &**value) {
+                        (MalType::Str(name), MalType::Str(value)) => {
+                            command.env.push((name.clone(), value.clone()))
+                        }
+                        _ => return usage(),
+                    }
+                }
+            }
+            _ => return usage(),
+        }
+    }
+    Some(command)
+}
+
+// (parse-args *ARGV* {:output {:short "o" :default "a.out"} :verbose {:flag true}})
+// gives {:output "x" :verbose true :args ("rest" ...)} for `-o x --verbose rest ...`.
+// --name=value works too, -- ends the options.
+fn parse_args(argv: &[Rc<MalType>], spec: &[KV]) -> Option<Rc<MalType>> {
+    struct Opt {
+        name: Symbol,
+        short: Option<String>,
+        flag: bool,
+    }
+    let mut options = vec![];
+    let mut parsed: Vec<KV> = vec![];
+    for (name, settings) in spec.iter() {
+        let (name, settings) = match (&**name, &**settings) {
+            (MalType::Keyword(name), MalType::HashMap(settings)) => (*name, settings),
+            _ => {
+                report!("parse-args expects options as keywords mapped to their settings");
+                return None;
+            }
+        };
+        let setting = |setting: &str| {
+            settings
+                .iter()
+                .find(|(key, _)| matches!(&**key, MalType::Keyword(key) if key.as_str() == setting))
+                .map(|(_, value)| value.clone())
+        };
+        let flag = setting("flag").is_some_and(|flag| is_truthy(&flag));
+        match setting("default") {
+            Some(default) => parsed.push((Rc::new(MalType::Keyword(name)), default)),
+            None if flag => parsed.push((
+                Rc::new(MalType::Keyword(name)),
+                Rc::new(MalType::Bool(false)),
+            )),
+            None => {}
+        }
+        let short = match setting("short").as_deref() {
+            Some(MalType::Str(short)) => Some(short.clone()),
+            _ => None,
+        };
+        options.push(Opt { name, short, flag });
+    }
+    let mut rest = vec![];
+    let mut args = argv.iter();
+    while let Some(arg) = args.next() {
+        let arg = match &**arg {
+            MalType::Str(arg) => arg,
+            _ => {
+                report!("parse-args expects a list of strings");
+                return None;
+            }
+        };
+        if arg == "--" {
+            rest.extend(args.cloned());
+            break;
+        }
+        let (option, value) = if let Some(long) = arg.strip_prefix("--") {
+            let (name, value) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (long, None),
+            };
+            let option = options.iter().find(|option| option.name.as_str() == name);
+            (option, value)
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            let option = options
+                .iter()
+                .find(|option| option.short.as_deref() == Some(&arg[1..]));
+            (option, None)
+        } else {
+            rest.push(Rc::new(MalType::Str(arg.clone())));
+            continue;
+        };
+        let option = match option {
+            Some(option) => option,
+            None => {
+                report!("Unknown option {}", arg);
+                return None;
+            }
+        };
+        let value = match (option.flag, value) {
+            (true, None) => Rc::new(MalType::Bool(true)),
+            (false, Some(value)) => Rc::new(MalType::Str(value.to_string())),
+            (false, None) => match args.next() {
+                Some(value) => value.clone(),
+                None => {
+                    report!("Option {} expects a value", arg);
+                    return None;
+                }
+            },
+            (true, Some(_)) => {
+                report!("Option {} takes no value", arg);
+                return None;
+            }
+        };
+        parsed.retain(|(key, _)| !matches!(&**key, MalType::Keyword(key) if *key == option.name));
+        parsed.push((Rc::new(MalType::Keyword(option.name)), value));
+    }
+    parsed.push((
+        Rc::new(MalType::Keyword(Symbol::new("args"))),
+        Rc::new(MalType::List(rest)),
+    ));
+    Some(Rc::new(MalType::HashMap(parsed)))
+}
+
+// Calls inner inside fixtures, outermost first, each handed a function going on with
+// the rest of them
+fn with_fixtures(fixtures: &[Rc<MalType>], inner: Rc<MalType>) -> Option<Rc<MalType>> {
+    match fixtures.split_first() {
+        None => call(&inner, &[]),
+        Some((fixture, rest)) => {
+            let rest = rest.to_vec();
+            let next = MalType::BuiltinFunc(
+                String::from("run"),
+                Rc::new(move |_| with_fixtures(&rest, inner.clone())),
+            );
+            call(fixture, &[Rc::new(next)])
+        }
+    }
+}
+
+// Runs each test inside the :each fixtures, None if the run was cut short
+fn run_tests(suite: &Rc<Suite>) -> Option<Rc<MalType>> {
+    let each = suite.fixtures(false);
+    for (name, test) in suite.tests() {
+        suite.start(&name);
+        let started = Instant::now();
+        if with_fixtures(&each, test).is_none() {
+            budget::check()?;
+            suite.error("the test failed with an error, see its output");
+        }
+        suite.finish(started.elapsed());
+    }
+    Some(Rc::new(MalType::Nil))
+}
+
+thread_local! {
+    // How many go blocks are running on this thread. Without the `threads` feature
+    // they run to the end when they are made, one inside the other.
+    static IN_GO: Cell<usize> = const { Cell::new(0) };
+}
+
+// A channel operation nothing else could ever complete without the `threads` feature
+fn blocked() -> Option<Rc<MalType>> {
+    budget::check()?;
+    if IN_GO.with(Cell::get) > 0 {
+        report!(
+            "Channel operation in a go block would block forever, go blocks only wait for \
+             each other with the threads feature"
+        );
+    } else {
+        report!("Channel operation would block forever");
+    }
+    None
+}
 
 pub struct NameSpace {
     pub builtin: Vec<(&'static str, MalType)>,
+    // Where the builtins print to and read from
+    pub streams: Rc<Streams>,
+}
+
+impl Default for NameSpace {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NameSpace {
     pub fn new() -> Self {
-        let mut builtin = vec![];
+        Self::with_capabilities(Capabilities::all())
+    }
+
+    pub fn with_capabilities(capabilities: Capabilities) -> Self {
+        let capabilities = Rc::new(capabilities);
+        let streams = Rc::new(Streams::new(capabilities.stdout, capabilities.stdin));
+        let suite = Rc::new(Suite::default());
+        let standard = FileHandle::standard(&streams)
+            .map(|(name, file)| (name, Rc::new(MalType::File(Rc::new(file)))));
+        let mut builtin: Vec<(&'static str, Rc<FuncType>)> = vec![];
 
         builtin.push((
             "+",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.len() != 2 {
-                    println!("Wrong amount of arguments for +");
+                    report!("Wrong amount of arguments for +");
                     return None;
                 }
                 match (&*args[0], &*args[1]) {
                     (MalType::Int(a), MalType::Int(b)) => Some(Rc::new(MalType::Int(a + b))),
                     _ => None,
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             "-",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.len() != 2 {
-                    println!("Wrong amount of arguments for -");
+                    report!("Wrong amount of arguments for -");
                     return None;
                 }
                 match (&*args[0], &*args[1]) {
                     (MalType::Int(a), MalType::Int(b)) => Some(Rc::new(MalType::Int(a - b))),
                     _ => None,
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             "*",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.len() != 2 {
-                    println!("Wrong amount of arguments for *");
+                    report!("Wrong amount of arguments for *");
                     return None;
                 }
                 match (&*args[0], &*args[1]) {
                     (MalType::Int(a), MalType::Int(b)) => Some(Rc::new(MalType::Int(a * b))),
                     _ => None,
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             "/",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.len() != 2 {
-                    println!("Wrong amount of arguments for /");
+                    report!("Wrong amount of arguments for /");
                     return None;
                 }
                 match (&*args[0], &*args[1]) {
                     (MalType::Int(a), MalType::Int(b)) => Some(Rc::new(MalType::Int(a / b))),
                     _ => None,
                 }
-            })),
+            }),
         ));
 
+        let out = streams.clone();
         builtin.push((
             "prn",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(move |args| {
                 let mut result = String::from("");
                 for i in 0..args.len() {
-                    result += &print_str(args[i].clone(), false, true);
+                    result += &try_print_str(args[i].clone(), false, true)?;
                     if i != args.len() - 1 {
                         result.push(' ');
                     }
                 }
-                println!("{}", result);
+                if let Err(err) = out.print(&(result + "\n")) {
+                    return io_error("*out*", err);
+                }
                 Some(Rc::new(MalType::Nil))
-            })),
+            }),
         ));
 
         builtin.push((
             "pr-str",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 let mut result = String::from("");
                 for i in 0..args.len() {
-                    result += &print_str(args[i].clone(), false, true);
+                    result += &try_print_str(args[i].clone(), false, true)?;
                     if i != args.len() - 1 {
                         result.push(' ');
                     }
                 }
+                budget::allocate(result.len() / 8)?;
                 Some(Rc::new(MalType::Str(result)))
-            })),
+            }),
         ));
 
         builtin.push((
             "str",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 let mut result = String::from("");
                 for arg in args.iter() {
-                    result += &print_str(arg.clone(), false, false);
+                    result += &try_print_str(arg.clone(), false, false)?;
                 }
+                budget::allocate(result.len() / 8)?;
                 Some(Rc::new(MalType::Str(result)))
-            })),
+            }),
         ));
 
+        let out = streams.clone();
         builtin.push((
             "println",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(move |args| {
                 let mut result = String::from("");
                 for i in 0..args.len() {
-                    result += &print_str(args[i].clone(), false, false);
+                    result += &try_print_str(args[i].clone(), false, false)?;
                     if i != args.len() - 1 {
                         result.push(' ');
                     }
                 }
-                println!("{}", result);
+                if let Err(err) = out.print(&(result + "\n")) {
+                    return io_error("*out*", err);
+                }
                 Some(Rc::new(MalType::Nil))
-            })),
+            }),
         ));
 
         builtin.push((
             "list",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 let mut list = vec![];
                 for item in args.iter() {
                     list.push(item.clone());
                 }
+                budget::allocate(list.len())?;
                 Some(Rc::new(MalType::List(list)))
-            })),
+            }),
         ));
 
         builtin.push((
             "list?",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.is_empty() {
                     Some(Rc::new(MalType::Nil))
                 } else if let MalType::List(_) = &*args[0] {
@@ -147,12 +449,12 @@ impl NameSpace {
                 } else {
                     Some(Rc::new(MalType::Bool(false)))
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             "empty?",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.is_empty() {
                     Some(Rc::new(MalType::Nil))
                 } else if let MalType::List(list) | MalType::Vector(list) = &*args[0] {
@@ -160,12 +462,12 @@ impl NameSpace {
                 } else {
                     Some(Rc::new(MalType::Nil))
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             "count",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.is_empty() {
                     Some(Rc::new(MalType::Nil))
                 } else if let MalType::List(list) | MalType::Vector(list) = &*args[0] {
@@ -173,122 +475,437 @@ impl NameSpace {
                 } else {
                     Some(Rc::new(MalType::Int(0)))
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             "=",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.len() != 2 {
-                    println!("Wrong amount of arguments for =");
+                    report!("Wrong amount of arguments for =");
                     return None;
                 }
                 Some(Rc::new(MalType::Bool(args[0] == args[1])))
-            })),
+            }),
         ));
 
         builtin.push((
             "<",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.len() != 2 {
-                    println!("Wrong amount of arguments for <");
+                    report!("Wrong amount of arguments for <");
                     return None;
                 }
                 match (&*args[0], &*args[1]) {
                     (MalType::Int(a), MalType::Int(b)) => Some(Rc::new(MalType::Bool(a < b))),
                     _ => None,
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             "<=",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.len() != 2 {
-                    println!("Wrong amount of arguments for <=");
+                    report!("Wrong amount of arguments for <=");
                     return None;
                 }
                 match (&*args[0], &*args[1]) {
                     (MalType::Int(a), MalType::Int(b)) => Some(Rc::new(MalType::Bool(a <= b))),
                     _ => None,
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             ">",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.len() != 2 {
-                    println!("Wrong amount of arguments for >");
+                    report!("Wrong amount of arguments for >");
                     return None;
                 }
                 match (&*args[0], &*args[1]) {
                     (MalType::Int(a), MalType::Int(b)) => Some(Rc::new(MalType::Bool(a > b))),
                     _ => None,
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             ">=",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.len() != 2 {
-                    println!("Wrong amount of arguments for >=");
+                    report!("Wrong amount of arguments for >=");
                     return None;
                 }
                 match (&*args[0], &*args[1]) {
                     (MalType::Int(a), MalType::Int(b)) => Some(Rc::new(MalType::Bool(a >= b))),
                     _ => None,
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             "read-string",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if !args.is_empty() {
                     if let MalType::Str(s) = &*args[0] {
+                        budget::allocate(s.len() / 8)?;
                         if let Ok((_, mal)) = read_str(s) {
                             return Some(mal);
                         }
                     }
                 }
                 Some(Rc::new(MalType::Nil))
-            })),
+            }),
         ));
 
+        let allowed = capabilities.clone();
         builtin.push((
             "slurp",
-            MalType::BuiltinFunc(Rc::new(|args| {
-                if !args.is_empty() {
-                    if let MalType::Str(s) = &*args[0] {
-                        let path = Path::new(&s);
-                        if let Ok(mut file) = File::open(&path) {
-                            let mut content = String::new();
-                            if file.read_to_string(&mut content).is_ok() {
-                                return Some(Rc::new(MalType::Str(content)));
-                            }
+            Rc::new(move |args| {
+                let path = path_arg("slurp", args)?;
+                allowed.check_read(path)?;
+                match fs::read_to_string(path) {
+                    Ok(content) => {
+                        budget::allocate(content.len() / 8)?;
+                        Some(Rc::new(MalType::Str(content)))
+                    }
+                    Err(err) => io_error(path, err),
+                }
+            }),
+        ));
+
+        let io = streams.clone();
+        builtin.push((
+            "readline",
+            Rc::new(move |args| {
+                if let Some(MalType::Str(prompt)) = args.first().map(|prompt| &**prompt) {
+                    let prompted = io.print(prompt).and_then(|_| io.flush());
+                    if let Err(err) = prompted {
+                        return io_error("*out*", err);
+                    }
+                }
+                match io.read() {
+                    Ok(Some(line)) => Some(Rc::new(MalType::Str(line))),
+                    Ok(None) => Some(Rc::new(MalType::Nil)),
+                    Err(err) => io_error("*in*", err),
+                }
+            }),
+        ));
+
+        let allowed = capabilities.clone();
+        builtin.push((
+            "spit",
+            Rc::new(move |args| {
+                let path = path_arg("spit", args)?;
+                allowed.check_write(path)?;
+                let content = match args.get(1) {
+                    Some(content) => try_print_str(content.clone(), false, false)?,
+                    None => String::new(),
+                };
+                // (spit path content :append true) adds to the end of the file
+                let options = args.get(2..).unwrap_or(&[]);
+                let append = options
+                    .chunks(2)
+                    .any(|option| match (&*option[0], option.get(1)) {
+                        (MalType::Keyword(keyword), Some(on)) => {
+                            keyword.as_str() == "append" && is_truthy(on)
                         }
+                        _ => false,
+                    });
+                let written = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .append(append)
+                    .truncate(!append)
+                    .open(path)
+                    .and_then(|mut file| file.write_all(content.as_bytes()));
+                match written {
+                    Ok(()) => Some(Rc::new(MalType::Nil)),
+                    Err(err) => io_error(path, err),
+                }
+            }),
+        ));
+
+        let allowed = capabilities.clone();
+        builtin.push((
+            "file-exists?",
+            Rc::new(move |args| {
+                let path = path_arg("file-exists?", args)?;
+                allowed.check_read(path)?;
+                Some(Rc::new(MalType::Bool(Path::new(path).exists())))
+            }),
+        ));
+
+        let allowed = capabilities.clone();
+        builtin.push((
+            "delete-file",
+            Rc::new(move |args| {
+                let path = path_arg("delete-file", args)?;
+                allowed.check_write(path)?;
+                // Directories only go if they are empty
+                let deleted = if Path::new(path).is_dir() {
+                    fs::remove_dir(path)
+                } else {
+                    fs::remove_file(path)
+                };
+                match deleted {
+                    Ok(()) => Some(Rc::new(MalType::Nil)),
+                    Err(err) => io_error(path, err),
+                }
+            }),
+        ));
+
+        let allowed = capabilities.clone();
+        builtin.push((
+            "list-dir",
+            Rc::new(move |args| {
+                let path = path_arg("list-dir", args)?;
+                allowed.check_read(path)?;
+                let entries = match fs::read_dir(path) {
+                    Ok(entries) => entries,
+                    Err(err) => return io_error(path, err),
+                };
+                let mut names = vec![];
+                for entry in entries {
+                    match entry {
+                        Ok(entry) => names.push(entry.file_name().to_string_lossy().into_owned()),
+                        Err(err) => return io_error(path, err),
                     }
                 }
-                Some(Rc::new(MalType::Nil))
-            })),
+                names.sort();
+                budget::allocate(names.len())?;
+                Some(Rc::new(MalType::List(
+                    names
+                        .into_iter()
+                        .map(|name| Rc::new(MalType::Str(name)))
+                        .collect(),
+                )))
+            }),
+        ));
+
+        let allowed = capabilities.clone();
+        builtin.push((
+            "mkdir",
+            Rc::new(move |args| {
+                let path = path_arg("mkdir", args)?;
+                allowed.check_write(path)?;
+                match fs::create_dir_all(path) {
+                    Ok(()) => Some(Rc::new(MalType::Nil)),
+                    Err(err) => io_error(path, err),
+                }
+            }),
+        ));
+
+        let allowed = capabilities.clone();
+        builtin.push((
+            "open",
+            Rc::new(move |args| {
+                // (open path :read), :write or :append, reading by default
+                let path = path_arg("open", args)?;
+                let mode = match args.get(1).map(|mode| &**mode) {
+                    None => Mode::Read,
+                    Some(MalType::Keyword(mode)) if mode.as_str() == "read" => Mode::Read,
+                    Some(MalType::Keyword(mode)) if mode.as_str() == "write" => Mode::Write,
+                    Some(MalType::Keyword(mode)) if mode.as_str() == "append" => Mode::Append,
+                    Some(_) => {
+                        report!("open expects :read, :write or :append");
+                        return None;
+                    }
+                };
+                match mode {
+                    Mode::Read => allowed.check_read(path)?,
+                    _ => allowed.check_write(path)?,
+                }
+                match FileHandle::open(path, mode) {
+                    Ok(file) => Some(Rc::new(MalType::File(Rc::new(file)))),
+                    Err(err) => io_error(path, err),
+                }
+            }),
+        ));
+
+        // (read-line) reads *in*
+        let (io, stdin) = (streams.clone(), capabilities.stdin);
+        builtin.push((
+            "read-line",
+            Rc::new(move |args| {
+                let (path, read) = match args.first().map(|file| &**file) {
+                    Some(MalType::File(file)) => (file.path.as_str(), file.read_line()),
+                    None if stdin => ("*in*", io.read()),
+                    _ => {
+                        report!("read-line expects a file");
+                        return None;
+                    }
+                };
+                match read {
+                    Ok(Some(line)) => {
+                        budget::allocate(line.len() / 8)?;
+                        Some(Rc::new(MalType::Str(line)))
+                    }
+                    Ok(None) => Some(Rc::new(MalType::Nil)),
+                    Err(err) => io_error(path, err),
+                }
+            }),
+        ));
+
+        builtin.push((
+            "write",
+            Rc::new(|args| match args.first().map(|file| &**file) {
+                Some(MalType::File(file)) => {
+                    for value in args[1..].iter() {
+                        let text = try_print_str(value.clone(), false, false)?;
+                        if let Err(err) = file.write(&text) {
+                            return io_error(&file.path, err);
+                        }
+                    }
+                    Some(Rc::new(MalType::Nil))
+                }
+                _ => {
+                    report!("write expects a file");
+                    None
+                }
+            }),
+        ));
+
+        builtin.push((
+            "close",
+            Rc::new(|args| match args.first().map(|file| &**file) {
+                Some(MalType::File(file)) => match file.close() {
+                    Ok(()) => Some(Rc::new(MalType::Nil)),
+                    Err(err) => io_error(&file.path, err),
+                },
+                _ => {
+                    report!("close expects a file");
+                    None
+                }
+            }),
+        ));
+
+        builtin.push((
+            "with-open-call",
+            Rc::new(|args| {
+                if args.len() < 2 || !is_function(&args[1]) {
+                    report!("with-open-call expects a file and a function");
+                    return None;
+                }
+                let result = call(&args[1], &args[..1]);
+                if let MalType::File(file) = &*args[0] {
+                    if let Err(err) = file.close() {
+                        return io_error(&file.path, err);
+                    }
+                }
+                result
+            }),
+        ));
+
+        builtin.push((
+            "sh",
+            Rc::new(|args| {
+                let command = command_arg("sh", args)?;
+                let output = match command.run() {
+                    Some(Ok(output)) => output,
+                    Some(Err(err)) => return io_error(&command.program, err),
+                    None => {
+                        budget::check()?;
+                        return None;
+                    }
+                };
+                budget::allocate((output.out.len() + output.err.len()) / 8)?;
+                let entry = |name, value| (Rc::new(MalType::Keyword(Symbol::new(name))), value);
+                Some(Rc::new(MalType::HashMap(vec![
+                    entry("exit", Rc::new(MalType::Int(output.exit))),
+                    entry("out", Rc::new(MalType::Str(output.out))),
+                    entry("err", Rc::new(MalType::Str(output.err))),
+                ])))
+            }),
+        ));
+
+        builtin.push((
+            "process",
+            Rc::new(|args| {
+                // Like sh, but the output is read from :out and :err as it comes and
+                // without :in the input is written to the :in file
+                let command = command_arg("process", args)?;
+                let pipes = match command.spawn() {
+                    Ok(pipes) => pipes,
+                    Err(err) => return io_error(&command.program, err),
+                };
+                let file = |file| Rc::new(MalType::File(Rc::new(file)));
+                let name = |stream| format!("{} {}", command.program, stream);
+                let entry = |name, value| (Rc::new(MalType::Keyword(Symbol::new(name))), value);
+                let mut process = vec![
+                    entry("pid", Rc::new(MalType::Int(pipes.pid as i32))),
+                    entry(
+                        "out",
+                        file(FileHandle::reader(&name("stdout"), pipes.stdout)),
+                    ),
+                    entry(
+                        "err",
+                        file(FileHandle::reader(&name("stderr"), pipes.stderr)),
+                    ),
+                ];
+                if let Some(stdin) = pipes.stdin {
+                    process.push(entry("in", file(FileHandle::writer(&name("stdin"), stdin))));
+                }
+                Some(Rc::new(MalType::HashMap(process)))
+            }),
+        ));
+
+        builtin.push((
+            "wait-process",
+            Rc::new(|args| {
+                // The exit code of what process started, once it exits
+                let pid = match args.first().map(|process| &**process) {
+                    Some(MalType::HashMap(process)) => {
+                        process
+                            .iter()
+                            .find_map(|(key, pid)| match (&**key, &**pid) {
+                                (MalType::Keyword(key), MalType::Int(pid))
+                                    if key.as_str() == "pid" =>
+                                {
+                                    Some(*pid as u32)
+                                }
+                                _ => None,
+                            })
+                    }
+                    _ => None,
+                };
+                let pid = match pid {
+                    Some(pid) => pid,
+                    None => {
+                        report!("wait-process expects a process");
+                        return None;
+                    }
+                };
+                match process::wait(pid) {
+                    Some(Ok(exit)) => Some(Rc::new(MalType::Int(exit))),
+                    Some(Err(err)) => io_error(&pid.to_string(), err),
+                    None => {
+                        budget::check()?;
+                        None
+                    }
+                }
+            }),
         ));
 
         builtin.push((
             "atom",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.is_empty() {
                     Some(Rc::new(MalType::Nil))
                 } else {
-                    Some(Rc::new(MalType::Atom(RefCell::new(args[0].clone()))))
+                    budget::allocate(0)?;
+                    let atom = Rc::new(MalType::Atom(RefCell::new(args[0].clone())));
+                    gc::track(&atom);
+                    Some(atom)
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             "atom?",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.is_empty() {
                     Some(Rc::new(MalType::Nil))
                 } else if let MalType::Atom(_) = &*args[0] {
@@ -296,25 +913,51 @@ impl NameSpace {
                 } else {
                     Some(Rc::new(MalType::Bool(false)))
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             "deref",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.is_empty() {
                     Some(Rc::new(MalType::Nil))
                 } else if let MalType::Atom(value) = &*args[0] {
                     Some(value.borrow().clone())
+                } else if let MalType::Promise(promise) = &*args[0] {
+                    // (deref p timeout-ms timeout-val) gives up waiting after timeout-ms
+                    let timeout = match args.get(1).map(|timeout| &**timeout) {
+                        Some(MalType::Int(ms)) => Some(Duration::from_millis((*ms).max(0) as u64)),
+                        _ => None,
+                    };
+                    let state = promise.wait(timeout);
+                    if let State::Pending = state {
+                        budget::check()?;
+                    }
+                    match state {
+                        State::Delivered(value) => Some(value),
+                        State::Failed => {
+                            report!("Future failed");
+                            None
+                        }
+                        State::Pending if timeout.is_some() => Some(
+                            args.get(2)
+                                .cloned()
+                                .unwrap_or_else(|| Rc::new(MalType::Nil)),
+                        ),
+                        State::Pending => {
+                            report!("Promise is never delivered");
+                            None
+                        }
+                    }
                 } else {
                     Some(Rc::new(MalType::Nil))
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             "reset!",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.len() < 2 {
                     Some(Rc::new(MalType::Nil))
                 } else {
@@ -325,55 +968,60 @@ impl NameSpace {
                         Some(Rc::new(MalType::Nil))
                     }
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             "swap!",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.len() < 2 {
                     Some(Rc::new(MalType::Nil))
                 } else {
                     if let MalType::Atom(value) = &*args[0] {
-                        let mut list = vec![value.borrow().clone()];
-                        for parameter in args.iter().skip(2) {
-                            list.push(parameter.clone());
+                        if !is_function(&args[1]) {
+                            return Some(Rc::new(MalType::Nil));
                         }
-                        let result;
-                        match &*args[1] {
-                            MalType::BuiltinFunc(func) => result = func(&list)?,
-                            MalType::Func(closure) => result = (closure.func)(&list)?,
-                            _ => {
-                                return Some(Rc::new(MalType::Nil));
+                        // Starts over if another thread changed the atom while the
+                        // function ran, which may then be called more than once
+                        loop {
+                            let old = value.borrow().clone();
+                            let mut list = vec![old.clone()];
+                            for parameter in args.iter().skip(2) {
+                                list.push(parameter.clone());
+                            }
+                            let result = call(&args[1], &list)?;
+                            let mut current = value.borrow_mut();
+                            if Rc::ptr_eq(&current, &old) {
+                                *current = result.clone();
+                                return Some(result);
                             }
                         }
-                        value.replace(result.clone());
-                        Some(result)
                     } else {
                         Some(Rc::new(MalType::Nil))
                     }
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             "cons",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.len() < 2 {
                     Some(Rc::new(MalType::Nil))
                 } else if let MalType::List(list) | MalType::Vector(list) = &*args[1] {
+                    budget::allocate(list.len() + 1)?;
                     let mut result = list.clone();
                     result.insert(0, args[0].clone());
                     Some(Rc::new(MalType::List(result)))
                 } else {
                     Some(Rc::new(MalType::Int(0)))
                 }
-            })),
+            }),
         ));
 
         builtin.push((
             "concat",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 let mut result = vec![];
                 for arg in args {
                     if let MalType::List(list) | MalType::Vector(list) = &**arg {
@@ -382,22 +1030,956 @@ impl NameSpace {
                         }
                     }
                 }
+                budget::allocate(result.len())?;
                 Some(Rc::new(MalType::List(result)))
-            })),
+            }),
         ));
 
         builtin.push((
             "vec",
-            MalType::BuiltinFunc(Rc::new(|args| {
+            Rc::new(|args| {
                 if args.is_empty() {
                     Some(Rc::new(MalType::Nil))
                 } else if let MalType::List(list) | MalType::Vector(list) = &*args[0] {
+                    budget::allocate(list.len())?;
                     Some(Rc::new(MalType::Vector(list.clone())))
                 } else {
                     Some(Rc::new(MalType::Nil))
                 }
-            })),
+            }),
+        ));
+
+        builtin.push((
+            "nth",
+            Rc::new(|args| {
+                if args.len() != 2 {
+                    report!("Wrong amount of arguments for nth");
+                    return None;
+                }
+                match (&*args[0], &*args[1]) {
+                    (MalType::List(list) | MalType::Vector(list), MalType::Int(index)) => {
+                        match list.get(*index as usize).filter(|_| *index >= 0) {
+                            Some(item) => Some(item.clone()),
+                            None => {
+                                report!("Index {} out of bounds", index);
+                                None
+                            }
+                        }
+                    }
+                    _ => {
+                        report!("nth expects a sequence and an index");
+                        None
+                    }
+                }
+            }),
+        ));
+
+        builtin.push((
+            "first",
+            Rc::new(|args| match args.first().map(|seq| &**seq) {
+                Some(MalType::List(list) | MalType::Vector(list)) if !list.is_empty() => {
+                    Some(list[0].clone())
+                }
+                _ => Some(Rc::new(MalType::Nil)),
+            }),
+        ));
+
+        builtin.push((
+            "rest",
+            Rc::new(|args| match args.first().map(|seq| &**seq) {
+                Some(MalType::List(list) | MalType::Vector(list)) if !list.is_empty() => {
+                    budget::allocate(list.len() - 1)?;
+                    Some(Rc::new(MalType::List(list[1..].to_vec())))
+                }
+                _ => Some(Rc::new(MalType::List(vec![]))),
+            }),
+        ));
+
+        builtin.push((
+            "defmacro-call",
+            Rc::new(|args| match args.first() {
+                Some(func) if matches!(**func, MalType::Func(_)) => {
+                    Some(Rc::new(MalType::Macro(func.clone())))
+                }
+                _ => {
+                    report!("defmacro! expects a fn*");
+                    None
+                }
+            }),
+        ));
+
+        builtin.push((
+            "try-call",
+            Rc::new(|args| {
+                let (body, handler) = match args {
+                    [body] => (body, None),
+                    [body, handler] => (body, Some(handler)),
+                    _ => {
+                        report!("Wrong amount of arguments for try*");
+                        return None;
+                    }
+                };
+                let handler = match handler {
+                    Some(handler) => handler,
+                    None => return call(body, &[]),
+                };
+                let catching = exception::Catching::enter();
+                if let Some(value) = call(body, &[]) {
+                    return Some(value);
+                }
+                let thrown = exception::take();
+                drop(catching);
+                // Running out of budget or being interrupted isn't for catch* to handle
+                if budget::check().is_none() {
+                    report!("{}", print_str(thrown, false, false));
+                    return None;
+                }
+                call(handler, &[thrown])
+            }),
+        ));
+
+        builtin.push((
+            "throw",
+            Rc::new(|args| match args.first() {
+                Some(value) => exception::throw(value.clone()),
+                None => exception::throw(Rc::new(MalType::Nil)),
+            }),
+        ));
+
+        builtin.push((
+            "apply",
+            Rc::new(|args| {
+                if args.len() < 2 || !is_function(&args[0]) {
+                    report!("apply expects a function and a sequence");
+                    return None;
+                }
+                let mut list = args[1..args.len() - 1].to_vec();
+                match &*args[args.len() - 1] {
+                    MalType::List(items) | MalType::Vector(items) => {
+                        list.extend(items.iter().cloned())
+                    }
+                    MalType::Nil => {}
+                    _ => {
+                        report!("apply expects a function and a sequence");
+                        return None;
+                    }
+                }
+                call(&args[0], &list)
+            }),
+        ));
+
+        builtin.push((
+            "map",
+            Rc::new(|args| {
+                if args.len() != 2 || !is_function(&args[0]) {
+                    report!("map expects a function and a sequence");
+                    return None;
+                }
+                let items = match &*args[1] {
+                    MalType::List(items) | MalType::Vector(items) => items.as_slice(),
+                    _ => &[],
+                };
+                budget::allocate(items.len())?;
+                let mut result = vec![];
+                for item in items.iter() {
+                    result.push(call(&args[0], std::slice::from_ref(item))?);
+                }
+                Some(Rc::new(MalType::List(result)))
+            }),
+        ));
+
+        let predicates: [(&'static str, Predicate); 12] = [
+            ("nil?", |value| matches!(value, MalType::Nil)),
+            ("true?", |value| matches!(value, MalType::Bool(true))),
+            ("false?", |value| matches!(value, MalType::Bool(false))),
+            ("symbol?", |value| matches!(value, MalType::Symbol(_))),
+            ("keyword?", |value| matches!(value, MalType::Keyword(_))),
+            ("string?", |value| matches!(value, MalType::Str(_))),
+            ("number?", |value| matches!(value, MalType::Int(_))),
+            ("fn?", is_function),
+            ("vector?", |value| matches!(value, MalType::Vector(_))),
+            ("sequential?", |value| {
+                matches!(value, MalType::List(_) | MalType::Vector(_))
+            }),
+            ("map?", |value| matches!(value, MalType::HashMap(_))),
+            ("macro?", |value| matches!(value, MalType::Macro(_))),
+        ];
+        for (name, predicate) in predicates {
+            builtin.push((
+                name,
+                Rc::new(move |args| match args.first() {
+                    Some(value) => Some(Rc::new(MalType::Bool(predicate(value)))),
+                    None => {
+                        report!("Wrong amount of arguments for {}", name);
+                        None
+                    }
+                }),
+            ));
+        }
+
+        builtin.push((
+            "symbol",
+            Rc::new(|args| match args.first().map(|name| &**name) {
+                Some(MalType::Str(name)) => Some(Rc::new(MalType::Symbol(Symbol::new(name)))),
+                Some(MalType::Symbol(_)) => Some(args[0].clone()),
+                _ => {
+                    report!("symbol expects a string");
+                    None
+                }
+            }),
+        ));
+
+        builtin.push((
+            "keyword",
+            Rc::new(|args| match args.first().map(|name| &**name) {
+                Some(MalType::Str(name)) => Some(Rc::new(MalType::Keyword(Symbol::new(name)))),
+                Some(MalType::Keyword(_)) => Some(args[0].clone()),
+                _ => {
+                    report!("keyword expects a string");
+                    None
+                }
+            }),
+        ));
+
+        builtin.push((
+            "gensym",
+            Rc::new(|_| {
+                static COUNT: AtomicUsize = AtomicUsize::new(0);
+                let count = COUNT.fetch_add(1, Ordering::Relaxed);
+                Some(Rc::new(MalType::Symbol(Symbol::new(&format!(
+                    "G__{}",
+                    count
+                )))))
+            }),
+        ));
+
+        builtin.push((
+            "vector",
+            Rc::new(|args| {
+                budget::allocate(args.len())?;
+                Some(Rc::new(MalType::Vector(args.to_vec())))
+            }),
+        ));
+
+        builtin.push((
+            "hash-map",
+            Rc::new(|args| {
+                budget::allocate(args.len() / 2)?;
+                Some(Rc::new(MalType::HashMap(assoc(&[], args)?)))
+            }),
+        ));
+
+        builtin.push((
+            "assoc",
+            Rc::new(|args| match args.first().map(|map| &**map) {
+                Some(MalType::HashMap(kvs)) => {
+                    budget::allocate(kvs.len() + args.len() / 2)?;
+                    Some(Rc::new(MalType::HashMap(assoc(kvs, &args[1..])?)))
+                }
+                Some(MalType::Nil) => Some(Rc::new(MalType::HashMap(assoc(&[], &args[1..])?))),
+                _ => {
+                    report!("assoc expects a map");
+                    None
+                }
+            }),
+        ));
+
+        builtin.push((
+            "get",
+            Rc::new(|args| {
+                let found = match (args.first().map(|map| &**map), args.get(1)) {
+                    (Some(MalType::HashMap(kvs)), Some(key)) => kvs
+                        .iter()
+                        .find(|(other, _)| other == key)
+                        .map(|(_, value)| value.clone()),
+                    _ => None,
+                };
+                found
+                    .or_else(|| args.get(2).cloned())
+                    .or_else(|| Some(Rc::new(MalType::Nil)))
+            }),
+        ));
+
+        builtin.push((
+            "contains?",
+            Rc::new(|args| {
+                let found = match (args.first().map(|map| &**map), args.get(1)) {
+                    (Some(MalType::HashMap(kvs)), Some(key)) => {
+                        kvs.iter().any(|(other, _)| other == key)
+                    }
+                    _ => false,
+                };
+                Some(Rc::new(MalType::Bool(found)))
+            }),
+        ));
+
+        builtin.push((
+            "keys",
+            Rc::new(|args| match args.first().map(|map| &**map) {
+                Some(MalType::HashMap(kvs)) => {
+                    budget::allocate(kvs.len())?;
+                    Some(Rc::new(MalType::List(
+                        kvs.iter().map(|(key, _)| key.clone()).collect(),
+                    )))
+                }
+                _ => Some(Rc::new(MalType::List(vec![]))),
+            }),
+        ));
+
+        builtin.push((
+            "seq",
+            Rc::new(|args| match args.first().map(|seq| &**seq) {
+                Some(MalType::List(items) | MalType::Vector(items)) if !items.is_empty() => {
+                    budget::allocate(items.len())?;
+                    Some(Rc::new(MalType::List(items.clone())))
+                }
+                Some(MalType::Str(string)) if !string.is_empty() => {
+                    budget::allocate(string.len())?;
+                    Some(Rc::new(MalType::List(
+                        string
+                            .chars()
+                            .map(|char| Rc::new(MalType::Str(char.to_string())))
+                            .collect(),
+                    )))
+                }
+                _ => Some(Rc::new(MalType::Nil)),
+            }),
+        ));
+
+        builtin.push((
+            "conj",
+            Rc::new(|args| match args.first().map(|seq| &**seq) {
+                Some(MalType::List(items)) => {
+                    budget::allocate(items.len() + args.len())?;
+                    let mut result: Vec<Rc<MalType>> = args[1..].iter().rev().cloned().collect();
+                    result.extend(items.iter().cloned());
+                    Some(Rc::new(MalType::List(result)))
+                }
+                Some(MalType::Vector(items)) => {
+                    budget::allocate(items.len() + args.len())?;
+                    let mut result = items.clone();
+                    result.extend(args[1..].iter().cloned());
+                    Some(Rc::new(MalType::Vector(result)))
+                }
+                _ => {
+                    report!("conj expects a list or a vector");
+                    None
+                }
+            }),
+        ));
+
+        builtin.push((
+            "meta",
+            Rc::new(|args| {
+                if args.is_empty() {
+                    Some(Rc::new(MalType::Nil))
+                } else if let MalType::Func(closure) = &*args[0] {
+                    Some(Rc::new(MalType::HashMap(vec![(
+                        Rc::new(MalType::Keyword(ARGLISTS)),
+                        arglists(&closure.arities),
+                    )])))
+                } else {
+                    Some(Rc::new(MalType::Nil))
+                }
+            }),
+        ));
+
+        builtin.push((
+            "future-call",
+            Rc::new(|args| {
+                if args.is_empty() || !is_function(&args[0]) {
+                    report!("future-call expects a function");
+                    return None;
+                }
+                let future = future_call(args[0].clone(), vec![])?;
+                Some(Rc::new(MalType::Promise(future)))
+            }),
+        ));
+
+        builtin.push((
+            "promise",
+            Rc::new(|_| Some(Rc::new(MalType::Promise(Rc::new(Promise::new(false)))))),
+        ));
+
+        builtin.push((
+            "deliver",
+            Rc::new(|args| {
+                if args.len() < 2 {
+                    report!("Wrong amount of arguments for deliver");
+                    return None;
+                }
+                match &*args[0] {
+                    MalType::Promise(promise) if promise.deliver(Some(args[1].clone())) => {
+                        Some(args[0].clone())
+                    }
+                    _ => Some(Rc::new(MalType::Nil)),
+                }
+            }),
+        ));
+
+        builtin.push((
+            "realized?",
+            Rc::new(|args| match args.first().map(|promise| &**promise) {
+                Some(MalType::Promise(promise)) => Some(Rc::new(MalType::Bool(!matches!(
+                    promise.state(),
+                    State::Pending
+                )))),
+                _ => Some(Rc::new(MalType::Bool(false))),
+            }),
+        ));
+
+        builtin.push((
+            "pmap",
+            Rc::new(|args| {
+                if args.len() < 2 || !is_function(&args[0]) {
+                    report!("pmap expects a function and a collection");
+                    return None;
+                }
+                let items = match &*args[1] {
+                    MalType::List(items) | MalType::Vector(items) => items.as_slice(),
+                    _ => &[],
+                };
+                let futures: Vec<Rc<Promise>> = items
+                    .iter()
+                    .map(|item| future_call(args[0].clone(), vec![item.clone()]))
+                    .collect::<Option<_>>()?;
+                let mut results = vec![];
+                for future in futures {
+                    match future.wait(None) {
+                        State::Delivered(value) => results.push(value),
+                        State::Pending => return budget::check().and(None),
+                        State::Failed => return None,
+                    }
+                }
+                Some(Rc::new(MalType::List(results)))
+            }),
+        ));
+
+        builtin.push((
+            "chan",
+            Rc::new(|args| {
+                let capacity = match args.first().map(|capacity| &**capacity) {
+                    Some(MalType::Int(capacity)) => (*capacity).max(0) as usize,
+                    _ => 0,
+                };
+                Some(Rc::new(MalType::Chan(Rc::new(Channel::new(capacity)))))
+            }),
+        ));
+
+        builtin.push((
+            ">!!",
+            Rc::new(|args| match (args.first().map(|c| &**c), args.get(1)) {
+                (Some(MalType::Chan(_)), Some(value)) if matches!(**value, MalType::Nil) => {
+                    report!("Can't put nil on a channel");
+                    None
+                }
+                (Some(MalType::Chan(channel)), Some(value)) => {
+                    match channel.put(value.clone(), None) {
+                        Some(done) => Some(Rc::new(MalType::Bool(done))),
+                        None => blocked(),
+                    }
+                }
+                _ => {
+                    report!(">!! expects a channel and a value");
+                    None
+                }
+            }),
+        ));
+
+        builtin.push((
+            "<!!",
+            Rc::new(|args| match args.first().map(|c| &**c) {
+                Some(MalType::Chan(channel)) => channel.take(None).or_else(blocked),
+                _ => {
+                    report!("<!! expects a channel");
+                    None
+                }
+            }),
+        ));
+
+        builtin.push((
+            "close!",
+            Rc::new(|args| {
+                if let Some(MalType::Chan(channel)) = args.first().map(|c| &**c) {
+                    channel.close();
+                }
+                Some(Rc::new(MalType::Nil))
+            }),
+        ));
+
+        builtin.push((
+            "alts!!",
+            Rc::new(|args| {
+                // (alts!! [take-chan [put-chan value]] :timeout ms :default value) gives
+                // [value port], or [nil :timeout] and [value :default]
+                let ports = match args.first().map(|ports| &**ports) {
+                    Some(MalType::List(ports) | MalType::Vector(ports)) => ports.as_slice(),
+                    _ => {
+                        report!("alts!! expects a vector of channels");
+                        return None;
+                    }
+                };
+                // Each op with the channel it's on
+                let mut ops = vec![];
+                let mut channels = vec![];
+                for port in ports.iter() {
+                    let (target, value) = match &**port {
+                        MalType::List(put) | MalType::Vector(put) if put.len() == 2 => {
+                            (&put[0], Some(&put[1]))
+                        }
+                        _ => (port, None),
+                    };
+                    let channel = match &**target {
+                        MalType::Chan(channel) => channel,
+                        _ => {
+                            report!("alts!! expects channels or [channel value] pairs");
+                            return None;
+                        }
+                    };
+                    ops.push(match value {
+                        Some(value) if matches!(**value, MalType::Nil) => {
+                            report!("Can't put nil on a channel");
+                            return None;
+                        }
+                        Some(value) => Op::Put(channel, value.clone()),
+                        None => Op::Take(channel),
+                    });
+                    channels.push(target.clone());
+                }
+                let mut deadline = None;
+                let mut default = None;
+                for option in args[1..].chunks(2) {
+                    match (&*option[0], option.get(1)) {
+                        (MalType::Keyword(keyword), Some(ms)) if keyword.as_str() == "timeout" => {
+                            if let MalType::Int(ms) = **ms {
+                                let timeout = Duration::from_millis(ms.max(0) as u64);
+                                deadline = Some(Instant::now() + timeout);
+                            }
+                        }
+                        (MalType::Keyword(keyword), Some(value))
+                            if keyword.as_str() == "default" =>
+                        {
+                            default = Some(value.clone())
+                        }
+                        _ => {}
+                    }
+                }
+                let result = |value, port| Some(Rc::new(MalType::Vector(vec![value, port])));
+                let keyword = |name| Rc::new(MalType::Keyword(Symbol::new(name)));
+                match select(&ops, deadline, default.is_some()) {
+                    Some((index, value)) => result(value, channels[index].clone()),
+                    None if budget::check().is_none() => None,
+                    None => match default {
+                        Some(value) => result(value, keyword("default")),
+                        None if deadline.is_some() => {
+                            result(Rc::new(MalType::Nil), keyword("timeout"))
+                        }
+                        None => blocked(),
+                    },
+                }
+            }),
+        ));
+
+        builtin.push((
+            "go-call",
+            Rc::new(|args| {
+                if args.is_empty() || !is_function(&args[0]) {
+                    report!("go-call expects a function");
+                    return None;
+                }
+                // Like go in core.async, the channel gets what the function returns and
+                // is closed then
+                let channel = Rc::new(Channel::new(1));
+                let result = channel.clone();
+                let func = args[0].clone();
+                promise::spawn(move || {
+                    IN_GO.with(|in_go| in_go.set(in_go.get() + 1));
+                    let value = call(&func, &[]);
+                    IN_GO.with(|in_go| in_go.set(in_go.get() - 1));
+                    if let Some(value) = value {
+                        if !matches!(*value, MalType::Nil) {
+                            result.put(value, None);
+                        }
+                    }
+                    result.close();
+                })?;
+                Some(Rc::new(MalType::Chan(channel)))
+            }),
+        ));
+
+        builtin.push((
+            "with-out-str-call",
+            Rc::new(|args| {
+                if args.is_empty() || !is_function(&args[0]) {
+                    report!("with-out-str-call expects a function");
+                    return None;
+                }
+                let (result, text) = stream::capture(|| call(&args[0], &[]));
+                result?;
+                budget::allocate(text.len() / 8)?;
+                Some(Rc::new(MalType::Str(text)))
+            }),
+        ));
+
+        let tests = suite.clone();
+        builtin.push((
+            "deftest-call",
+            Rc::new(move |args| match args.first().map(|name| &**name) {
+                Some(MalType::Symbol(name)) if args.len() >= 2 && is_function(&args[1]) => {
+                    tests.define(name.as_str(), args[1].clone());
+                    Some(args[1].clone())
+                }
+                _ => {
+                    report!("deftest-call expects a name and a function");
+                    None
+                }
+            }),
+        ));
+
+        let tests = suite.clone();
+        let out = streams.clone();
+        builtin.push((
+            "is-call",
+            Rc::new(move |args| {
+                // (is-call 'form (fn* [] form) message), with (= a b ...) the function
+                // gives [a b ...]
+                if args.len() < 2 || !is_function(&args[1]) {
+                    report!("is-call expects a form and a function");
+                    return None;
+                }
+                let printed = |value: &Rc<MalType>| print_str(value.clone(), false, true);
+                let expected = printed(&args[0]);
+                let message = match args.get(2).map(|message| &**message) {
+                    Some(MalType::Str(message)) => Some(message.clone()),
+                    Some(MalType::Nil) | None => None,
+                    Some(_) => Some(printed(&args[2])),
+                };
+                let equality = matches!(&*args[0], MalType::List(form)
+                    if matches!(form.first().map(|head| &**head), Some(MalType::Symbol(EQUAL))));
+                let (passed, failure) = match call(&args[1], &[]) {
+                    None => {
+                        budget::check()?;
+                        let mut failure = tests.failure(
+                            message,
+                            expected,
+                            String::from("an error, see the output above"),
+                        );
+                        failure.error = true;
+                        (false, Some(failure))
+                    }
+                    Some(value) => {
+                        let passed = match (equality, &*value) {
+                            (true, MalType::Vector(values)) => {
+                                values.windows(2).all(|pair| pair[0] == pair[1])
+                            }
+                            _ => is_truthy(&value),
+                        };
+                        let actual = match (equality, &*value) {
+                            (true, MalType::Vector(values)) => {
+                                let values: Vec<String> = values.iter().map(printed).collect();
+                                format!("(not (= {}))", values.join(" "))
+                            }
+                            _ => printed(&value),
+                        };
+                        (
+                            passed,
+                            (!passed).then(|| tests.failure(message, expected, actual)),
+                        )
+                    }
+                };
+                // Outside of run-tests failures are reported right away
+                if let Some(failure) = tests.assert(failure) {
+                    if let Err(err) = out.print(&failure.describe("")) {
+                        return io_error("*out*", err);
+                    }
+                }
+                Some(Rc::new(MalType::Bool(passed)))
+            }),
+        ));
+
+        let tests = suite.clone();
+        builtin.push((
+            "testing-call",
+            Rc::new(move |args| {
+                if args.len() < 2 || !is_function(&args[1]) {
+                    report!("testing-call expects a description and a function");
+                    return None;
+                }
+                let what = match &*args[0] {
+                    MalType::Str(what) => what.clone(),
+                    _ => print_str(args[0].clone(), false, true),
+                };
+                tests.enter(&what);
+                let result = call(&args[1], &[]);
+                tests.leave();
+                result
+            }),
+        ));
+
+        let tests = suite.clone();
+        builtin.push((
+            "use-fixtures",
+            Rc::new(move |args| {
+                // (use-fixtures :each f ...) wraps each test, :once all of them, in
+                // (f run) where run runs what is inside
+                let once = match args.first().map(|kind| &**kind) {
+                    Some(MalType::Keyword(kind)) if kind.as_str() == "once" => true,
+                    Some(MalType::Keyword(kind)) if kind.as_str() == "each" => false,
+                    _ => {
+                        report!("use-fixtures expects :once or :each and functions");
+                        return None;
+                    }
+                };
+                if !args[1..].iter().all(|fixture| is_function(fixture)) {
+                    report!("use-fixtures expects :once or :each and functions");
+                    return None;
+                }
+                tests.set_fixtures(once, args[1..].to_vec());
+                Some(Rc::new(MalType::Nil))
+            }),
+        ));
+
+        let tests = suite.clone();
+        let io = streams.clone();
+        let allowed = capabilities.clone();
+        builtin.push((
+            "run-tests",
+            Rc::new(move |args| {
+                // (run-tests :junit "report.xml" :name "suite" :exit true) fails if
+                // anything failed, so that a script running it exits with 1. :exit
+                // ends the process right away, with 0 or 1.
+                let mut junit = None;
+                let mut name = String::from("mal");
+                let mut exit = false;
+                for option in args.chunks(2) {
+                    match (&*option[0], option.get(1).map(|value| &**value)) {
+                        (MalType::Keyword(key), Some(MalType::Str(path)))
+                            if key.as_str() == "junit" =>
+                        {
+                            junit = Some(path.clone())
+                        }
+                        (MalType::Keyword(key), Some(MalType::Str(value)))
+                            if key.as_str() == "name" =>
+                        {
+                            name = value.clone()
+                        }
+                        (MalType::Keyword(key), Some(value)) if key.as_str() == "exit" => {
+                            exit = is_truthy(value)
+                        }
+                        _ => {
+                            report!("run-tests expects :junit, :name or :exit options");
+                            return None;
+                        }
+                    }
+                }
+                if let Some(path) = &junit {
+                    allowed.check_write(path)?;
+                }
+                if exit && !allowed.exec {
+                    report!("run-tests :exit needs the exec capability");
+                    return None;
+                }
+                if tests.is_running() {
+                    report!("run-tests is already running");
+                    return None;
+                }
+                tests.begin();
+                let inner = tests.clone();
+                let all =
+                    MalType::BuiltinFunc(String::from("run"), Rc::new(move |_| run_tests(&inner)));
+                let ran = with_fixtures(&tests.fixtures(true), Rc::new(all));
+                let outcomes = tests.end();
+                ran?;
+                if let Err(err) = io.print(&testing::report(&outcomes)) {
+                    return io_error("*out*", err);
+                }
+                if let Some(path) = &junit {
+                    if let Err(err) = fs::write(path, testing::junit(&name, &outcomes)) {
+                        return io_error(path, err);
+                    }
+                }
+                let summary = testing::summarize(&outcomes);
+                let failed = summary.failures + summary.errors;
+                if exit {
+                    io.flush().ok();
+                    std::process::exit(if failed > 0 { 1 } else { 0 });
+                }
+                if failed > 0 {
+                    let failing = outcomes.iter().filter(|test| !test.failures.is_empty());
+                    report!("{} of {} tests failed", failing.count(), summary.tests);
+                    return None;
+                }
+                let stat = |name, count: usize| {
+                    (
+                        Rc::new(MalType::Keyword(Symbol::new(name))),
+                        Rc::new(MalType::Int(count as i32)),
+                    )
+                };
+                Some(Rc::new(MalType::HashMap(vec![
+                    stat("test", summary.tests),
+                    stat("pass", summary.assertions.saturating_sub(failed)),
+                    stat("fail", summary.failures),
+                    stat("error", summary.errors),
+                ])))
+            }),
+        ));
+
+        builtin.push((
+            "time-ms",
+            Rc::new(|_| {
+                // Since the first call, an i32 can't hold the time since the epoch
+                static START: OnceLock<Instant> = OnceLock::new();
+                let start = START.get_or_init(Instant::now);
+                Some(Rc::new(MalType::Int(start.elapsed().as_millis() as i32)))
+            }),
+        ));
+
+        builtin.push((
+            "getenv",
+            Rc::new(|args| match args.first().map(|name| &**name) {
+                // All of them as a map without a name
+                None => Some(Rc::new(MalType::HashMap(
+                    std::env::vars()
+                        .map(|(name, value)| {
+                            (Rc::new(MalType::Str(name)), Rc::new(MalType::Str(value)))
+                        })
+                        .collect(),
+                ))),
+                Some(MalType::Str(name)) => match std::env::var(name) {
+                    Ok(value) => Some(Rc::new(MalType::Str(value))),
+                    Err(_) => Some(Rc::new(MalType::Nil)),
+                },
+                Some(_) => {
+                    report!("getenv expects a name");
+                    None
+                }
+            }),
+        ));
+
+        builtin.push((
+            "setenv",
+            Rc::new(|args| {
+                // A nil value removes the variable
+                let (name, value) = (
+                    args.first().map(|name| &**name),
+                    args.get(1).map(|value| &**value),
+                );
+                // std panics on names and values the OS can't take
+                if let Some(MalType::Str(name)) = name {
+                    if name.is_empty() || name.contains(['=', '\0']) {
+                        report!("setenv can't set {:?}", name);
+                        return None;
+                    }
+                }
+                if let Some(MalType::Str(value)) = value {
+                    if value.contains('\0') {
+                        report!("setenv can't set a value with a NUL in it");
+                        return None;
+                    }
+                }
+                match (name, value) {
+                    (Some(MalType::Str(name)), Some(MalType::Str(value))) => {
+                        std::env::set_var(name, value)
+                    }
+                    (Some(MalType::Str(name)), Some(MalType::Nil) | None) => {
+                        std::env::remove_var(name)
+                    }
+                    _ => {
+                        report!("setenv expects a name and a value");
+                        return None;
+                    }
+                }
+                Some(Rc::new(MalType::Nil))
+            }),
+        ));
+
+        let io = streams.clone();
+        builtin.push((
+            "exit",
+            Rc::new(move |args| {
+                let code = match args.first().map(|code| &**code) {
+                    None => 0,
+                    Some(MalType::Int(code)) => *code,
+                    Some(_) => {
+                        report!("exit expects an exit code");
+                        return None;
+                    }
+                };
+                io.flush().ok();
+                std::process::exit(code)
+            }),
+        ));
+
+        builtin.push((
+            "parse-args",
+            Rc::new(|args| {
+                match (
+                    args.first().map(|argv| &**argv),
+                    args.get(1).map(|spec| &**spec),
+                ) {
+                    (
+                        Some(MalType::List(argv) | MalType::Vector(argv)),
+                        Some(MalType::HashMap(spec)),
+                    ) => parse_args(argv, spec),
+                    _ => {
+                        report!("parse-args expects arguments and a map of options");
+                        None
+                    }
+                }
+            }),
+        ));
+
+        builtin.push((
+            "gc",
+            Rc::new(|_| {
+                let stats = gc::collect();
+                let stat = |name, count| {
+                    (
+                        Rc::new(MalType::Keyword(Symbol::new(name))),
+                        Rc::new(MalType::Int(count as i32)),
+                    )
+                };
+                Some(Rc::new(MalType::HashMap(vec![
+                    stat("freed", stats.freed),
+                    stat("total-freed", stats.total_freed),
+                    stat("tracked", stats.tracked),
+                    stat("collections", stats.collections),
+                ])))
+            }),
         ));
-        Self { builtin }
+        // Builtins reaching for what wasn't granted are left out
+        builtin.retain(|(name, _)| match *name {
+            "prn" | "println" => capabilities.stdout,
+            "readline" => capabilities.stdin,
+            "slurp" | "file-exists?" | "list-dir" => !capabilities.read.is_empty(),
+            "spit" | "delete-file" | "mkdir" => !capabilities.write.is_empty(),
+            "open" | "with-open-call" => {
+                !capabilities.read.is_empty() || !capabilities.write.is_empty()
+            }
+            "read-line" | "write" | "close" => {
+                !capabilities.read.is_empty()
+                    || !capabilities.write.is_empty()
+                    || capabilities.stdin
+                    || capabilities.stdout
+            }
+            "time-ms" => capabilities.time,
+            "sh" | "process" | "wait-process" | "exit" => capabilities.exec,
+            "getenv" | "setenv" => capabilities.env,
+            _ => true,
+        });
+        let mut builtin: Vec<(&'static str, MalType)> = builtin
+            .into_iter()
+            .map(|(name, func)| (name, MalType::BuiltinFunc(String::from(name), func)))
+            .collect();
+        for (name, stream) in standard {
+            let granted = match name {
+                "*in*" => capabilities.stdin,
+                _ => capabilities.stdout,
+            };
+            if granted {
+                builtin.push((name, (*stream).clone()));
+            }
+        }
+        Self { builtin, streams }
     }
 }
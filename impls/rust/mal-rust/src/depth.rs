@@ -0,0 +1,95 @@
+use crate::stream::report;
+use std::cell::Cell;
+
+// How deep eval, the reader and the printer may nest before giving up with an error
+// instead of overflowing the native stack. Whatever the limit, they give up too once
+// the stack of the thread is nearly used up, so that a thread with less of it than
+// the binaries here give theirs still reports an error.
+pub const DEFAULT_MAX_DEPTH: usize = 10000;
+
+// The stack kept back for one more level of nesting and reporting the error
+const STACK_RESERVE: usize = 256 * 1024;
+
+thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    // The limit of the interpreter evaluating on this thread, see Limited
+    static MAX_DEPTH: Cell<usize> = const { Cell::new(DEFAULT_MAX_DEPTH) };
+    // The lowest address of the stack of this thread nesting may go down to
+    static STACK_END: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+pub fn max_depth() -> usize {
+    MAX_DEPTH.with(Cell::get)
+}
+
+// Makes max the limit on the current thread while it is alive
+pub struct Limited(usize);
+
+impl Limited {
+    pub fn enter(max: usize) -> Self {
+        Limited(MAX_DEPTH.with(|depth| depth.replace(max)))
+    }
+}
+
+impl Drop for Limited {
+    fn drop(&mut self) {
+        MAX_DEPTH.with(|depth| depth.set(self.0));
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn stack_bottom() -> Option<usize> {
+    unsafe {
+        let mut attr = std::mem::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            return None;
+        }
+        let (mut addr, mut size) = (std::ptr::null_mut(), 0);
+        let found = libc::pthread_attr_getstack(&attr, &mut addr, &mut size) == 0;
+        libc::pthread_attr_destroy(&mut attr);
+        found.then_some(addr as usize)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn stack_bottom() -> Option<usize> {
+    None
+}
+
+// Whether the stack, which grows down, has room left for another level
+#[inline(never)]
+fn stack_left() -> bool {
+    let end = STACK_END.with(|end| match end.get() {
+        Some(end) => end,
+        None => {
+            let bottom = stack_bottom().map_or(0, |bottom| bottom + STACK_RESERVE);
+            end.set(Some(bottom));
+            bottom
+        }
+    });
+    let here = 0u8;
+    std::ptr::addr_of!(here) as usize > end
+}
+
+// Counts one level of nesting on the current thread while it is alive
+pub struct DepthGuard;
+
+impl DepthGuard {
+    pub fn enter() -> Option<Self> {
+        DEPTH.with(|depth| {
+            if depth.get() >= max_depth() || !stack_left() {
+                report!("Stack depth exceeded");
+                None
+            } else {
+                depth.set(depth.get() + 1);
+                Some(DepthGuard)
+            }
+        })
+    }
+}
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
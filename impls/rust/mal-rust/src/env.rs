@@ -1,38 +1,62 @@
+use crate::shared::{Rc, RefCell};
+use crate::symbol::{Symbol, SymbolMap, SymbolSet};
 use crate::types::MalType;
-use std::collections::HashMap;
-use std::{cell::RefCell, rc::Rc};
 
 pub struct Env {
-    pub map: HashMap<String, Rc<MalType>>,
+    pub map: SymbolMap<Rc<MalType>>,
+    // Locals of a frame made by the analyzer, by the index it resolved them to
+    pub slots: Vec<Rc<MalType>>,
     pub outer: Option<Rc<RefCell<Env>>>,
+    // The globals defined with def-, kept in the root env, see namespace.rs
+    pub private: SymbolSet,
 }
 
 impl Env {
     pub fn new_root() -> Self {
         Self {
-            map: HashMap::new(),
+            map: SymbolMap::default(),
+            slots: vec![],
             outer: None,
+            private: SymbolSet::default(),
         }
         //env.load_builtin();
     }
 
     pub fn new(outer: Rc<RefCell<Env>>) -> Self {
         Self {
-            map: HashMap::new(),
+            map: SymbolMap::default(),
+            slots: vec![],
             outer: Some(outer),
+            private: SymbolSet::default(),
         }
     }
 
-    pub fn new_bind(outer: Rc<RefCell<Env>>, binds: &[&str], exprs: &[Rc<MalType>]) -> Self {
+    pub fn new_frame(outer: Rc<RefCell<Env>>, slots: Vec<Rc<MalType>>) -> Self {
+        Self {
+            map: SymbolMap::default(),
+            slots,
+            outer: Some(outer),
+            private: SymbolSet::default(),
+        }
+    }
+
+    pub fn root(env: &Rc<RefCell<Env>>) -> Rc<RefCell<Env>> {
+        match &env.borrow().outer {
+            Some(outer) => Env::root(outer),
+            None => env.clone(),
+        }
+    }
+
+    pub fn new_bind(outer: Rc<RefCell<Env>>, binds: &[Symbol], exprs: &[Rc<MalType>]) -> Self {
         let mut env = Env::new(outer);
         for (bind, expr) in binds.iter().zip(exprs.iter()) {
-            env.map.insert(String::from(*bind), expr.clone());
+            env.map.insert(*bind, expr.clone());
         }
         env
     }
 
-    pub fn get(&self, symbol: &str) -> Option<Rc<MalType>> {
-        let result = self.map.get(symbol).cloned();
+    pub fn get(&self, symbol: Symbol) -> Option<Rc<MalType>> {
+        let result = self.map.get(&symbol).cloned();
         if result.is_none() {
             self.outer.as_ref()?.borrow().get(symbol)
         } else {
@@ -40,7 +64,30 @@ impl Env {
         }
     }
 
-    pub fn set(&mut self, symbol: &str, mal: Rc<MalType>) {
-        self.map.insert(String::from(symbol), mal);
+    // The slot at index in the frame depth levels up
+    pub fn get_local(&self, depth: usize, index: usize) -> Option<Rc<MalType>> {
+        if depth == 0 {
+            self.slots.get(index).cloned()
+        } else {
+            self.outer.as_ref()?.borrow().get_local(depth - 1, index)
+        }
+    }
+
+    pub fn get_global(&self, symbol: Symbol) -> Option<Rc<MalType>> {
+        match &self.outer {
+            Some(outer) => outer.borrow().get_global(symbol),
+            None => self.map.get(&symbol).cloned(),
+        }
+    }
+
+    pub fn is_private(&self, symbol: Symbol) -> bool {
+        match &self.outer {
+            Some(outer) => outer.borrow().is_private(symbol),
+            None => self.private.contains(&symbol),
+        }
+    }
+
+    pub fn set(&mut self, symbol: Symbol, mal: Rc<MalType>) {
+        self.map.insert(symbol, mal);
     }
 }
@@ -0,0 +1,66 @@
+// What try* catches. An error is reported where it happens, then every caller gives
+// up by returning None down to the try-call its try* became. While the body of one
+// runs, the first error reported on its thread is kept as the exception for catch*
+// instead of being printed, and throw keeps the value it is given.
+
+use crate::printer::print_str;
+use crate::shared::Rc;
+use crate::stream::report;
+use crate::types::MalType;
+use std::cell::{Cell, RefCell};
+
+thread_local! {
+    // How many try* bodies are running on this thread
+    static CATCHING: Cell<usize> = const { Cell::new(0) };
+    static THROWN: RefCell<Option<Rc<MalType>>> = const { RefCell::new(None) };
+}
+
+// Catches what is thrown on this thread while it is alive
+pub struct Catching(());
+
+impl Catching {
+    pub fn enter() -> Self {
+        CATCHING.with(|catching| catching.set(catching.get() + 1));
+        THROWN.with(|thrown| thrown.borrow_mut().take());
+        Catching(())
+    }
+}
+
+impl Drop for Catching {
+    fn drop(&mut self) {
+        CATCHING.with(|catching| catching.set(catching.get() - 1));
+    }
+}
+
+fn catching() -> bool {
+    CATCHING.with(Cell::get) > 0
+}
+
+// Whether the error was kept for a catch*, rather than to be printed
+pub fn keep(message: &str) -> bool {
+    if !catching() {
+        return false;
+    }
+    THROWN.with(|thrown| {
+        thrown
+            .borrow_mut()
+            .get_or_insert_with(|| Rc::new(MalType::Str(message.to_string())));
+    });
+    true
+}
+
+pub fn throw(value: Rc<MalType>) -> Option<Rc<MalType>> {
+    if catching() {
+        THROWN.with(|thrown| *thrown.borrow_mut() = Some(value));
+    } else {
+        report!("Exception: {}", print_str(value, false, true));
+    }
+    None
+}
+
+// What the body that just failed threw, its error if it only returned None
+pub fn take() -> Rc<MalType> {
+    THROWN
+        .with(|thrown| thrown.borrow_mut().take())
+        .unwrap_or_else(|| Rc::new(MalType::Str(String::from("Error"))))
+}
@@ -0,0 +1,137 @@
+// Files made by open, read line by line or written to until they are closed, pipes
+// to the programs started by process, and *in*, *out* and *err*, which stay open
+
+use crate::shared::{Rc, RefCell};
+use crate::stream::{chomp, Streams};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    Read,
+    Write,
+    Append,
+}
+
+enum Stream {
+    Reader(Box<dyn BufRead + Send + Sync>),
+    Writer(Box<dyn Write + Send + Sync>),
+    In(Rc<Streams>),
+    Out(Rc<Streams>),
+    Err(Rc<Streams>),
+}
+
+pub struct FileHandle {
+    pub path: String,
+    pub mode: Mode,
+    // None once closed
+    stream: RefCell<Option<Stream>>,
+}
+
+fn closed() -> io::Error {
+    io::Error::other("file is closed")
+}
+
+impl FileHandle {
+    pub fn open(path: &str, mode: Mode) -> io::Result<Self> {
+        let stream = match mode {
+            Mode::Read => Stream::Reader(Box::new(BufReader::new(File::open(path)?))),
+            Mode::Write => Stream::Writer(Box::new(BufWriter::new(File::create(path)?))),
+            Mode::Append => Stream::Writer(Box::new(BufWriter::new(
+                OpenOptions::new().append(true).create(true).open(path)?,
+            ))),
+        };
+        Ok(Self {
+            path: path.to_string(),
+            mode,
+            stream: RefCell::new(Some(stream)),
+        })
+    }
+
+    // A pipe read from as a file named name
+    pub fn reader(name: &str, pipe: impl Read + Send + Sync + 'static) -> Self {
+        Self {
+            path: name.to_string(),
+            mode: Mode::Read,
+            stream: RefCell::new(Some(Stream::Reader(Box::new(BufReader::new(pipe))))),
+        }
+    }
+
+    // A pipe written to as a file named name, closing it lets the reader see the end.
+    // It isn't buffered, so that a program being talked to gets each write right away.
+    pub fn writer(name: &str, pipe: impl Write + Send + Sync + 'static) -> Self {
+        Self {
+            path: name.to_string(),
+            mode: Mode::Append,
+            stream: RefCell::new(Some(Stream::Writer(Box::new(pipe)))),
+        }
+    }
+
+    pub fn standard(streams: &Rc<Streams>) -> [(&'static str, Self); 3] {
+        let handle = |name: &str, mode, stream| Self {
+            path: name.to_string(),
+            mode,
+            stream: RefCell::new(Some(stream)),
+        };
+        [
+            (
+                "*in*",
+                handle("*in*", Mode::Read, Stream::In(streams.clone())),
+            ),
+            (
+                "*out*",
+                handle("*out*", Mode::Append, Stream::Out(streams.clone())),
+            ),
+            (
+                "*err*",
+                handle("*err*", Mode::Append, Stream::Err(streams.clone())),
+            ),
+        ]
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.stream.borrow().is_none()
+    }
+
+    // The next line without its line ending, None at the end of the file
+    pub fn read_line(&self) -> io::Result<Option<String>> {
+        let mut stream = self.stream.borrow_mut();
+        let reader = match &mut *stream {
+            Some(Stream::Reader(reader)) => reader,
+            Some(Stream::In(streams)) => return streams.read_line(),
+            Some(_) => return Err(io::Error::other("file is open for writing")),
+            None => return Err(closed()),
+        };
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        chomp(&mut line);
+        Ok(Some(line))
+    }
+
+    pub fn write(&self, text: &str) -> io::Result<()> {
+        match &mut *self.stream.borrow_mut() {
+            Some(Stream::Writer(writer)) => writer.write_all(text.as_bytes()),
+            Some(Stream::Out(streams)) => streams.write_out(text),
+            Some(Stream::Err(streams)) => streams.write_err(text),
+            Some(_) => Err(io::Error::other("file is open for reading")),
+            None => Err(closed()),
+        }
+    }
+
+    // Closing twice does nothing, what is left to write is written first. The
+    // standard streams are only flushed.
+    pub fn close(&self) -> io::Result<()> {
+        let mut stream = self.stream.borrow_mut();
+        match &*stream {
+            Some(Stream::In(_)) => return Ok(()),
+            Some(Stream::Out(streams) | Stream::Err(streams)) => return streams.flush(),
+            _ => {}
+        }
+        match stream.take() {
+            Some(Stream::Writer(mut writer)) => writer.flush(),
+            _ => Ok(()),
+        }
+    }
+}
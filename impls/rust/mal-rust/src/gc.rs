@@ -0,0 +1,290 @@
+// Frees what reference counting alone can't: a closure kept in the env it closes
+// over, as named fn* and recursive let* bindings are, or an atom holding itself.
+//
+// It works like the cycle collector of CPython. Starting from the atoms and closures
+// made so far, since every cycle goes through one, it walks what they point to and
+// takes the references found on the way off the counts of their targets. Whatever
+// still has a count left is referred to from outside, like the Rust stack or an
+// Interpreter, and stays alive together with everything it leads to. The rest is
+// only kept alive by cycles, which are broken by emptying its envs and atoms.
+
+use crate::env::Env;
+use crate::shared::{Rc, RefCell, Shared, Weak};
+use crate::types::MalType;
+use std::any::Any;
+use std::cell;
+use std::collections::HashMap;
+
+// Atoms and closures to make between two collections, at least
+const MIN_THRESHOLD: usize = 10000;
+
+// For the `compiled` field of a closure, which may point to values of its own
+pub trait Trace: Any + Shared {
+    fn trace(&self, _tracer: &mut Tracer) {}
+}
+
+// The `compiled` field of a closure as what the analyzer or the VM made it
+pub fn downcast<T: Trace>(compiled: Rc<dyn Trace>) -> Option<Rc<T>> {
+    #[cfg(not(feature = "threads"))]
+    let compiled: Rc<dyn Any> = compiled;
+    #[cfg(feature = "threads")]
+    let compiled: Rc<dyn Any + Send + Sync> = compiled;
+    compiled.downcast().ok()
+}
+
+// Gathers what a node points to
+#[derive(Default)]
+pub struct Tracer {
+    children: Vec<Node>,
+}
+
+impl Tracer {
+    pub fn value(&mut self, value: &Rc<MalType>) {
+        if matches!(
+            &**value,
+            MalType::List(_)
+                | MalType::Vector(_)
+                | MalType::HashMap(_)
+                | MalType::Atom(_)
+                | MalType::Func(_)
+                | MalType::Macro(_)
+        ) {
+            self.children.push(Node::Value(value.clone()));
+        }
+    }
+
+    pub fn env(&mut self, env: &Rc<RefCell<Env>>) {
+        self.children.push(Node::Env(env.clone()));
+    }
+}
+
+pub enum Node {
+    Value(Rc<MalType>),
+    Env(Rc<RefCell<Env>>),
+    Compiled(Rc<dyn Trace>),
+}
+
+impl Node {
+    fn address(&self) -> usize {
+        match self {
+            Node::Value(value) => Rc::as_ptr(value) as *const u8 as usize,
+            Node::Env(env) => Rc::as_ptr(env) as *const u8 as usize,
+            Node::Compiled(compiled) => Rc::as_ptr(compiled) as *const u8 as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Value(value) => Rc::strong_count(value),
+            Node::Env(env) => Rc::strong_count(env),
+            Node::Compiled(compiled) => Rc::strong_count(compiled),
+        }
+    }
+
+    // None if the node is borrowed at the moment and can't be looked into
+    fn children(&self) -> Option<Vec<Node>> {
+        let mut tracer = Tracer::default();
+        match self {
+            Node::Value(value) => match &**value {
+                MalType::List(items) | MalType::Vector(items) => {
+                    items.iter().for_each(|item| tracer.value(item))
+                }
+                MalType::HashMap(kvs) => {
+                    for (key, value) in kvs.iter() {
+                        tracer.value(key);
+                        tracer.value(value);
+                    }
+                }
+                MalType::Atom(cell) => tracer.value(&*cell.try_borrow().ok()?),
+                MalType::Macro(func) => tracer.value(func),
+                MalType::Func(closure) => {
+                    tracer.env(&closure.env);
+                    if let Some(compiled) = &closure.compiled {
+                        tracer.children.push(Node::Compiled(compiled.clone()));
+                    }
+                }
+                _ => {}
+            },
+            Node::Env(env) => {
+                let env = env.try_borrow().ok()?;
+                env.map.values().for_each(|value| tracer.value(value));
+                env.slots.iter().for_each(|value| tracer.value(value));
+                if let Some(outer) = &env.outer {
+                    tracer.env(outer);
+                }
+            }
+            Node::Compiled(compiled) => compiled.trace(&mut tracer),
+        }
+        Some(tracer.children)
+    }
+
+    // Lets go of everything a garbage env or atom holds. The graph still holds the
+    // nodes, they are freed once it's dropped.
+    fn clear(&self) {
+        match self {
+            Node::Value(value) => {
+                if let MalType::Atom(cell) = &**value {
+                    if let Ok(mut inner) = cell.try_borrow_mut() {
+                        *inner = Rc::new(MalType::Nil);
+                    }
+                }
+            }
+            Node::Env(env) => {
+                if let Ok(mut env) = env.try_borrow_mut() {
+                    env.map.clear();
+                    env.slots.clear();
+                    env.outer = None;
+                }
+            }
+            Node::Compiled(_) => {}
+        }
+    }
+}
+
+// Everything reachable from the atoms and closures, each node held once
+#[derive(Default)]
+struct Graph {
+    nodes: Vec<Node>,
+    edges: Vec<Vec<usize>>,
+    index: HashMap<usize, usize>,
+    borrowed: Vec<usize>,
+}
+
+impl Graph {
+    fn add(&mut self, node: Node) -> usize {
+        let address = node.address();
+        if let Some(index) = self.index.get(&address) {
+            return *index;
+        }
+        self.nodes.push(node);
+        self.edges.push(vec![]);
+        self.index.insert(address, self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    fn explore(&mut self) {
+        let mut next = 0;
+        while next < self.nodes.len() {
+            match self.nodes[next].children() {
+                Some(children) => {
+                    let edges = children.into_iter().map(|child| self.add(child)).collect();
+                    self.edges[next] = edges;
+                }
+                None => self.borrowed.push(next),
+            }
+            next += 1;
+        }
+    }
+
+    // The nodes nothing outside the graph leads to
+    fn garbage(&self) -> Vec<usize> {
+        // The graph itself holds one reference to each node
+        let mut outside: Vec<isize> = self
+            .nodes
+            .iter()
+            .map(|node| node.strong_count() as isize - 1)
+            .collect();
+        for edges in self.edges.iter() {
+            for to in edges.iter() {
+                outside[*to] -= 1;
+            }
+        }
+        let mut alive = vec![false; self.nodes.len()];
+        let mut pending: Vec<usize> = (0..self.nodes.len())
+            .filter(|index| outside[*index] > 0)
+            .chain(self.borrowed.iter().cloned())
+            .collect();
+        while let Some(index) = pending.pop() {
+            if !alive[index] {
+                alive[index] = true;
+                pending.extend(self.edges[index].iter().cloned());
+            }
+        }
+        (0..self.nodes.len())
+            .filter(|index| !alive[*index])
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Stats {
+    pub collections: usize,
+    // Nodes freed by the last collection, and by all of them
+    pub freed: usize,
+    pub total_freed: usize,
+    // Atoms and closures left after the last collection
+    pub tracked: usize,
+}
+
+struct Heap {
+    tracked: Vec<Weak<MalType>>,
+    threshold: usize,
+    stats: Stats,
+}
+
+thread_local! {
+    static HEAP: cell::RefCell<Heap> = const {
+        cell::RefCell::new(Heap {
+            tracked: vec![],
+            threshold: MIN_THRESHOLD,
+            stats: Stats {
+                collections: 0,
+                freed: 0,
+                total_freed: 0,
+                tracked: 0,
+            },
+        })
+    };
+}
+
+// Registers a new atom or closure, collecting once enough were made since last time.
+// Values shared between threads may change while the graph is walked, so with the
+// `threads` feature they are left to reference counting alone.
+pub fn track(value: &Rc<MalType>) {
+    if cfg!(feature = "threads") {
+        return;
+    }
+    let due = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.tracked.push(Rc::downgrade(value));
+        heap.tracked.len() >= heap.threshold
+    });
+    if due {
+        collect();
+    }
+}
+
+pub fn stats() -> Stats {
+    HEAP.with(|heap| heap.borrow().stats)
+}
+
+pub fn collect() -> Stats {
+    if cfg!(feature = "threads") {
+        return stats();
+    }
+    let tracked = HEAP.with(|heap| std::mem::take(&mut heap.borrow_mut().tracked));
+    let mut graph = Graph::default();
+    for value in tracked.iter().filter_map(Weak::upgrade) {
+        graph.add(Node::Value(value));
+    }
+    graph.explore();
+    let garbage = graph.garbage();
+    for index in garbage.iter() {
+        graph.nodes[*index].clear();
+    }
+    let live = graph.nodes.len() - garbage.len();
+    drop(graph);
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        let survivors = tracked.into_iter().filter(|value| value.strong_count() > 0);
+        heap.tracked.extend(survivors);
+        // The next collection waits for about as many new values as there are live
+        // ones to walk again
+        heap.threshold = heap.tracked.len() + live.max(MIN_THRESHOLD);
+        heap.stats.collections += 1;
+        heap.stats.freed = garbage.len();
+        heap.stats.total_freed += garbage.len();
+        heap.stats.tracked = heap.tracked.len();
+        heap.stats
+    })
+}
@@ -0,0 +1,534 @@
+use crate::budget::{Budget, Exhausted, Interrupt, Meter, Metered};
+use crate::capability::Capabilities;
+use crate::core::NameSpace;
+use crate::depth::{self, Limited};
+use crate::env::Env;
+use crate::namespace::{self, Entered, Namespaces};
+use crate::printer::{print_str, try_print_str};
+use crate::reader::read_str;
+use crate::shared::{Rc, RefCell};
+use crate::stream::{report, Reader, Reporting, Streams, Writer};
+use crate::symbol::Symbol;
+use crate::types::{FuncType, MalType};
+use crate::{analyzer, library, vm};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+// How an Interpreter runs forms, both give the same results
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    // Trees of closures, see analyzer.rs
+    Closures,
+    // Bytecode on a stack VM, see compiler.rs and vm.rs
+    Bytecode,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "closures" => Ok(Backend::Closures),
+            "bytecode" | "vm" => Ok(Backend::Bytecode),
+            _ => Err(format!("Unknown backend {}", name)),
+        }
+    }
+}
+
+fn eval_with(backend: Backend, ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
+    match backend {
+        Backend::Closures => analyzer::eval(ast, env),
+        Backend::Bytecode => vm::eval(ast, env),
+    }
+}
+
+// The top level forms of source, None if they can't be read
+pub fn read_forms(source: &str) -> Option<Vec<Rc<MalType>>> {
+    match read_str(&format!("(do {}\nnil)", source)) {
+        Ok((_, ast)) => match &*ast {
+            MalType::List(forms) => Some(forms[1..].to_vec()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn eval_forms(
+    backend: Backend,
+    forms: Vec<Rc<MalType>>,
+    env: &Rc<RefCell<Env>>,
+) -> Option<Rc<MalType>> {
+    let mut value = Rc::new(MalType::Nil);
+    for form in forms {
+        value = eval_with(backend, form, env.clone())?;
+    }
+    Some(value)
+}
+
+// Evaluates the forms of the file at path one after the other, so that an ns form
+// is in effect for the ones after it
+fn load_file(
+    backend: Backend,
+    env: &Rc<RefCell<Env>>,
+    namespaces: &Namespaces,
+    path: &Rc<MalType>,
+) -> Option<Rc<MalType>> {
+    // Through slurp, so that loading needs what reading does
+    let slurp = match env.borrow().get_global(Symbol::new("slurp")) {
+        Some(slurp) => slurp,
+        None => {
+            report!("slurp not found");
+            return None;
+        }
+    };
+    let source = analyzer::apply(&slurp, std::slice::from_ref(path))?;
+    let forms = match &*source {
+        MalType::Str(source) => read_forms(source),
+        _ => None,
+    };
+    let forms = match forms {
+        Some(forms) => forms,
+        None => {
+            report!("{} can't be read", print_str(path.clone(), false, true));
+            return None;
+        }
+    };
+    load_forms(backend, env, namespaces, path, forms)
+}
+
+// *file* is path while forms are evaluated, after which it and the current namespace
+// are back to what they were
+fn load_forms(
+    backend: Backend,
+    env: &Rc<RefCell<Env>>,
+    namespaces: &Namespaces,
+    path: &Rc<MalType>,
+    forms: Vec<Rc<MalType>>,
+) -> Option<Rc<MalType>> {
+    let file = Symbol::new("*file*");
+    let outer = env.borrow().get_global(file);
+    let ns = namespaces.current();
+    env.borrow_mut().set(file, path.clone());
+    let result = eval_forms(backend, forms, env);
+    env.borrow_mut()
+        .set(file, outer.unwrap_or_else(|| Rc::new(MalType::Nil)));
+    namespaces.switch(ns);
+    result.map(|_| Rc::new(MalType::Nil))
+}
+
+// A bundled library, as if it were the file module.mal, see library.rs
+fn load_library(
+    backend: Backend,
+    env: &Rc<RefCell<Env>>,
+    namespaces: &Namespaces,
+    module: Symbol,
+    source: &str,
+) -> Option<Rc<MalType>> {
+    let path = Rc::new(MalType::Str(format!("{}.mal", module)));
+    let forms = match read_forms(source) {
+        Some(forms) => forms.into_iter().filter_map(library::rewrite).collect(),
+        None => {
+            report!("{} can't be read", print_str(path, false, true));
+            return None;
+        }
+    };
+    load_forms(backend, env, namespaces, &path, forms)
+}
+
+// What require is given for a module: foo.bar or [foo.bar :as bar :refer [baz]], with
+// :refer :all for all its public names
+struct Spec {
+    module: Symbol,
+    alias: Option<Symbol>,
+    refer: Vec<Symbol>,
+    refer_all: bool,
+}
+
+fn parse_spec(spec: &Rc<MalType>) -> Option<Spec> {
+    let usage = || {
+        report!(
+            "require expects a module or [module :as alias :refer [names]], got {}",
+            print_str(spec.clone(), false, true)
+        );
+        None
+    };
+    let (module, options) = match &**spec {
+        MalType::Symbol(module) => (*module, &[][..]),
+        MalType::Vector(items) | MalType::List(items) => match items.split_first() {
+            Some((module, options)) if options.len() % 2 == 0 => match &**module {
+                MalType::Symbol(module) => (*module, options),
+                _ => return usage(),
+            },
+            _ => return usage(),
+        },
+        _ => return usage(),
+    };
+    let mut parsed = Spec {
+        module,
+        alias: None,
+        refer: vec![],
+        refer_all: false,
+    };
+    for option in options.chunks(2) {
+        match (&*option[0], &*option[1]) {
+            (MalType::Keyword(key), MalType::Symbol(alias)) if key.as_str() == "as" => {
+                parsed.alias = Some(*alias)
+            }
+            (MalType::Keyword(key), MalType::Keyword(all))
+                if key.as_str() == "refer" && all.as_str() == "all" =>
+            {
+                parsed.refer_all = true
+            }
+            (MalType::Keyword(key), MalType::Vector(names) | MalType::List(names))
+                if key.as_str() == "refer" =>
+            {
+                let names = names.iter().map(|name| match &**name {
+                    MalType::Symbol(name) => Some(*name),
+                    _ => None,
+                });
+                parsed.refer = match names.collect() {
+                    Some(names) => names,
+                    None => return usage(),
+                }
+            }
+            _ => return usage(),
+        }
+    }
+    Some(parsed)
+}
+
+// Loads the module the first time it is required, in its own namespace, from a file
+// or else the bundled library of that name. Then makes its alias and referred names
+// in the current one.
+fn require(
+    backend: Backend,
+    env: &Rc<RefCell<Env>>,
+    namespaces: &Namespaces,
+    spec: &Rc<MalType>,
+) -> Option<()> {
+    let spec = parse_spec(spec)?;
+    if namespaces.start_loading(spec.module) {
+        let dir = match env.borrow().get_global(Symbol::new("*file*")).as_deref() {
+            Some(MalType::Str(file)) => Path::new(file).parent().map(Path::to_path_buf),
+            _ => None,
+        };
+        let path = namespaces.find(spec.module, dir.as_deref());
+        let bundled = library::source(spec.module);
+        if path.is_none() && bundled.is_none() {
+            namespaces.unload(spec.module);
+            match library::unsupported(spec.module) {
+                Some(why) => report!("The {} library can't be loaded: {}", spec.module, why),
+                None => report!("Could not find module {} on the load path", spec.module),
+            }
+            return None;
+        }
+        let ns = namespaces.current();
+        namespaces.switch(spec.module);
+        let loaded = match path {
+            Some(path) => {
+                let path = Rc::new(MalType::Str(path.to_string_lossy().into_owned()));
+                load_file(backend, env, namespaces, &path)
+            }
+            None => bundled
+                .and_then(|source| load_library(backend, env, namespaces, spec.module, source)),
+        };
+        namespaces.switch(ns);
+        if loaded.is_none() {
+            namespaces.unload(spec.module);
+            return None;
+        }
+    }
+    if let Some(alias) = spec.alias {
+        namespaces.alias(alias, spec.module);
+    }
+    let mut names = spec.refer;
+    if spec.refer_all {
+        names.extend(namespaces.publics(spec.module));
+    }
+    for name in names {
+        namespaces.refer(spec.module, name)?;
+    }
+    Some(())
+}
+
+// A global env with the core functions loaded, evaluating forms with its backend
+pub struct Interpreter {
+    env: Rc<RefCell<Env>>,
+    backend: Backend,
+    // Limits for each eval, and what was left of them by the last one
+    budget: Option<Budget>,
+    meter: RefCell<Option<Rc<Meter>>>,
+    interrupt: Interrupt,
+    streams: Rc<Streams>,
+    namespaces: Rc<Namespaces>,
+    max_depth: usize,
+}
+
+impl Interpreter {
+    pub fn new(backend: Backend) -> Self {
+        Self::with_capabilities(backend, Capabilities::all())
+    }
+
+    // Only the builtins capabilities allow are defined, see capability.rs
+    pub fn with_capabilities(backend: Backend, capabilities: Capabilities) -> Self {
+        let env = Rc::new(RefCell::new(Env::new_root()));
+        let namespaces = Rc::new(Namespaces::new(&env, capabilities.clone()));
+        let namespace = NameSpace::with_capabilities(capabilities);
+        namespace.streams.set_globals(&env);
+        let interpreter = Self {
+            namespaces,
+            env,
+            backend,
+            budget: None,
+            meter: RefCell::new(None),
+            interrupt: Interrupt::default(),
+            streams: namespace.streams,
+            max_depth: depth::DEFAULT_MAX_DEPTH,
+        };
+        for (name, func) in namespace.builtin {
+            interpreter.set(name, Rc::new(func));
+        }
+        let env = interpreter.env.clone();
+        interpreter.set(
+            "eval",
+            Rc::new(MalType::BuiltinFunc(
+                String::from("eval"),
+                Rc::new(move |args| {
+                    if args.is_empty() {
+                        Some(Rc::new(MalType::Nil))
+                    } else {
+                        eval_with(backend, args[0].clone(), env.clone())
+                    }
+                }),
+            )),
+        );
+        interpreter.eval_str("(def! not (fn* (a) (if a false true)))");
+        interpreter.eval_str(
+            "(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) \
+             (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) \
+             (cons 'cond (rest (rest xs)))))))",
+        );
+        // *file* is the path of the file being loaded while it is
+        interpreter.set("*file*", Rc::new(MalType::Nil));
+        interpreter.namespaces.switch(namespace::user());
+        interpreter.define_loading();
+        interpreter
+    }
+
+    // load-file, and the namespace functions which may load files
+    fn define_loading(&self) {
+        let builtin = |name: &str, func: Rc<FuncType>| {
+            self.set(name, Rc::new(MalType::BuiltinFunc(name.to_string(), func)));
+        };
+        let backend = self.backend;
+        let (env, namespaces) = (self.env.clone(), self.namespaces.clone());
+        builtin(
+            "load-file",
+            Rc::new(move |args| match args.first() {
+                Some(path) => load_file(backend, &env, &namespaces, path),
+                None => {
+                    report!("load-file expects a path");
+                    None
+                }
+            }),
+        );
+        let (env, namespaces) = (self.env.clone(), self.namespaces.clone());
+        builtin(
+            "require",
+            Rc::new(move |args| {
+                for spec in args {
+                    require(backend, &env, &namespaces, spec)?;
+                }
+                Some(Rc::new(MalType::Nil))
+            }),
+        );
+        let namespaces = self.namespaces.clone();
+        builtin(
+            "in-ns",
+            Rc::new(move |args| match args.first().map(|name| &**name) {
+                Some(MalType::Symbol(name)) => {
+                    namespaces.switch(*name);
+                    Some(args[0].clone())
+                }
+                _ => {
+                    report!("in-ns expects a symbol");
+                    None
+                }
+            }),
+        );
+        let (env, namespaces) = (self.env.clone(), self.namespaces.clone());
+        builtin(
+            "ns-call",
+            Rc::new(move |args| {
+                let name = match args.first().map(|name| &**name) {
+                    Some(MalType::Symbol(name)) => *name,
+                    _ => {
+                        report!("ns expects a name");
+                        return None;
+                    }
+                };
+                namespaces.switch(name);
+                for clause in args[1..].iter() {
+                    match &**clause {
+                        MalType::List(items) if matches!(items.first().map(|key| &**key), Some(MalType::Keyword(key)) if key.as_str() == "require") => {
+                            for spec in items[1..].iter() {
+                                require(backend, &env, &namespaces, spec)?;
+                            }
+                        }
+                        _ => {
+                            report!(
+                                "ns expects (:require ...) clauses, got {}",
+                                print_str(clause.clone(), false, true)
+                            );
+                            return None;
+                        }
+                    }
+                }
+                Some(Rc::new(MalType::Nil))
+            }),
+        );
+    }
+
+    // What the binaries are asked for through the environment: MAL_BACKEND=bytecode
+    // runs everything on the VM instead, MAL_MAX_DEPTH limits how deep eval nests,
+    // MAL_MAX_STEPS, MAL_MAX_ALLOCATIONS and MAL_TIMEOUT_MS limit each form evaluated,
+    // and MAL_CAPABILITIES grants only those it lists instead of all
+    pub fn from_env() -> Result<Self, String> {
+        let backend = match std::env::var("MAL_BACKEND") {
+            Ok(name) => name.parse()?,
+            Err(_) => Backend::Closures,
+        };
+        let capabilities = match std::env::var("MAL_CAPABILITIES") {
+            Ok(list) => list.parse()?,
+            Err(_) => Capabilities::all(),
+        };
+        let mut interpreter = Self::with_capabilities(backend, capabilities);
+        if let Some(path) = std::env::var_os("MAL_PATH") {
+            interpreter.set_load_path(std::env::split_paths(&path).collect());
+        }
+        let limit = |name| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+        };
+        let budget = Budget {
+            steps: limit("MAL_MAX_STEPS"),
+            allocations: limit("MAL_MAX_ALLOCATIONS"),
+            timeout: limit("MAL_TIMEOUT_MS").map(Duration::from_millis),
+        };
+        if let Some(depth) = limit("MAL_MAX_DEPTH") {
+            interpreter.set_max_depth(depth as usize);
+        }
+        if budget.steps.is_some() || budget.allocations.is_some() || budget.timeout.is_some() {
+            interpreter.set_budget(Some(budget));
+        }
+        Ok(interpreter)
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    pub fn set_budget(&mut self, budget: Option<Budget>) {
+        self.budget = budget;
+    }
+
+    // How deep its evals, and the reader and printer in them, may nest, short of
+    // running out of the stack of the thread evaluating, see depth.rs
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    // Where require looks for modules, after the directory of the file requiring
+    // them and before the working directory
+    pub fn set_load_path(&self, path: Vec<PathBuf>) {
+        self.namespaces.set_path(path);
+    }
+
+    // Where *out*, *err* and *in* go for a host embedding the interpreter, None for
+    // the process' own stdio
+    pub fn set_out(&self, writer: Option<Writer>) {
+        self.streams.set_out(writer);
+    }
+
+    pub fn set_err(&self, writer: Option<Writer>) {
+        self.streams.set_err(writer);
+    }
+
+    pub fn set_in(&self, reader: Option<Reader>) {
+        self.streams.set_in(reader);
+    }
+
+    // Why the last eval was stopped, if it ran out of its budget
+    pub fn exhausted(&self) -> Option<Exhausted> {
+        self.meter.borrow().as_ref()?.exhausted()
+    }
+
+    // Stops the eval going on, and only that one, from another thread, as the REPL
+    // does on Ctrl-C
+    pub fn interrupter(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+
+    pub fn eval(&self, ast: Rc<MalType>) -> Option<Rc<MalType>> {
+        self.interrupt.clear();
+        let budget = self.budget.unwrap_or_default();
+        let meter = Rc::new(Meter::new(&budget, self.interrupt.clone()));
+        *self.meter.borrow_mut() = Some(meter.clone());
+        let _metered = Metered::enter(Some(meter));
+        let _limited = Limited::enter(self.max_depth);
+        let _entered = Entered::enter(self.namespaces.clone());
+        let _reporting = Reporting::enter(Some(self.streams.clone()));
+        eval_with(self.backend, ast, self.env.clone())
+    }
+
+    // How the REPL shows a value, None once it is nested deeper than this interpreter's
+    // depth limit allows
+    pub fn print(&self, mal: Rc<MalType>) -> Option<String> {
+        let _limited = Limited::enter(self.max_depth);
+        let _reporting = Reporting::enter(Some(self.streams.clone()));
+        try_print_str(mal, false, true)
+    }
+
+    // The forms one after the other, see read_forms and load_file
+    pub fn eval_forms(&self, forms: Vec<Rc<MalType>>) -> Option<Rc<MalType>> {
+        let mut value = Rc::new(MalType::Nil);
+        for form in forms {
+            value = self.eval(form)?;
+        }
+        Some(value)
+    }
+
+    pub fn eval_str(&self, input: &str) -> Option<Rc<MalType>> {
+        match read_str(input) {
+            Ok((_, ast)) => self.eval(ast),
+            _ => None,
+        }
+    }
+
+    pub fn set(&self, name: &str, value: Rc<MalType>) {
+        self.env.borrow_mut().set(Symbol::new(name), value);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Rc<MalType>> {
+        self.env.borrow().get(Symbol::new(name))
+    }
+}
+
+// With the `threads` feature an interpreter may be made on one thread and used from
+// another, as may the values it hands out
+#[cfg(feature = "threads")]
+const _: fn() = || {
+    fn shareable<T: Send + Sync>() {}
+    shareable::<Interpreter>();
+    shareable::<MalType>();
+};
+
+// Functions defined in the interpreter keep its env alive, and its eval holds on to
+// it where the cycle collector can't see, so the globals are dropped here
+impl Drop for Interpreter {
+    fn drop(&mut self) {
+        self.env.borrow_mut().map.clear();
+    }
+}
@@ -0,0 +1,28 @@
+// The interpreter, and what the step binaries share of it. Hosts embed it through
+// Interpreter, see interpreter.rs, with the capabilities of capability.rs.
+
+pub mod analyzer;
+pub mod budget;
+pub mod capability;
+pub mod channel;
+pub mod compiler;
+pub mod conformance;
+pub mod core;
+pub mod depth;
+pub mod env;
+pub mod exception;
+pub mod file;
+pub mod gc;
+pub mod interpreter;
+pub mod library;
+pub mod namespace;
+pub mod printer;
+pub mod process;
+pub mod promise;
+pub mod reader;
+pub mod shared;
+pub mod stream;
+pub mod symbol;
+pub mod testing;
+pub mod types;
+pub mod vm;
@@ -0,0 +1,84 @@
+// The impls/lib libraries, built into the binary so that (require 'memoize) finds them
+// wherever the program runs. Files on the load path come first, so a library can be
+// replaced by one of the same name there.
+
+use crate::shared::Rc;
+use crate::symbol::{Symbol, QUOTE};
+use crate::types::MalType;
+
+// load-file-once.mal is left out, require loads modules once already
+const LIBRARIES: [(&str, &str); 10] = [
+    ("alias-hacks", include_str!("../../../lib/alias-hacks.mal")),
+    ("benchmark", include_str!("../../../lib/benchmark.mal")),
+    ("equality", include_str!("../../../lib/equality.mal")),
+    ("memoize", include_str!("../../../lib/memoize.mal")),
+    ("perf", include_str!("../../../lib/perf.mal")),
+    ("pprint", include_str!("../../../lib/pprint.mal")),
+    ("reducers", include_str!("../../../lib/reducers.mal")),
+    (
+        "test_cascade",
+        include_str!("../../../lib/test_cascade.mal"),
+    ),
+    ("threading", include_str!("../../../lib/threading.mal")),
+    ("trivial", include_str!("../../../lib/trivial.mal")),
+];
+
+// The ones left out, with why they would not work
+const UNSUPPORTED: [(&str, &str); 1] = [(
+    "protocols",
+    "it keeps the types of values in their metadata, which only functions have here",
+)];
+
+pub fn source(module: Symbol) -> Option<&'static str> {
+    LIBRARIES
+        .iter()
+        .find(|(name, _)| module.as_str() == *name)
+        .map(|(_, source)| *source)
+}
+
+pub fn unsupported(module: Symbol) -> Option<&'static str> {
+    UNSUPPORTED
+        .iter()
+        .find(|(name, _)| module.as_str() == *name)
+        .map(|(_, why)| *why)
+}
+
+// The libraries load each other with (load-file-once "../lib/trivial.mal") and the
+// like, which are (require '[trivial :refer :all]) here. Loading load-file-once.mal
+// itself is dropped.
+pub fn rewrite(form: Rc<MalType>) -> Option<Rc<MalType>> {
+    let items = match &*form {
+        MalType::List(items) if items.len() == 2 => items,
+        _ => return Some(form),
+    };
+    let module = match (&*items[0], &*items[1]) {
+        (MalType::Symbol(load), MalType::Str(path))
+            if load.as_str() == "load-file" || load.as_str() == "load-file-once" =>
+        {
+            match path
+                .strip_prefix("../lib/")
+                .and_then(|file| file.strip_suffix(".mal"))
+            {
+                Some(module) => module,
+                None => return Some(form),
+            }
+        }
+        _ => return Some(form),
+    };
+    if module == "load-file-once" {
+        return None;
+    }
+    let symbol = |name: &str| Rc::new(MalType::Symbol(Symbol::new(name)));
+    let spec = MalType::Vector(vec![
+        symbol(module),
+        Rc::new(MalType::Keyword(Symbol::new("refer"))),
+        Rc::new(MalType::Keyword(Symbol::new("all"))),
+    ]);
+    Some(Rc::new(MalType::List(vec![
+        symbol("require"),
+        Rc::new(MalType::List(vec![
+            Rc::new(MalType::Symbol(QUOTE)),
+            Rc::new(spec),
+        ])),
+    ])))
+}
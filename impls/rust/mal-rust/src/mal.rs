@@ -0,0 +1,269 @@
+use mal_rust::conformance;
+use mal_rust::interpreter::{read_forms, Interpreter};
+use mal_rust::reader::read_str;
+use mal_rust::shared::Rc;
+use mal_rust::symbol::Symbol;
+use mal_rust::types::MalType;
+
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use std::io::Read;
+use std::path::Path;
+use std::process::{exit, Command};
+
+const USAGE: &str = "Usage: mal [-e EXPR]... [--repl] [-f FILE | FILE | -] [ARG]...
+       mal --step N [FILE [ARG]...]
+       mal test TESTFILE...
+
+  -e EXPR     evaluate EXPR and print its value, may be given more than once
+  -f FILE     run FILE, as does FILE alone, with the ARGs in *ARGV*
+  -           run what is read from stdin
+  --repl      start the REPL, after what else was given
+  --step N    run step N instead, steps 6 and 7 with FILE
+  test        run tests/step*.mal style test files, see conformance.rs
+";
+
+// What the other steps are called, next to this binary
+const STEPS: [&str; 8] = [
+    "step0_repl",
+    "step1_read_print",
+    "step2_eval",
+    "step3_env",
+    "step4_if_fn_do",
+    "step5_tco",
+    "step6_file",
+    "step7_quote",
+];
+
+// Same as the steps, see step7_quote.rs
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+enum Action {
+    Test(Vec<String>),
+    Eval(String),
+    Load(String),
+    Stdin,
+    Repl,
+}
+
+fn usage(message: &str) -> ! {
+    eprint!("{}{}", message, USAGE);
+    exit(2)
+}
+
+fn print(interpreter: &Interpreter, input: Option<Rc<MalType>>) -> String {
+    match input.and_then(|mal| interpreter.print(mal)) {
+        Some(output) => output,
+        _ => String::from("Error"),
+    }
+}
+
+// Runs the step binary next to this one in its place, which takes no options and
+// before step 6 no file either
+fn step(number: &str, args: &[String]) -> ! {
+    let name = match number.parse::<usize>().ok().and_then(|n| STEPS.get(n)) {
+        Some(name) => name,
+        None => usage(&format!("No step {}\n", number)),
+    };
+    match args.first() {
+        Some(arg) if arg.starts_with('-') => {
+            usage(&format!("--step expects a FILE, not {}\n", arg))
+        }
+        Some(file) if STEPS[..6].contains(name) => usage(&format!("{} can't run {}\n", name, file)),
+        _ => (),
+    }
+    let path = match std::env::current_exe() {
+        Ok(exe) => exe.with_file_name(name),
+        Err(err) => usage(&format!("{}\n", err)),
+    };
+    match Command::new(&path).args(args).status() {
+        Ok(status) => exit(status.code().unwrap_or(1)),
+        Err(err) => {
+            eprintln!("{}: {}", path.display(), err);
+            exit(1)
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut actions = vec![];
+    let mut argv = vec![];
+    let mut i = 0;
+    if args.first().map(String::as_str) == Some("test") {
+        if args.len() < 2 {
+            usage("test expects test files\n");
+        }
+        actions.push(Action::Test(args[1..].to_vec()));
+        i = args.len();
+    }
+    while i < args.len() {
+        let value = || match args.get(i + 1) {
+            Some(value) => value.clone(),
+            None => usage(&format!("{} expects a value\n", args[i])),
+        };
+        match args[i].as_str() {
+            "--step" if i > 0 => usage("--step must come first\n"),
+            "--step" => step(&value(), &args[i + 2..]),
+            "-e" => {
+                actions.push(Action::Eval(value()));
+                i += 1;
+            }
+            "--repl" => actions.push(Action::Repl),
+            "-h" | "--help" => usage(""),
+            // The rest are arguments to the script
+            "-f" => {
+                actions.push(Action::Load(value()));
+                argv = args[i + 2..].to_vec();
+                break;
+            }
+            "-" => {
+                actions.push(Action::Stdin);
+                argv = args[i + 1..].to_vec();
+                break;
+            }
+            arg if arg.starts_with('-') => usage(&format!("Unknown option {}\n", arg)),
+            file => {
+                actions.push(Action::Load(file.to_string()));
+                argv = args[i + 1..].to_vec();
+                break;
+            }
+        }
+        i += 1;
+    }
+    if actions.is_empty() {
+        actions.push(Action::Repl);
+    }
+
+    let interpreter = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || run(actions, argv))
+        .unwrap();
+    exit(interpreter.join().unwrap());
+}
+
+// The exit code, 1 if a form failed
+fn run(actions: Vec<Action>, argv: Vec<String>) -> i32 {
+    let interpreter = match Interpreter::from_env() {
+        Ok(interpreter) => interpreter,
+        Err(err) => {
+            println!("{}", err);
+            return 1;
+        }
+    };
+    let argv = argv.into_iter().map(|arg| Rc::new(MalType::Str(arg)));
+    interpreter.set("*ARGV*", Rc::new(MalType::List(argv.collect())));
+
+    for action in actions {
+        let result = match action {
+            Action::Test(files) => return test(&files),
+            Action::Eval(input) => {
+                let result = match read_str(&input) {
+                    Ok((_, ast)) => interpreter.eval(ast),
+                    _ => {
+                        eprintln!("{:?} can't be read", input);
+                        None
+                    }
+                };
+                println!("{}", print(&interpreter, result.clone()));
+                result
+            }
+            Action::Load(path) => interpreter.eval(Rc::new(MalType::List(vec![
+                Rc::new(MalType::Symbol(Symbol::new("load-file"))),
+                Rc::new(MalType::Str(path)),
+            ]))),
+            Action::Stdin => {
+                let mut input = String::new();
+                if let Err(err) = std::io::stdin().read_to_string(&mut input) {
+                    println!("-: {}", err);
+                    return 1;
+                }
+                interpreter.set("*file*", Rc::new(MalType::Str(String::from("-"))));
+                match read_forms(&input) {
+                    Some(forms) => interpreter.eval_forms(forms),
+                    None => {
+                        eprintln!("- can't be read");
+                        None
+                    }
+                }
+            }
+            Action::Repl => {
+                repl(&interpreter);
+                Some(Rc::new(MalType::Nil))
+            }
+        };
+        if result.is_none() {
+            return 1;
+        }
+    }
+    0
+}
+
+// Reports on each file like runtest.py does, 1 if any failed
+fn test(files: &[String]) -> i32 {
+    let sections = ["", "deferrable ", "optional "];
+    let mut code = 0;
+    for file in files {
+        let report = match conformance::run_file(Path::new(file)) {
+            Ok(report) => report,
+            Err(err) => {
+                println!("{}", err);
+                code = 1;
+                continue;
+            }
+        };
+        for failure in report.failures.iter() {
+            let case = &failure.case;
+            println!(
+                "{}FAILED {}TEST (line {}): {}",
+                if case.soft { "SOFT " } else { "" },
+                sections[case.section as usize],
+                case.line,
+                case.form
+            );
+            println!("    Expected : {:?}", failure.expected);
+            println!("    Got      : {:?}", failure.got);
+        }
+        for (section, name) in sections.iter().enumerate() {
+            let soft = report.soft_failed[section];
+            let total = report.passed[section] + report.failed[section] + soft;
+            if section > 0 && total == 0 {
+                continue;
+            }
+            println!(
+                "{}: {}{} passed, {} failed, {} soft failed",
+                file, name, report.passed[section], report.failed[section], soft
+            );
+        }
+        if !report.is_success() {
+            code = 1;
+        }
+    }
+    code
+}
+
+fn repl(interpreter: &Interpreter) {
+    let mut rl = Editor::<()>::new();
+    // Ctrl-C stops the form being evaluated and leaves the REPL running
+    let interrupt = interpreter.interrupter();
+    if let Err(err) = ctrlc::set_handler(move || interrupt.interrupt()) {
+        println!("Error: {}", err);
+    }
+    loop {
+        match rl.readline("user> ") {
+            Ok(input) => {
+                rl.add_history_entry(input.as_str());
+                match read_str(&input) {
+                    Ok((_, ast)) => println!("{}", print(interpreter, interpreter.eval(ast))),
+                    _ => println!("EOF"),
+                }
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(err) => {
+                println!("Error: {:?}", err);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,306 @@
+// Namespaces are prefixes of the names in the one global env: in namespace foo,
+// (def! bar ...) defines foo/bar and bar refers to it. Which global a symbol is, with
+// the aliases and referred names of the namespace, is worked out when the form is
+// analyzed or compiled, in the namespace current then. user is the namespace to
+// start with and its names have no prefix, so code that never leaves it works as
+// it always did. Whether a name of another namespace is private is only known once
+// that namespace is loaded, so it is checked when the name is looked up.
+
+use crate::capability::Capabilities;
+use crate::env::Env;
+use crate::shared::{Rc, RefCell, Weak};
+use crate::stream::report;
+use crate::symbol::{Symbol, SymbolMap};
+use crate::types::MalType;
+use std::cell;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+#[derive(Default)]
+struct Namespace {
+    aliases: SymbolMap<Symbol>,
+    // The names referred from other namespaces, qualified
+    referred: SymbolMap<Symbol>,
+    // Names def!'d in it so far, some of them with def-
+    defined: HashSet<Symbol>,
+}
+
+pub struct Namespaces {
+    globals: Weak<RefCell<Env>>,
+    current: RefCell<Symbol>,
+    spaces: RefCell<SymbolMap<Namespace>>,
+    // The modules require loaded or is loading
+    loaded: RefCell<HashSet<Symbol>>,
+    // Where require looks for modules, after the directory of the file requiring them
+    path: RefCell<Vec<PathBuf>>,
+    // Modules are only looked for where files may be read
+    capabilities: Capabilities,
+}
+
+pub fn user() -> Symbol {
+    Symbol::new("user")
+}
+
+// The namespace and name of foo/bar, / alone is a name
+pub fn split(symbol: Symbol) -> Option<(Symbol, Symbol)> {
+    match symbol.as_str().split_once('/') {
+        Some((ns, name)) if !ns.is_empty() && !name.is_empty() => {
+            Some((Symbol::new(ns), Symbol::new(name)))
+        }
+        _ => None,
+    }
+}
+
+// The global name stands for in ns
+pub fn qualify(ns: Symbol, name: Symbol) -> Symbol {
+    if ns == user() {
+        name
+    } else {
+        Symbol::new(&format!("{}/{}", ns, name))
+    }
+}
+
+impl Namespaces {
+    pub fn new(globals: &Rc<RefCell<Env>>, capabilities: Capabilities) -> Self {
+        let mut spaces = SymbolMap::default();
+        spaces.insert(user(), Namespace::default());
+        Self {
+            globals: Rc::downgrade(globals),
+            current: RefCell::new(user()),
+            spaces: RefCell::new(spaces),
+            loaded: RefCell::new(HashSet::new()),
+            path: RefCell::new(vec![]),
+            capabilities,
+        }
+    }
+
+    fn global(&self, symbol: Symbol) -> Option<Rc<MalType>> {
+        self.globals.upgrade()?.borrow().get_global(symbol)
+    }
+
+    pub fn current(&self) -> Symbol {
+        *self.current.borrow()
+    }
+
+    // Makes ns current, and *ns* its name
+    pub fn switch(&self, ns: Symbol) {
+        self.spaces.borrow_mut().entry(ns).or_default();
+        *self.current.borrow_mut() = ns;
+        if let Some(globals) = self.globals.upgrade() {
+            globals
+                .borrow_mut()
+                .set(Symbol::new("*ns*"), Rc::new(MalType::Symbol(ns)));
+        }
+    }
+
+    pub fn set_path(&self, path: Vec<PathBuf>) {
+        *self.path.borrow_mut() = path;
+    }
+
+    // The file of module foo.bar-baz is foo/bar-baz.mal, looked for in dir, then on
+    // the path, then in the working directory, within the roots files may be read from
+    pub fn find(&self, module: Symbol, dir: Option<&Path>) -> Option<PathBuf> {
+        let file = format!("{}.mal", module.as_str().replace('.', "/"));
+        let path = self.path.borrow();
+        let found = dir
+            .into_iter()
+            .chain(path.iter().map(PathBuf::as_path))
+            .chain([Path::new(".")])
+            .map(|dir| dir.join(&file))
+            .find(|path| self.capabilities.may_read(path) && path.is_file());
+        found
+    }
+
+    // Whether module has to be loaded, it is taken as loaded from then on
+    pub fn start_loading(&self, module: Symbol) -> bool {
+        self.loaded.borrow_mut().insert(module)
+    }
+
+    // It failed to load, the next require tries again
+    pub fn unload(&self, module: Symbol) {
+        self.loaded.borrow_mut().remove(&module);
+    }
+
+    pub fn alias(&self, alias: Symbol, ns: Symbol) {
+        let current = self.current();
+        let mut spaces = self.spaces.borrow_mut();
+        spaces.entry(current).or_default().aliases.insert(alias, ns);
+    }
+
+    fn is_private(&self, qualified: Symbol) -> bool {
+        self.globals
+            .upgrade()
+            .is_some_and(|globals| globals.borrow().is_private(qualified))
+    }
+
+    // Lets name of ns be used without its namespace in the current one
+    pub fn refer(&self, ns: Symbol, name: Symbol) -> Option<()> {
+        let qualified = qualify(ns, name);
+        if self.is_private(qualified) {
+            report!("{} is private", qualified);
+            return None;
+        }
+        if self.global(qualified).is_none() {
+            report!("'{}' not found", qualified);
+            return None;
+        }
+        let current = self.current();
+        let mut spaces = self.spaces.borrow_mut();
+        spaces
+            .entry(current)
+            .or_default()
+            .referred
+            .insert(name, qualified);
+        Some(())
+    }
+
+    // The public names defined in ns
+    pub fn publics(&self, ns: Symbol) -> Vec<Symbol> {
+        let spaces = self.spaces.borrow();
+        let space = match spaces.get(&ns) {
+            Some(space) => space,
+            None => return vec![],
+        };
+        space
+            .defined
+            .iter()
+            .filter(|name| !self.is_private(qualify(ns, **name)))
+            .filter(|name| self.global(qualify(ns, **name)).is_some())
+            .copied()
+            .collect()
+    }
+
+    // The global def! sets for name in the current namespace, which a qualified name
+    // has to be in
+    pub fn define(&self, name: Symbol, private: bool) -> Option<Symbol> {
+        let current = self.current();
+        let name = match split(name) {
+            Some((ns, unqualified)) if ns == current => unqualified,
+            Some(_) => {
+                report!("Can't define {} outside of its namespace", name);
+                return None;
+            }
+            None => name,
+        };
+        let qualified = qualify(current, name);
+        let mut spaces = self.spaces.borrow_mut();
+        spaces.entry(current).or_default().defined.insert(name);
+        if let Some(globals) = self.globals.upgrade() {
+            let private_names = &mut globals.borrow_mut().private;
+            if private {
+                private_names.insert(qualified);
+            } else {
+                private_names.remove(&qualified);
+            }
+        }
+        Some(qualified)
+    }
+
+    // Whether name is def!'d, referred or a global in the current namespace, so that a
+    // special form of that name is left to it
+    pub fn is_bound(&self, name: Symbol) -> bool {
+        let current = self.current();
+        if let Some(space) = self.spaces.borrow().get(&current) {
+            if space.defined.contains(&name) || space.referred.contains_key(&name) {
+                return true;
+            }
+        }
+        self.global(name).is_some() || self.global(qualify(current, name)).is_some()
+    }
+
+    // The global symbol is in the current namespace: its own names first, then the
+    // referred ones and the ones of user. Names it doesn't know yet are taken as its
+    // own, defined later on. The bool tells if the global is of another namespace,
+    // to be looked up only if it is public.
+    pub fn resolve(&self, symbol: Symbol) -> Option<(Symbol, bool)> {
+        let (global, public) = self.global_name(symbol);
+        if split(symbol).is_some() && public && self.is_private(global) {
+            report!("{} is private", symbol);
+            return None;
+        }
+        Some((global, public))
+    }
+
+    fn global_name(&self, symbol: Symbol) -> (Symbol, bool) {
+        let current = self.current();
+        let spaces = self.spaces.borrow();
+        let space = spaces.get(&current);
+        if let Some((ns, name)) = split(symbol) {
+            let ns = match space.and_then(|space| space.aliases.get(&ns)) {
+                Some(ns) => *ns,
+                None => ns,
+            };
+            return (qualify(ns, name), ns != current);
+        }
+        if let Some(space) = space {
+            if space.defined.contains(&symbol) {
+                return (qualify(current, symbol), false);
+            }
+            if let Some(qualified) = space.referred.get(&symbol) {
+                return (*qualified, true);
+            }
+        }
+        if current == user() {
+            (symbol, false)
+        } else if self.global(symbol).is_some() {
+            (symbol, true)
+        } else {
+            (qualify(current, symbol), false)
+        }
+    }
+
+    // The macro symbol is the global of, if it is one it may use
+    pub fn macro_for(&self, symbol: Symbol) -> Option<Rc<MalType>> {
+        let (global, public) = self.global_name(symbol);
+        if public && self.is_private(global) {
+            return None;
+        }
+        self.global(global)
+            .filter(|value| matches!(**value, MalType::Macro(_)))
+    }
+}
+
+thread_local! {
+    static CURRENT: cell::RefCell<Option<Rc<Namespaces>>> = const { cell::RefCell::new(None) };
+}
+
+// Forms are analyzed in the namespaces of an interpreter while it is alive
+pub struct Entered(Option<Rc<Namespaces>>);
+
+impl Entered {
+    pub fn enter(namespaces: Rc<Namespaces>) -> Self {
+        Entered(CURRENT.with(|current| current.replace(Some(namespaces))))
+    }
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+    }
+}
+
+// The symbols as they are outside of an interpreter, on the threads of futures
+pub fn resolve(symbol: Symbol) -> Option<(Symbol, bool)> {
+    CURRENT.with(|current| match &*current.borrow() {
+        Some(namespaces) => namespaces.resolve(symbol),
+        None => Some((symbol, false)),
+    })
+}
+
+pub fn is_bound(name: Symbol) -> bool {
+    CURRENT.with(|current| match &*current.borrow() {
+        Some(namespaces) => namespaces.is_bound(name),
+        None => false,
+    })
+}
+
+pub fn define(name: Symbol, private: bool) -> Option<Symbol> {
+    CURRENT.with(|current| match &*current.borrow() {
+        Some(namespaces) => namespaces.define(name, private),
+        None => Some(name),
+    })
+}
+
+pub fn macro_for(symbol: Symbol) -> Option<Rc<MalType>> {
+    CURRENT.with(|current| current.borrow().as_ref()?.macro_for(symbol))
+}
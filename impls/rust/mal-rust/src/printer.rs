@@ -1,19 +1,22 @@
-use crate::types::{MalType, KV};
-use std::cell::RefCell;
-use std::rc::Rc;
+use crate::channel::Channel;
+use crate::depth::DepthGuard;
+use crate::file::FileHandle;
+use crate::promise::{Promise, State};
+use crate::shared::{Rc, RefCell};
+use crate::types::{ClosureType, MalType, KV};
 
-fn dump_hash_map(kvs: &[KV], print_readably: bool) -> String {
+fn dump_hash_map(kvs: &[KV], print_readably: bool) -> Option<String> {
     let mut output = String::from("{");
     for (i, (k, v)) in kvs.iter().enumerate() {
-        output += &dump_mal(k.clone(), print_readably);
+        output += &dump_mal(k.clone(), print_readably)?;
         output.push(' ');
-        output += &dump_mal(v.clone(), print_readably);
+        output += &dump_mal(v.clone(), print_readably)?;
         if i != kvs.len() - 1 {
             output.push(' ');
         }
     }
     output.push('}');
-    output
+    Some(output)
 }
 
 fn dump_str(string: &str, print_readably: bool) -> String {
@@ -35,16 +38,16 @@ fn dump_str(string: &str, print_readably: bool) -> String {
     output
 }
 
-fn dump_vec(items: &[Rc<MalType>], print_readably: bool) -> String {
+fn dump_vec(items: &[Rc<MalType>], print_readably: bool) -> Option<String> {
     let mut output = String::from('[');
     for (i, item) in items.iter().enumerate() {
-        output += &dump_mal(item.clone(), print_readably);
+        output += &dump_mal(item.clone(), print_readably)?;
         if i != items.len() - 1 {
             output.push(' ');
         }
     }
     output.push(']');
-    output
+    Some(output)
 }
 
 fn dump_i32(value: &i32) -> String {
@@ -59,65 +62,130 @@ fn dump_keyword(keyword: &str) -> String {
     format!(":{}", keyword)
 }
 
-fn dump_list(items: &[Rc<MalType>], print_readably: bool) -> String {
+fn dump_list(items: &[Rc<MalType>], print_readably: bool) -> Option<String> {
     let mut output = String::from('(');
     for (i, item) in items.iter().enumerate() {
-        output += &dump_mal(item.clone(), print_readably);
+        output += &dump_mal(item.clone(), print_readably)?;
         if i != items.len() - 1 {
             output.push(' ');
         }
     }
     output.push(')');
-    output
+    Some(output)
 }
 
 fn dump_symbol(symbol: &str) -> String {
     String::from(symbol)
 }
 
-fn dump_atom(value: &RefCell<Rc<MalType>>, print_readably: bool) -> String {
-    format!(
+fn dump_atom(value: &RefCell<Rc<MalType>>, print_readably: bool) -> Option<String> {
+    Some(format!(
         "(atom {})",
-        dump_mal(value.borrow().clone(), print_readably)
-    )
+        dump_mal(value.borrow().clone(), print_readably)?
+    ))
 }
 
-fn dump_mal_debug(mal: Rc<MalType>, print_readably: bool) -> String {
-    match &*mal {
-        MalType::HashMap(kvs) => String::from("Hash:") + &dump_hash_map(kvs, print_readably),
+fn dump_func(closure: &ClosureType, print_readably: bool) -> Option<String> {
+    let mut output = String::from("#<fn");
+    if let Some(name) = &closure.name {
+        output.push(' ');
+        output.push_str(name);
+    }
+    for arity in closure.arities.iter() {
+        output.push(' ');
+        output += &dump_vec(&arity.params, print_readably)?;
+    }
+    output.push('>');
+    Some(output)
+}
+
+fn dump_macro(func: &Rc<MalType>, print_readably: bool) -> Option<String> {
+    Some(dump_mal(func.clone(), print_readably)?.replacen("#<fn", "#<macro", 1))
+}
+
+fn dump_promise(promise: &Promise, print_readably: bool) -> Option<String> {
+    let kind = if promise.future { "future" } else { "promise" };
+    Some(match promise.state() {
+        State::Pending => format!("#<{} pending>", kind),
+        State::Delivered(value) => format!("#<{} {}>", kind, dump_mal(value, print_readably)?),
+        State::Failed => format!("#<{} failed>", kind),
+    })
+}
+
+fn dump_chan(channel: &Channel) -> String {
+    if channel.is_closed() {
+        String::from("#<chan closed>")
+    } else {
+        String::from("#<chan>")
+    }
+}
+
+fn dump_file(file: &FileHandle) -> String {
+    if file.is_closed() {
+        format!("#<file {} closed>", file.path)
+    } else {
+        format!("#<file {}>", file.path)
+    }
+}
+
+fn dump_builtin(name: &str) -> String {
+    format!("#<builtin {}>", name)
+}
+
+fn dump_mal_debug(mal: Rc<MalType>, print_readably: bool) -> Option<String> {
+    let _depth = DepthGuard::enter()?;
+    Some(match &*mal {
+        MalType::HashMap(kvs) => String::from("Hash:") + &dump_hash_map(kvs, print_readably)?,
         MalType::Str(string) => String::from("Str:") + &dump_str(string, print_readably),
-        MalType::Vector(items) => String::from("Vec:") + &dump_vec(items, print_readably),
+        MalType::Vector(items) => String::from("Vec:") + &dump_vec(items, print_readably)?,
         MalType::Int(value) => String::from("Int:") + &dump_i32(value),
         MalType::Bool(value) => String::from("Bool:") + &dump_boolean(value),
         MalType::Nil => String::from("nil"),
-        MalType::Keyword(keyword) => String::from("Key:") + &dump_keyword(keyword),
-        MalType::List(items) => String::from("List:") + &dump_list(items, print_readably),
-        MalType::Symbol(symbol) => String::from("Sym:") + &dump_symbol(symbol),
-        MalType::Atom(value) => dump_atom(value, print_readably),
-        MalType::Func(_) | MalType::BuiltinFunc(_) => String::from("#<function>"),
-    }
+        MalType::Keyword(keyword) => String::from("Key:") + &dump_keyword(keyword.as_str()),
+        MalType::List(items) => String::from("List:") + &dump_list(items, print_readably)?,
+        MalType::Symbol(symbol) => String::from("Sym:") + &dump_symbol(symbol.as_str()),
+        MalType::Atom(value) => dump_atom(value, print_readably)?,
+        MalType::Func(closure) => dump_func(closure, print_readably)?,
+        MalType::Macro(func) => dump_macro(func, print_readably)?,
+        MalType::BuiltinFunc(name, _) => dump_builtin(name),
+        MalType::Promise(promise) => dump_promise(promise, print_readably)?,
+        MalType::Chan(channel) => dump_chan(channel),
+        MalType::File(file) => dump_file(file),
+    })
 }
 
-fn dump_mal(mal: Rc<MalType>, print_readably: bool) -> String {
-    match &*mal {
-        MalType::HashMap(kvs) => dump_hash_map(kvs, print_readably),
+fn dump_mal(mal: Rc<MalType>, print_readably: bool) -> Option<String> {
+    let _depth = DepthGuard::enter()?;
+    Some(match &*mal {
+        MalType::HashMap(kvs) => dump_hash_map(kvs, print_readably)?,
         MalType::Str(string) => dump_str(string, print_readably),
-        MalType::Vector(items) => dump_vec(items, print_readably),
+        MalType::Vector(items) => dump_vec(items, print_readably)?,
         MalType::Int(value) => dump_i32(value),
         MalType::Bool(value) => dump_boolean(value),
         MalType::Nil => String::from("nil"),
-        MalType::Keyword(keyword) => dump_keyword(keyword),
-        MalType::List(items) => dump_list(items, print_readably),
-        MalType::Symbol(symbol) => dump_symbol(symbol),
-        MalType::Atom(value) => dump_atom(value, print_readably),
-        MalType::Func(_) | MalType::BuiltinFunc(_) => String::from("#<function>"),
-    }
+        MalType::Keyword(keyword) => dump_keyword(keyword.as_str()),
+        MalType::List(items) => dump_list(items, print_readably)?,
+        MalType::Symbol(symbol) => dump_symbol(symbol.as_str()),
+        MalType::Atom(value) => dump_atom(value, print_readably)?,
+        MalType::Func(closure) => dump_func(closure, print_readably)?,
+        MalType::Macro(func) => dump_macro(func, print_readably)?,
+        MalType::BuiltinFunc(name, _) => dump_builtin(name),
+        MalType::Promise(promise) => dump_promise(promise, print_readably)?,
+        MalType::Chan(channel) => dump_chan(channel),
+        MalType::File(file) => dump_file(file),
+    })
 }
 
-pub fn print_str(mal: Rc<MalType>, debug: bool, print_readably: bool) -> String {
+// Fails once mal is nested deeper than the depth limit allows
+pub fn try_print_str(mal: Rc<MalType>, debug: bool, print_readably: bool) -> Option<String> {
     if debug {
         dump_mal_debug(mal, print_readably)
     } else {
         dump_mal(mal, print_readably)
     }
 }
+
+// For messages, too deeply nested values are cut off with ...
+pub fn print_str(mal: Rc<MalType>, debug: bool, print_readably: bool) -> String {
+    try_print_str(mal, debug, print_readably).unwrap_or_else(|| String::from("..."))
+}
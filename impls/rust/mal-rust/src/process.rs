@@ -0,0 +1,171 @@
+// Other programs run by sh, to completion, and process, which hands back pipes to
+// them. Children started by process are kept here until wait-process reaps them.
+
+use crate::budget;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, ExitStatus, Stdio};
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How often a running child is looked at, short as most commands are quick
+const POLL: Duration = Duration::from_millis(5);
+
+pub struct Command {
+    pub program: String,
+    pub args: Vec<String>,
+    // Written to the child's stdin, which is closed after
+    pub input: Option<String>,
+    pub env: Vec<(String, String)>,
+    pub dir: Option<String>,
+}
+
+pub struct Output {
+    pub exit: i32,
+    pub out: String,
+    pub err: String,
+}
+
+// The pipes of a child started by spawn, stdin is None when input was given
+pub struct Pipes {
+    pub pid: u32,
+    pub stdin: Option<ChildStdin>,
+    pub stdout: ChildStdout,
+    pub stderr: ChildStderr,
+}
+
+fn children() -> MutexGuard<'static, HashMap<u32, Child>> {
+    static CHILDREN: OnceLock<Mutex<HashMap<u32, Child>>> = OnceLock::new();
+    CHILDREN
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+// -1 when it was killed by a signal
+fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or(-1)
+}
+
+fn read_all(mut pipe: impl Read + Send + 'static) -> JoinHandle<io::Result<String>> {
+    thread::spawn(move || {
+        let mut bytes = vec![];
+        pipe.read_to_end(&mut bytes)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    })
+}
+
+fn joined(reader: JoinHandle<io::Result<String>>) -> io::Result<String> {
+    reader
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("reading the output failed")))
+}
+
+impl Command {
+    fn spawn_child(&self) -> io::Result<Child> {
+        let mut command = std::process::Command::new(&self.program);
+        command
+            .args(&self.args)
+            .envs(self.env.iter().map(|(name, value)| (name, value)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(dir) = &self.dir {
+            command.current_dir(dir);
+        }
+        let mut child = command.spawn()?;
+        // On its own thread, the child may not read it all before writing
+        if let Some(input) = self.input.clone() {
+            if let Some(mut stdin) = child.stdin.take() {
+                thread::spawn(move || stdin.write_all(input.as_bytes()));
+            }
+        }
+        Ok(child)
+    }
+
+    // Runs the command to completion, None if the evaluation was cut short while
+    // it ran, which kills the child
+    pub fn run(&self) -> Option<io::Result<Output>> {
+        let mut child = match self.spawn_child() {
+            Ok(child) => child,
+            Err(err) => return Some(Err(err)),
+        };
+        // Without input the child gets an empty stdin
+        child.stdin.take();
+        let out = read_all(child.stdout.take()?);
+        let err = read_all(child.stderr.take()?);
+        let status = match wait_child(&mut child)? {
+            Ok(status) => status,
+            Err(err) => return Some(Err(err)),
+        };
+        let output = joined(out).and_then(|out| {
+            Ok(Output {
+                exit: exit_code(status),
+                out,
+                err: joined(err)?,
+            })
+        });
+        Some(output)
+    }
+
+    // Starts the command, it runs until wait reaps it
+    pub fn spawn(&self) -> io::Result<Pipes> {
+        let mut child = self.spawn_child()?;
+        let pipes = Pipes {
+            pid: child.id(),
+            stdin: child.stdin.take(),
+            stdout: child
+                .stdout
+                .take()
+                .ok_or_else(|| io::Error::other("no stdout"))?,
+            stderr: child
+                .stderr
+                .take()
+                .ok_or_else(|| io::Error::other("no stderr"))?,
+        };
+        children().insert(pipes.pid, child);
+        Ok(pipes)
+    }
+}
+
+// Waits for the child to exit, None if the evaluation was cut short first
+fn wait_child(child: &mut Child) -> Option<io::Result<ExitStatus>> {
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Some(Ok(status)),
+            Ok(None) => {}
+            Err(err) => return Some(Err(err)),
+        }
+        match budget::wait_slice(None) {
+            Some(slice) => thread::sleep(slice.min(POLL)),
+            None => {
+                child.kill().ok();
+                child.wait().ok();
+                return None;
+            }
+        }
+    }
+}
+
+// The exit code of a child started by spawn once it exits, None if the evaluation
+// was cut short first, which leaves the child running
+pub fn wait(pid: u32) -> Option<io::Result<i32>> {
+    loop {
+        let mut children = children();
+        let child = match children.get_mut(&pid) {
+            Some(child) => child,
+            None => return Some(Err(io::Error::other("no such process"))),
+        };
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                children.remove(&pid);
+                return Some(Ok(exit_code(status)));
+            }
+            Ok(None) => {}
+            Err(err) => return Some(Err(err)),
+        }
+        drop(children);
+        thread::sleep(budget::wait_slice(None)?.min(POLL));
+    }
+}
@@ -0,0 +1,192 @@
+// Promises, and futures running mal functions on worker threads. Without the `threads`
+// feature values can't leave the thread they were made on, so futures run right away.
+
+use crate::budget;
+use crate::shared::Rc;
+use crate::types::MalType;
+#[cfg(feature = "threads")]
+use crate::{depth, stream};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+pub enum State {
+    Pending,
+    Delivered(Rc<MalType>),
+    // The function of a future ended with an error
+    Failed,
+}
+
+pub struct Promise {
+    // Made by future, delivered with what its function returns
+    pub future: bool,
+    state: Mutex<State>,
+    delivered: Condvar,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Promise {
+    pub fn new(future: bool) -> Self {
+        Self {
+            future,
+            state: Mutex::new(State::Pending),
+            delivered: Condvar::new(),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        lock(&self.state).clone()
+    }
+
+    // None fails the promise, only the first delivery counts
+    pub fn deliver(&self, value: Option<Rc<MalType>>) -> bool {
+        let mut state = lock(&self.state);
+        if !matches!(*state, State::Pending) {
+            return false;
+        }
+        *state = match value {
+            Some(value) => State::Delivered(value),
+            None => State::Failed,
+        };
+        self.delivered.notify_all();
+        true
+    }
+
+    // Pending if it's still pending after timeout. On a single thread nothing else
+    // could deliver it, so it doesn't wait at all.
+    pub fn wait(&self, timeout: Option<Duration>) -> State {
+        let mut state = lock(&self.state);
+        if cfg!(not(feature = "threads")) {
+            return state.clone();
+        }
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        while let State::Pending = *state {
+            let slice = match budget::wait_slice(deadline) {
+                Some(slice) => slice,
+                None => break,
+            };
+            state = self
+                .delivered
+                .wait_timeout(state, slice)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        state.clone()
+    }
+}
+
+#[cfg(not(feature = "threads"))]
+pub fn spawn(job: impl FnOnce() + 'static) -> Option<()> {
+    job();
+    Some(())
+}
+
+// Jobs work for the evaluation that spawned them, within its budget and depth limit,
+// reporting errors where it does
+#[cfg(feature = "threads")]
+pub fn spawn(job: impl FnOnce() + Send + 'static) -> Option<()> {
+    let meter = budget::current();
+    let max_depth = depth::max_depth();
+    let streams = stream::current();
+    pool::spawn(move || {
+        let _metered = budget::Metered::enter(meter);
+        let _limited = depth::Limited::enter(max_depth);
+        let _reporting = stream::Reporting::enter(streams);
+        job()
+    })
+}
+
+// Workers are started whenever none is free, up to MAX_WORKERS, and stop after they
+// had nothing to do for a while. The jobs after that wait in the queue, so a future
+// waiting on one queued behind it waits until a worker is free, or its budget ends.
+#[cfg(feature = "threads")]
+mod pool {
+    use super::lock;
+    use crate::stream::report;
+    use std::collections::VecDeque;
+    use std::sync::{Condvar, Mutex, OnceLock, PoisonError};
+    use std::time::Duration;
+
+    const KEEP_ALIVE: Duration = Duration::from_secs(10);
+    const MAX_WORKERS: usize = 256;
+    // Mal code nests as deep on workers as on the main thread
+    const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+    type Job = Box<dyn FnOnce() + Send>;
+
+    struct Queue {
+        jobs: VecDeque<Job>,
+        // Workers waiting for a job
+        idle: usize,
+        workers: usize,
+    }
+
+    struct Pool {
+        queue: Mutex<Queue>,
+        ready: Condvar,
+    }
+
+    fn pool() -> &'static Pool {
+        static POOL: OnceLock<Pool> = OnceLock::new();
+        POOL.get_or_init(|| Pool {
+            queue: Mutex::new(Queue {
+                jobs: VecDeque::new(),
+                idle: 0,
+                workers: 0,
+            }),
+            ready: Condvar::new(),
+        })
+    }
+
+    pub fn spawn(job: impl FnOnce() + Send + 'static) -> Option<()> {
+        let pool = pool();
+        let mut queue = lock(&pool.queue);
+        queue.jobs.push_back(Box::new(job));
+        if queue.idle >= queue.jobs.len() || queue.workers >= MAX_WORKERS {
+            pool.ready.notify_one();
+            return Some(());
+        }
+        // The new worker waits for the queue to be unlocked
+        let started = std::thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(work);
+        match started {
+            Ok(_) => queue.workers += 1,
+            // The workers there are get to the job in time
+            Err(_) if queue.workers > 0 => {}
+            Err(err) => {
+                queue.jobs.pop_back();
+                report!("Can't start a worker thread: {}", err);
+                return None;
+            }
+        }
+        Some(())
+    }
+
+    fn work() {
+        let pool = pool();
+        let mut queue = lock(&pool.queue);
+        loop {
+            if let Some(job) = queue.jobs.pop_front() {
+                drop(queue);
+                job();
+                queue = lock(&pool.queue);
+                continue;
+            }
+            queue.idle += 1;
+            let (woken, timeout) = pool
+                .ready
+                .wait_timeout(queue, KEEP_ALIVE)
+                .unwrap_or_else(PoisonError::into_inner);
+            queue = woken;
+            queue.idle -= 1;
+            if timeout.timed_out() && queue.jobs.is_empty() {
+                queue.workers -= 1;
+                return;
+            }
+        }
+    }
+}
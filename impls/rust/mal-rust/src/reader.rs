@@ -1,33 +1,39 @@
+use crate::depth::DepthGuard;
+use crate::shared::Rc;
+use crate::symbol::{Symbol, DEREF, QUASIQUOTE, QUOTE, SPLICE_UNQUOTE, UNQUOTE, WITH_META};
 use crate::types::{MalType, KV};
 use nom::{
     branch::alt,
-    bytes::complete::{escaped_transform, tag, take_till1, take_while1},
+    bytes::complete::{escaped_transform, tag, take_till1, take_while, take_while1},
     character::complete::{char, digit1, none_of},
-    combinator::{eof, map, map_res, opt, recognize, success, value},
+    combinator::{eof, map, map_res, opt, recognize, success, value, verify},
+    error::{Error, ErrorKind},
     multi::many0,
     sequence::{delimited, pair, preceded, terminated},
-    IResult,
+    Err, IResult,
 };
-use std::rc::Rc;
 use std::str::FromStr;
 
-// space and comma
+// space, comma and comments. #! starts one too, as in Clojure, so that scripts may
+// start with #!/usr/bin/env mal whatever reads them.
 fn spc(input: &str) -> IResult<&str, Vec<&str>> {
     let chars = " \t\n,";
     many0(alt((
         take_while1(move |c| chars.contains(c)),
         preceded(char(';'), take_while1(|c| c != '\n')),
+        preceded(tag("#!"), take_while(|c| c != '\n')),
     )))(input)
 }
 
+// true, false and nil are whole tokens, true? is a symbol
 fn parse_boolean(input: &str) -> IResult<&str, bool> {
-    let parse_true = value(true, tag("true"));
-    let parse_false = value(false, tag("false"));
+    let parse_true = value(true, verify(parse_symbol, |s: &str| s == "true"));
+    let parse_false = value(false, verify(parse_symbol, |s: &str| s == "false"));
     alt((parse_true, parse_false))(input)
 }
 
 fn parse_nil(input: &str) -> IResult<&str, ()> {
-    value((), tag("nil"))(input)
+    value((), verify(parse_symbol, |s: &str| s == "nil"))(input)
 }
 
 fn parse_i32(input: &str) -> IResult<&str, i32> {
@@ -100,31 +106,26 @@ fn parse_quote(input: &str) -> IResult<&str, Vec<Rc<MalType>>> {
                 parse_mal,
             ),
             |x| match x.0 {
-                "~@" => vec![
-                    Rc::new(MalType::Symbol(String::from("splice-unquote"))),
-                    x.1,
-                ],
-                "\'" => vec![Rc::new(MalType::Symbol(String::from("quote"))), x.1],
-                "`" => vec![Rc::new(MalType::Symbol(String::from("quasiquote"))), x.1],
-                "@" => vec![Rc::new(MalType::Symbol(String::from("deref"))), x.1],
-                "~" => vec![Rc::new(MalType::Symbol(String::from("unquote"))), x.1],
+                "~@" => vec![Rc::new(MalType::Symbol(SPLICE_UNQUOTE)), x.1],
+                "\'" => vec![Rc::new(MalType::Symbol(QUOTE)), x.1],
+                "`" => vec![Rc::new(MalType::Symbol(QUASIQUOTE)), x.1],
+                "@" => vec![Rc::new(MalType::Symbol(DEREF)), x.1],
+                "~" => vec![Rc::new(MalType::Symbol(UNQUOTE)), x.1],
                 _ => vec![Rc::new(MalType::Nil)],
             },
         ),
         map(
             preceded(tag("^"), pair(parse_mal, preceded(spc, parse_mal))),
-            |x| {
-                vec![
-                    Rc::new(MalType::Symbol(String::from("with-meta"))),
-                    x.1,
-                    x.0,
-                ]
-            },
+            |x| vec![Rc::new(MalType::Symbol(WITH_META)), x.1, x.0],
         ),
     ))(input)
 }
 
 fn parse_mal(input: &str) -> IResult<&str, Rc<MalType>> {
+    let _depth = match DepthGuard::enter() {
+        Some(depth) => depth,
+        None => return Err(Err::Failure(Error::new(input, ErrorKind::TooLarge))),
+    };
     map(
         alt((
             map(parse_hash_map, MalType::HashMap),
@@ -133,10 +134,10 @@ fn parse_mal(input: &str) -> IResult<&str, Rc<MalType>> {
             map(parse_i32, MalType::Int),
             map(parse_boolean, MalType::Bool),
             map(parse_nil, |_| MalType::Nil),
-            map(parse_keyword, |s| MalType::Keyword(String::from(s))),
+            map(parse_keyword, |s| MalType::Keyword(Symbol::new(s))),
             map(parse_list, MalType::List),
             map(parse_quote, MalType::List),
-            map(parse_symbol, |s| MalType::Symbol(String::from(s))),
+            map(parse_symbol, |s| MalType::Symbol(Symbol::new(s))),
         )),
         Rc::new,
     )(input)
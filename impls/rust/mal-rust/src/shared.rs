@@ -0,0 +1,65 @@
+// The pointers and cells values are made of. By default they are Rc and RefCell, with
+// the `threads` feature Arc and a RwLock behind the same names, so that values and
+// interpreters can be sent to and shared with other threads.
+
+#[cfg(not(feature = "threads"))]
+pub use std::cell::RefCell;
+#[cfg(not(feature = "threads"))]
+pub use std::rc::{Rc, Weak};
+
+#[cfg(feature = "threads")]
+pub use std::sync::{Arc as Rc, Weak};
+
+#[cfg(feature = "threads")]
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockResult};
+
+// What trait objects kept in values must be to share them, see FuncType
+#[cfg(not(feature = "threads"))]
+pub trait Shared {}
+#[cfg(not(feature = "threads"))]
+impl<T: ?Sized> Shared for T {}
+
+#[cfg(feature = "threads")]
+pub trait Shared: Send + Sync {}
+#[cfg(feature = "threads")]
+impl<T: ?Sized + Send + Sync> Shared for T {}
+
+// A RwLock with the methods of RefCell we use. Like RefCell, it doesn't care if a
+// thread panicked while holding it.
+#[cfg(feature = "threads")]
+#[derive(Default)]
+pub struct RefCell<T>(RwLock<T>);
+
+#[cfg(feature = "threads")]
+impl<T> RefCell<T> {
+    pub const fn new(value: T) -> Self {
+        RefCell(RwLock::new(value))
+    }
+
+    pub fn borrow(&self) -> RwLockReadGuard<'_, T> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn borrow_mut(&self) -> RwLockWriteGuard<'_, T> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn try_borrow(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        self.0.try_read()
+    }
+
+    pub fn try_borrow_mut(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        self.0.try_write()
+    }
+
+    pub fn replace(&self, value: T) -> T {
+        std::mem::replace(&mut *self.borrow_mut(), value)
+    }
+}
+
+#[cfg(feature = "threads")]
+impl<T: Clone> Clone for RefCell<T> {
+    fn clone(&self) -> Self {
+        RefCell::new(self.borrow().clone())
+    }
+}
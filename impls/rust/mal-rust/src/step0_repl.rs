@@ -1,9 +1,3 @@
-mod core;
-mod env;
-mod printer;
-mod reader;
-mod types;
-
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
@@ -1,15 +1,9 @@
-mod core;
-mod env;
-mod printer;
-mod reader;
-mod types;
-
-use printer::print_str;
-use reader::read_str;
+use mal_rust::printer::print_str;
+use mal_rust::reader::read_str;
+use mal_rust::shared::Rc;
+use mal_rust::types::MalType;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use std::rc::Rc;
-use types::MalType;
 
 fn read(input: &str) -> Option<Rc<MalType>> {
     match read_str(input) {
@@ -1,16 +1,10 @@
-mod core;
-mod env;
-mod printer;
-mod reader;
-mod types;
-
-use env::Env;
-use printer::print_str;
-use reader::read_str;
+use mal_rust::env::Env;
+use mal_rust::printer::print_str;
+use mal_rust::reader::read_str;
+use mal_rust::shared::Rc;
+use mal_rust::types::MalType;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use std::rc::Rc;
-use types::MalType;
 
 fn read(input: &str) -> Option<Rc<MalType>> {
     match read_str(input) {
@@ -22,7 +16,7 @@ fn read(input: &str) -> Option<Rc<MalType>> {
 fn eval_ast(ast: Rc<MalType>, env: &mut Env) -> Option<Rc<MalType>> {
     match &*ast {
         MalType::Symbol(symbol) => {
-            let mal = env.get(symbol);
+            let mal = env.get(*symbol);
             if mal.is_none() {
                 println!("Cannot resolve symbol \"{}\" in the current scope", symbol);
             }
@@ -72,7 +66,7 @@ fn eval(ast: Rc<MalType>, env: &mut Env) -> Option<Rc<MalType>> {
             match res {
                 Some(mal) => match &*mal {
                     MalType::List(list) => match &*list[0] {
-                        MalType::BuiltinFunc(func) => func(&list[1..]),
+                        MalType::BuiltinFunc(_, func) => func(&list[1..]),
                         _ => None,
                     },
                     _ => panic!(),
@@ -1,16 +1,11 @@
-mod core;
-mod env;
-mod printer;
-mod reader;
-mod types;
-
-use env::Env;
-use printer::print_str;
-use reader::read_str;
+use mal_rust::env::Env;
+use mal_rust::printer::print_str;
+use mal_rust::reader::read_str;
+use mal_rust::shared::{Rc, RefCell};
+use mal_rust::symbol::{DEF, LET};
+use mal_rust::types::MalType;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use std::{cell::RefCell, rc::Rc};
-use types::MalType;
 
 fn read(input: &str) -> Option<Rc<MalType>> {
     match read_str(input) {
@@ -22,7 +17,7 @@ fn read(input: &str) -> Option<Rc<MalType>> {
 fn eval_ast(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
     match &*ast {
         MalType::Symbol(symbol) => {
-            let mal = env.borrow_mut().get(symbol);
+            let mal = env.borrow_mut().get(*symbol);
             if mal.is_none() {
                 println!("{} not found", symbol);
             }
@@ -70,7 +65,7 @@ fn eval(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
             }
 
             if let MalType::Symbol(symbol) = &*list[0] {
-                if symbol == "def!" {
+                if *symbol == DEF {
                     if list.len() != 3 {
                         println!("Wrong amount of arguments for def!");
                         return None;
@@ -79,7 +74,7 @@ fn eval(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                         MalType::Symbol(bind) => {
                             let value = eval(list[2].clone(), env.clone());
                             if let Some(mal) = &value {
-                                env.borrow_mut().set(bind, mal.clone())
+                                env.borrow_mut().set(*bind, mal.clone())
                             }
                             return value;
                         }
@@ -90,7 +85,7 @@ fn eval(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                     }
                 }
 
-                if symbol == "let*" {
+                if *symbol == LET {
                     if list.len() != 3 {
                         println!("Wrong amount of arguments for let*");
                         return None;
@@ -107,7 +102,7 @@ fn eval(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                                         let value =
                                             eval(bind_list[i * 2 + 1].clone(), new_env.clone());
                                         if let Some(mal) = value {
-                                            new_env.borrow_mut().set(bind, mal);
+                                            new_env.borrow_mut().set(*bind, mal);
                                         } else {
                                             return value;
                                         }
@@ -135,7 +130,7 @@ fn eval(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
             match res {
                 Some(mal) => match &*mal {
                     MalType::List(list) => match &*list[0] {
-                        MalType::BuiltinFunc(func) => func(&list[1..]),
+                        MalType::BuiltinFunc(_, func) => func(&list[1..]),
                         _ => None,
                     },
                     _ => panic!(),
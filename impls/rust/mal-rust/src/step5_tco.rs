@@ -1,18 +1,13 @@
-mod core;
-mod env;
-mod printer;
-mod reader;
-mod types;
-
-use crate::core::NameSpace;
-use env::Env;
-use printer::print_str;
-use reader::read_str;
-use types::{ClosureType, MalType};
+use mal_rust::core::NameSpace;
+use mal_rust::env::Env;
+use mal_rust::printer::print_str;
+use mal_rust::reader::read_str;
+use mal_rust::shared::{Rc, RefCell};
+use mal_rust::symbol::{Symbol, AMPERSAND, DEF, DO, FN, IF, LET};
+use mal_rust::types::{Arity, ClosureType, MalType};
 
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use std::{cell::RefCell, rc::Rc};
 
 fn read(input: &str) -> Option<Rc<MalType>> {
     match read_str(input) {
@@ -24,7 +19,7 @@ fn read(input: &str) -> Option<Rc<MalType>> {
 fn eval_ast(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
     match &*ast {
         MalType::Symbol(symbol) => {
-            let mal = env.borrow().get(symbol);
+            let mal = env.borrow().get(*symbol);
             if mal.is_none() {
                 println!("{} not found", symbol);
             }
@@ -74,7 +69,7 @@ fn eval_def(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
             MalType::Symbol(bind) => {
                 let value = eval(list[2].clone(), env.clone());
                 if let Some(mal) = &value {
-                    env.borrow_mut().set(bind, mal.clone())
+                    env.borrow_mut().set(*bind, mal.clone())
                 }
                 value
             }
@@ -105,7 +100,7 @@ fn eval_let(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<(Rc<MalType>, Rc<
                         MalType::Symbol(bind) => {
                             let value = eval(bind_list[i * 2 + 1].clone(), new_env.clone());
                             if let Some(mal) = value {
-                                new_env.borrow_mut().set(bind, mal);
+                                new_env.borrow_mut().set(*bind, mal);
                             } else {
                                 return None;
                             }
@@ -142,7 +137,7 @@ fn eval_fn(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                 let mut parameters = vec![];
                 for bind in bind_list.iter() {
                     if let MalType::Symbol(symbol) = &**bind {
-                        parameters.push(symbol.clone());
+                        parameters.push(*symbol);
                     } else {
                         println!("{} is not a symbol", print_str((*bind).clone(), true, true));
                         return None;
@@ -150,16 +145,20 @@ fn eval_fn(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                 }
                 let body = list[2].clone();
                 Some(Rc::new(MalType::Func(ClosureType {
-                    ast: list[2].clone(),
-                    params: parameters.clone(),
+                    name: None,
+                    arities: vec![Arity {
+                        params: bind_list.clone(),
+                        ast: list[2].clone(),
+                    }],
                     env: env.clone(),
+                    compiled: None,
                     func: Rc::new(move |args| {
                         let mut binds = vec![];
                         let mut exprs = vec![];
                         for i in 0..parameters.len() {
-                            if parameters[i] == "&" {
+                            if parameters[i] == AMPERSAND {
                                 if i + 1 < parameters.len() {
-                                    binds.push(parameters[i + 1].as_str());
+                                    binds.push(parameters[i + 1]);
                                     let mut rest = vec![];
                                     for arg in args.iter().skip(i) {
                                         rest.push(arg.clone());
@@ -168,7 +167,7 @@ fn eval_fn(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                                 }
                                 break;
                             } else {
-                                binds.push(parameters[i].as_str());
+                                binds.push(parameters[i]);
                                 exprs.push(args[i].clone());
                             }
                         }
@@ -245,24 +244,24 @@ fn eval(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                 }
 
                 if let MalType::Symbol(symbol) = &*list[0] {
-                    if symbol == "def!" {
+                    if *symbol == DEF {
                         return eval_def(ast, env);
                     }
-                    if symbol == "let*" {
+                    if *symbol == LET {
                         // Tail Call Optimization
                         let (new_ast, new_env) = eval_let(ast, env)?;
                         ast = new_ast;
                         env = new_env;
                         continue;
                     }
-                    if symbol == "fn*" {
+                    if *symbol == FN {
                         return eval_fn(ast, env);
                     }
-                    if symbol == "if" {
+                    if *symbol == IF {
                         ast = eval_if(ast, env.clone())?;
                         continue;
                     }
-                    if symbol == "do" {
+                    if *symbol == DO {
                         // Tail Call Optimization
                         ast = eval_do(ast, env.clone())?;
                         continue;
@@ -273,14 +272,22 @@ fn eval(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                 return match res {
                     Some(mal) => match &*mal {
                         MalType::List(list) => match &*list[0] {
-                            MalType::BuiltinFunc(func) => func(&list[1..]),
+                            MalType::BuiltinFunc(_, func) => func(&list[1..]),
                             MalType::Func(closure) => {
+                                let params: Vec<Symbol> = closure.arities[0]
+                                    .params
+                                    .iter()
+                                    .map(|param| match &**param {
+                                        MalType::Symbol(symbol) => *symbol,
+                                        _ => Symbol::new(""),
+                                    })
+                                    .collect();
                                 let mut binds = vec![];
                                 let mut exprs = vec![];
-                                for i in 0..closure.params.len() {
-                                    if closure.params[i] == "&" {
-                                        if i + 1 < closure.params.len() {
-                                            binds.push(closure.params[i + 1].as_str());
+                                for i in 0..params.len() {
+                                    if params[i] == AMPERSAND {
+                                        if i + 1 < params.len() {
+                                            binds.push(params[i + 1]);
                                             let mut rest = vec![];
                                             for arg in list.iter().skip(i + 1) {
                                                 rest.push(arg.clone());
@@ -289,7 +296,7 @@ fn eval(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                                         }
                                         break;
                                     } else {
-                                        binds.push(closure.params[i].as_str());
+                                        binds.push(params[i]);
                                         exprs.push(list[i + 1].clone());
                                     }
                                 }
@@ -299,7 +306,7 @@ fn eval(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                                     &exprs,
                                 )));
                                 env = new_env;
-                                ast = closure.ast.clone();
+                                ast = closure.arities[0].ast.clone();
                                 continue;
                             }
                             _ => Some(mal.clone()),
@@ -330,7 +337,7 @@ fn rep(input: &str, env: Rc<RefCell<Env>>) {
 
 fn load_builtin(env: Rc<RefCell<Env>>) {
     for (name, func) in NameSpace::new().builtin {
-        env.borrow_mut().set(name, Rc::new(func));
+        env.borrow_mut().set(Symbol::new(name), Rc::new(func));
     }
     eval(read("(def! not (fn* (a) (if a false true)))").unwrap(), env);
 }
@@ -1,18 +1,13 @@
-mod core;
-mod env;
-mod printer;
-mod reader;
-mod types;
-
-use crate::core::NameSpace;
-use env::Env;
-use printer::print_str;
-use reader::read_str;
-use types::{ClosureType, MalType};
+use mal_rust::core::NameSpace;
+use mal_rust::env::Env;
+use mal_rust::printer::print_str;
+use mal_rust::reader::read_str;
+use mal_rust::shared::{Rc, RefCell};
+use mal_rust::symbol::{Symbol, AMPERSAND, DEF, DO, FN, IF, LET};
+use mal_rust::types::{Arity, ClosureType, MalType};
 
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use std::{cell::RefCell, rc::Rc};
 
 fn read(input: &str) -> Option<Rc<MalType>> {
     match read_str(input) {
@@ -24,7 +19,7 @@ fn read(input: &str) -> Option<Rc<MalType>> {
 fn eval_ast(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
     match &*ast {
         MalType::Symbol(symbol) => {
-            let mal = env.borrow().get(symbol);
+            let mal = env.borrow().get(*symbol);
             if mal.is_none() {
                 println!("{} not found", symbol);
             }
@@ -74,7 +69,7 @@ fn eval_def(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
             MalType::Symbol(bind) => {
                 let value = eval(list[2].clone(), env.clone());
                 if let Some(mal) = &value {
-                    env.borrow_mut().set(bind, mal.clone())
+                    env.borrow_mut().set(*bind, mal.clone())
                 }
                 value
             }
@@ -105,7 +100,7 @@ fn eval_let(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<(Rc<MalType>, Rc<
                         MalType::Symbol(bind) => {
                             let value = eval(bind_list[i * 2 + 1].clone(), new_env.clone());
                             if let Some(mal) = value {
-                                new_env.borrow_mut().set(bind, mal);
+                                new_env.borrow_mut().set(*bind, mal);
                             } else {
                                 return None;
                             }
@@ -142,7 +137,7 @@ fn eval_fn(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                 let mut parameters = vec![];
                 for bind in bind_list.iter() {
                     if let MalType::Symbol(symbol) = &**bind {
-                        parameters.push(symbol.clone());
+                        parameters.push(*symbol);
                     } else {
                         println!("{} is not a symbol", print_str((*bind).clone(), true, true));
                         return None;
@@ -150,16 +145,20 @@ fn eval_fn(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                 }
                 let body = list[2].clone();
                 Some(Rc::new(MalType::Func(ClosureType {
-                    ast: list[2].clone(),
-                    params: parameters.clone(),
+                    name: None,
+                    arities: vec![Arity {
+                        params: bind_list.clone(),
+                        ast: list[2].clone(),
+                    }],
                     env: env.clone(),
+                    compiled: None,
                     func: Rc::new(move |args| {
                         let mut binds = vec![];
                         let mut exprs = vec![];
                         for i in 0..parameters.len() {
-                            if parameters[i] == "&" {
+                            if parameters[i] == AMPERSAND {
                                 if i + 1 < parameters.len() {
-                                    binds.push(parameters[i + 1].as_str());
+                                    binds.push(parameters[i + 1]);
                                     let mut rest = vec![];
                                     for arg in args.iter().skip(i) {
                                         rest.push(arg.clone());
@@ -171,7 +170,7 @@ fn eval_fn(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                                 if i > args.len() - 1 {
                                     break;
                                 }
-                                binds.push(parameters[i].as_str());
+                                binds.push(parameters[i]);
                                 exprs.push(args[i].clone());
                             }
                         }
@@ -248,24 +247,24 @@ fn eval(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                 }
 
                 if let MalType::Symbol(symbol) = &*list[0] {
-                    if symbol == "def!" {
+                    if *symbol == DEF {
                         return eval_def(ast, env);
                     }
-                    if symbol == "let*" {
+                    if *symbol == LET {
                         // Tail Call Optimization
                         let (new_ast, new_env) = eval_let(ast, env)?;
                         ast = new_ast;
                         env = new_env;
                         continue;
                     }
-                    if symbol == "fn*" {
+                    if *symbol == FN {
                         return eval_fn(ast, env);
                     }
-                    if symbol == "if" {
+                    if *symbol == IF {
                         ast = eval_if(ast, env.clone())?;
                         continue;
                     }
-                    if symbol == "do" {
+                    if *symbol == DO {
                         // Tail Call Optimization
                         ast = eval_do(ast, env.clone())?;
                         continue;
@@ -276,14 +275,22 @@ fn eval(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                 return match res {
                     Some(mal) => match &*mal {
                         MalType::List(list) => match &*list[0] {
-                            MalType::BuiltinFunc(func) => func(&list[1..]),
+                            MalType::BuiltinFunc(_, func) => func(&list[1..]),
                             MalType::Func(closure) => {
+                                let params: Vec<Symbol> = closure.arities[0]
+                                    .params
+                                    .iter()
+                                    .map(|param| match &**param {
+                                        MalType::Symbol(symbol) => *symbol,
+                                        _ => Symbol::new(""),
+                                    })
+                                    .collect();
                                 let mut binds = vec![];
                                 let mut exprs = vec![];
-                                for i in 0..closure.params.len() {
-                                    if closure.params[i] == "&" {
-                                        if i + 1 < closure.params.len() {
-                                            binds.push(closure.params[i + 1].as_str());
+                                for i in 0..params.len() {
+                                    if params[i] == AMPERSAND {
+                                        if i + 1 < params.len() {
+                                            binds.push(params[i + 1]);
                                             let mut rest = vec![];
                                             for arg in list.iter().skip(i + 1) {
                                                 rest.push(arg.clone());
@@ -295,7 +302,7 @@ fn eval(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                                         if i + 1 > list.len() - 1 {
                                             break;
                                         }
-                                        binds.push(closure.params[i].as_str());
+                                        binds.push(params[i]);
                                         exprs.push(list[i + 1].clone());
                                     }
                                 }
@@ -305,7 +312,7 @@ fn eval(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
                                     &exprs,
                                 )));
                                 env = new_env;
-                                ast = closure.ast.clone();
+                                ast = closure.arities[0].ast.clone();
                                 continue;
                             }
                             _ => Some(mal.clone()),
@@ -336,7 +343,7 @@ fn rep(input: &str, env: Rc<RefCell<Env>>) {
 
 fn load_builtin(repl_env: Rc<RefCell<Env>>) {
     for (name, func) in NameSpace::new().builtin {
-        repl_env.borrow_mut().set(name, Rc::new(func));
+        repl_env.borrow_mut().set(Symbol::new(name), Rc::new(func));
     }
     eval(
         read("(def! not (fn* (a) (if a false true)))").unwrap(),
@@ -351,14 +358,17 @@ fn load_builtin(repl_env: Rc<RefCell<Env>>) {
     // Looks silly, need to beutify
     let clone_env = repl_env.clone();
     repl_env.borrow_mut().set(
-        "eval",
-        Rc::new(MalType::BuiltinFunc(Rc::new(move |args| {
-            if args.is_empty() {
-                Some(Rc::new(MalType::Nil))
-            } else {
-                eval(args[0].clone(), clone_env.clone())
-            }
-        }))),
+        Symbol::new("eval"),
+        Rc::new(MalType::BuiltinFunc(
+            String::from("eval"),
+            Rc::new(move |args| {
+                if args.is_empty() {
+                    Some(Rc::new(MalType::Nil))
+                } else {
+                    eval(args[0].clone(), clone_env.clone())
+                }
+            }),
+        )),
     );
 }
 
@@ -375,14 +385,15 @@ fn main() {
         for arg in args.iter().skip(2) {
             argv.push(Rc::new(MalType::Str(arg.clone())));
         }
-        env.borrow_mut().set("*ARGV*", Rc::new(MalType::List(argv)));
+        env.borrow_mut()
+            .set(Symbol::new("*ARGV*"), Rc::new(MalType::List(argv)));
         let command = format!("(load-file \"{}\")", filename);
         if let Some(ast) = read(command.as_str()) {
             eval(ast, env);
         }
     } else {
         env.borrow_mut()
-            .set("*ARGV*", Rc::new(MalType::List(vec![])));
+            .set(Symbol::new("*ARGV*"), Rc::new(MalType::List(vec![])));
         loop {
             let readline = rl.readline("user> ");
             match readline {
@@ -0,0 +1,224 @@
+// Where prn, println and writes to *out* and *err* go, and where reading *in* comes
+// from. Each interpreter has its own, the process' stdio unless the host hands it
+// writers and a reader of its own. with-out-str captures what is printed to *out* on
+// the thread running its body. Errors are reported to *err* too, dropped like
+// everything else written to stdio the interpreter wasn't given.
+//
+// *out*, *err* and *in* are looked up when they are used, so that (def! *out* file)
+// sends what prn and println print, and errors with *err*, to the file instead.
+
+use crate::env::Env;
+use crate::exception;
+use crate::file::FileHandle;
+use crate::shared::{Rc, RefCell, Weak};
+use crate::symbol::Symbol;
+use crate::types::MalType;
+use std::cell;
+use std::io::{self, BufRead, Write};
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+
+pub type Writer = Box<dyn Write + Send>;
+pub type Reader = Box<dyn BufRead + Send>;
+
+// None stands for the stdio of the process, which is shared with the REPL
+pub struct Streams {
+    out: Mutex<Option<Writer>>,
+    err: Mutex<Option<Writer>>,
+    input: Mutex<Option<Reader>>,
+    // Whether stdout and stderr, and stdin, may be used without a writer or reader
+    stdout: bool,
+    stdin: bool,
+    // Where *out*, *err* and *in* are defined, see set_globals
+    globals: OnceLock<Weak<RefCell<Env>>>,
+}
+
+thread_local! {
+    // What with-out-str bodies running on this thread printed so far, innermost last
+    static CAPTURED: cell::RefCell<Vec<String>> = const { cell::RefCell::new(vec![]) };
+    // The streams of the interpreter evaluating on this thread, see Reporting
+    static CURRENT: cell::RefCell<Option<Rc<Streams>>> = const { cell::RefCell::new(None) };
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Streams {
+    pub fn new(stdout: bool, stdin: bool) -> Self {
+        Self {
+            out: Mutex::new(None),
+            err: Mutex::new(None),
+            input: Mutex::new(None),
+            stdout,
+            stdin,
+            globals: OnceLock::new(),
+        }
+    }
+
+    // The env of the interpreter the streams are for
+    pub fn set_globals(&self, globals: &Rc<RefCell<Env>>) {
+        let _ = self.globals.set(Rc::downgrade(globals));
+    }
+
+    // The file name is bound to now. Not while the globals are being changed, as
+    // when an error is reported defining something.
+    fn bound(&self, name: &str) -> Option<Rc<FileHandle>> {
+        let globals = self.globals.get()?.upgrade()?;
+        let value = globals.try_borrow().ok()?.get_global(Symbol::new(name))?;
+        match &*value {
+            MalType::File(file) => Some(file.clone()),
+            _ => None,
+        }
+    }
+
+    // Prints to *out*, unless with-out-str is capturing it
+    pub fn print(&self, text: &str) -> io::Result<()> {
+        let capturing = CAPTURED.with(|captured| !captured.borrow().is_empty());
+        match self.bound("*out*") {
+            Some(file) if !capturing => file.write(text),
+            _ => self.write_out(text),
+        }
+    }
+
+    pub fn print_err(&self, text: &str) -> io::Result<()> {
+        match self.bound("*err*") {
+            Some(file) => file.write(text),
+            None => self.write_err(text),
+        }
+    }
+
+    // The next line of *in*
+    pub fn read(&self) -> io::Result<Option<String>> {
+        match self.bound("*in*") {
+            Some(file) => file.read_line(),
+            None => self.read_line(),
+        }
+    }
+
+    pub fn set_out(&self, writer: Option<Writer>) {
+        *lock(&self.out) = writer;
+    }
+
+    pub fn set_err(&self, writer: Option<Writer>) {
+        *lock(&self.err) = writer;
+    }
+
+    pub fn set_in(&self, reader: Option<Reader>) {
+        *lock(&self.input) = reader;
+    }
+
+    pub fn write_out(&self, text: &str) -> io::Result<()> {
+        let captured = CAPTURED.with(|captured| match captured.borrow_mut().last_mut() {
+            Some(captured) => {
+                captured.push_str(text);
+                true
+            }
+            None => false,
+        });
+        if captured {
+            return Ok(());
+        }
+        match &mut *lock(&self.out) {
+            Some(writer) => writer.write_all(text.as_bytes()),
+            None if self.stdout => io::stdout().write_all(text.as_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn write_err(&self, text: &str) -> io::Result<()> {
+        match &mut *lock(&self.err) {
+            Some(writer) => writer.write_all(text.as_bytes()),
+            None if self.stdout => io::stderr().write_all(text.as_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        match &mut *lock(&self.out) {
+            Some(writer) => writer.flush()?,
+            None if self.stdout => io::stdout().flush()?,
+            None => {}
+        }
+        match &mut *lock(&self.err) {
+            Some(writer) => writer.flush(),
+            None if self.stdout => io::stderr().flush(),
+            None => Ok(()),
+        }
+    }
+
+    // The next line without its line ending, None at the end of the input
+    pub fn read_line(&self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        let read = match &mut *lock(&self.input) {
+            Some(reader) => reader.read_line(&mut line)?,
+            None if self.stdin => io::stdin().read_line(&mut line)?,
+            None => 0,
+        };
+        if read == 0 {
+            return Ok(None);
+        }
+        chomp(&mut line);
+        Ok(Some(line))
+    }
+}
+
+// Drops the line ending read_line leaves on line
+pub fn chomp(line: &mut String) {
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+}
+
+// Runs body with what it prints to *out* on this thread kept from the writer and
+// handed back instead
+pub fn capture<T>(body: impl FnOnce() -> T) -> (T, String) {
+    CAPTURED.with(|captured| captured.borrow_mut().push(String::new()));
+    let result = body();
+    let text = CAPTURED.with(|captured| captured.borrow_mut().pop().unwrap_or_default());
+    (result, text)
+}
+
+// Makes streams the ones errors on this thread are reported to while it is alive
+pub struct Reporting(Option<Rc<Streams>>);
+
+impl Reporting {
+    pub fn enter(streams: Option<Rc<Streams>>) -> Self {
+        Reporting(CURRENT.with(|current| current.replace(streams)))
+    }
+}
+
+impl Drop for Reporting {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+    }
+}
+
+pub fn current() -> Option<Rc<Streams>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+// An error, on a line of its own in the *err* of the interpreter evaluating, or on
+// stdout outside of one, as the steps before it print them. Inside a try* it is
+// kept for the catch* instead.
+pub fn error(message: &str) {
+    if exception::keep(message) {
+        return;
+    }
+    match current() {
+        Some(streams) => {
+            let _ = streams.print_err(&format!("{}\n", message));
+        }
+        None => println!("{}", message),
+    }
+}
+
+// error with the arguments of format!
+macro_rules! report {
+    ($($arg:tt)*) => {
+        $crate::stream::error(&format!($($arg)*))
+    };
+}
+pub(crate) use report;
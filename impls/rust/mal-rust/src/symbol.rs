@@ -0,0 +1,163 @@
+use crate::budget;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::sync::{OnceLock, RwLock};
+
+// The name of a symbol or keyword, interned: each name is stored once, for good, and
+// stands for a small id, so comparing and hashing symbols never looks at the text.
+// Since names are never freed, each new one counts against the allocation budget of
+// the evaluation making it, see budget.rs, as read-string and gensym may make any
+// number of them.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+// Names the evaluators look for, interned first so they can be matched as constants
+macro_rules! predefined {
+    ($($constant:ident = $name:literal,)*) => {
+        #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+        enum Predefined {
+            $($constant,)*
+        }
+
+        const PREDEFINED: &[&str] = &[$($name,)*];
+
+        $(pub const $constant: Symbol = Symbol(Predefined::$constant as u32);)*
+    };
+}
+
+predefined! {
+    DEF = "def!",
+    LET = "let*",
+    FN = "fn*",
+    DO = "do",
+    IF = "if",
+    QUOTE = "quote",
+    QUASIQUOTE = "quasiquote",
+    QUASIQUOTEEXPAND = "quasiquoteexpand",
+    UNQUOTE = "unquote",
+    SPLICE_UNQUOTE = "splice-unquote",
+    LOOP = "loop*",
+    RECUR = "recur",
+    AMPERSAND = "&",
+    AS = "as",
+    KEYS = "keys",
+    STRS = "strs",
+    SYMS = "syms",
+    OR = "or",
+    CONS = "cons",
+    CONCAT = "concat",
+    VEC = "vec",
+    DEREF = "deref",
+    WITH_META = "with-meta",
+    ARGLISTS = "arglists",
+    FUTURE = "future",
+    FUTURE_CALL = "future-call",
+    GO = "go",
+    GO_CALL = "go-call",
+    WITH_OPEN = "with-open",
+    WITH_OPEN_CALL = "with-open-call",
+    WITH_OUT_STR = "with-out-str",
+    WITH_OUT_STR_CALL = "with-out-str-call",
+    DEFTEST = "deftest",
+    DEFTEST_CALL = "deftest-call",
+    IS = "is",
+    IS_CALL = "is-call",
+    ARE = "are",
+    TESTING = "testing",
+    TESTING_CALL = "testing-call",
+    EQUAL = "=",
+    NS = "ns",
+    NS_CALL = "ns-call",
+    DEF_PRIVATE = "def-",
+    DEFMACRO = "defmacro!",
+    DEFMACRO_CALL = "defmacro-call",
+    MACROEXPAND = "macroexpand",
+    TRY = "try*",
+    CATCH = "catch*",
+    TRY_CALL = "try-call",
+}
+
+struct Interner {
+    ids: HashMap<&'static str, u32>,
+    names: Vec<&'static str>,
+}
+
+fn interner() -> &'static RwLock<Interner> {
+    static INTERNER: OnceLock<RwLock<Interner>> = OnceLock::new();
+    INTERNER.get_or_init(|| {
+        RwLock::new(Interner {
+            ids: PREDEFINED
+                .iter()
+                .enumerate()
+                .map(|(id, name)| (*name, id as u32))
+                .collect(),
+            names: PREDEFINED.to_vec(),
+        })
+    })
+}
+
+impl Symbol {
+    pub fn new(name: &str) -> Self {
+        if let Some(id) = interner().read().unwrap().ids.get(name) {
+            return Symbol(*id);
+        }
+        // Over budget, the evaluation fails at its next allocation. Before locking, as
+        // that is reported.
+        let _ = budget::allocate(name.len() / 8);
+        let mut interner = interner().write().unwrap();
+        if let Some(id) = interner.ids.get(name) {
+            return Symbol(*id);
+        }
+        let name: &'static str = Box::leak(name.into());
+        let id = interner.names.len() as u32;
+        interner.names.push(name);
+        interner.ids.insert(name, id);
+        Symbol(id)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        interner().read().unwrap().names[self.0 as usize]
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}#{}", self.as_str(), self.0)
+    }
+}
+
+// Symbol ids are small consecutive numbers, spreading them with a multiply is enough
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0.rotate_left(5) ^ *byte as u64).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+        }
+    }
+
+    fn write_u32(&mut self, id: u32) {
+        self.0 = (id as u64).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+    }
+}
+
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<SymbolHasher>>;
+pub type SymbolSet = HashSet<Symbol, BuildHasherDefault<SymbolHasher>>;
@@ -0,0 +1,244 @@
+// Bookkeeping for deftest, is and run-tests in the style of clojure.test: the tests
+// defined so far, the fixtures around them and what the run going on found, and the
+// summary and JUnit XML written for a run
+
+use crate::shared::{Rc, RefCell};
+use crate::types::MalType;
+use std::time::Duration;
+
+pub struct Failure {
+    // The testing descriptions around the assertion, outermost first
+    pub context: Vec<String>,
+    pub message: Option<String>,
+    pub expected: String,
+    pub actual: String,
+    // The assertion couldn't be evaluated, rather than being false
+    pub error: bool,
+}
+
+pub struct Outcome {
+    pub name: String,
+    pub assertions: usize,
+    pub failures: Vec<Failure>,
+    pub time: Duration,
+}
+
+#[derive(Default)]
+pub struct Suite {
+    tests: RefCell<Vec<(String, Rc<MalType>)>>,
+    // Called with a function running all the tests, and each of them
+    once: RefCell<Vec<Rc<MalType>>>,
+    each: RefCell<Vec<Rc<MalType>>>,
+    context: RefCell<Vec<String>>,
+    // The tests run so far while run-tests runs, the last one is running
+    outcomes: RefCell<Option<Vec<Outcome>>>,
+}
+
+impl Suite {
+    // A test defined again replaces the one before
+    pub fn define(&self, name: &str, test: Rc<MalType>) {
+        let mut tests = self.tests.borrow_mut();
+        match tests.iter_mut().find(|(defined, _)| defined == name) {
+            Some(defined) => defined.1 = test,
+            None => tests.push((name.to_string(), test)),
+        }
+    }
+
+    pub fn tests(&self) -> Vec<(String, Rc<MalType>)> {
+        self.tests.borrow().clone()
+    }
+
+    pub fn set_fixtures(&self, once: bool, fixtures: Vec<Rc<MalType>>) {
+        if once {
+            *self.once.borrow_mut() = fixtures;
+        } else {
+            *self.each.borrow_mut() = fixtures;
+        }
+    }
+
+    pub fn fixtures(&self, once: bool) -> Vec<Rc<MalType>> {
+        if once {
+            self.once.borrow().clone()
+        } else {
+            self.each.borrow().clone()
+        }
+    }
+
+    pub fn enter(&self, context: &str) {
+        self.context.borrow_mut().push(context.to_string());
+    }
+
+    pub fn leave(&self) {
+        self.context.borrow_mut().pop();
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.outcomes.borrow().is_some()
+    }
+
+    pub fn begin(&self) {
+        *self.outcomes.borrow_mut() = Some(vec![]);
+        self.context.borrow_mut().clear();
+    }
+
+    pub fn start(&self, name: &str) {
+        if let Some(outcomes) = &mut *self.outcomes.borrow_mut() {
+            outcomes.push(Outcome {
+                name: name.to_string(),
+                assertions: 0,
+                failures: vec![],
+                time: Duration::ZERO,
+            });
+        }
+    }
+
+    pub fn finish(&self, time: Duration) {
+        if let Some(outcome) = self.outcomes.borrow_mut().iter_mut().flatten().last() {
+            outcome.time = time;
+        }
+    }
+
+    pub fn end(&self) -> Vec<Outcome> {
+        self.outcomes.borrow_mut().take().unwrap_or_default()
+    }
+
+    // Counts an assertion of the running test, the failure given back when no test
+    // is running for it to be reported right away
+    pub fn assert(&self, failure: Option<Failure>) -> Option<Failure> {
+        let mut outcomes = self.outcomes.borrow_mut();
+        let outcome = match outcomes.iter_mut().flatten().last() {
+            Some(outcome) => outcome,
+            None => return failure,
+        };
+        outcome.assertions += 1;
+        outcome.failures.extend(failure);
+        None
+    }
+
+    // The running test stopped with an error
+    pub fn error(&self, actual: &str) {
+        let failure = Failure {
+            context: vec![],
+            message: None,
+            expected: String::from("the test to run to the end"),
+            actual: actual.to_string(),
+            error: true,
+        };
+        if let Some(outcome) = self.outcomes.borrow_mut().iter_mut().flatten().last() {
+            outcome.failures.push(failure);
+        }
+    }
+
+    pub fn failure(&self, message: Option<String>, expected: String, actual: String) -> Failure {
+        Failure {
+            context: self.context.borrow().clone(),
+            message,
+            expected,
+            actual,
+            error: false,
+        }
+    }
+}
+
+impl Failure {
+    pub fn describe(&self, test: &str) -> String {
+        let mut text = format!(
+            "\n{} in ({})",
+            if self.error { "ERROR" } else { "FAIL" },
+            test
+        );
+        if !self.context.is_empty() {
+            text = text + "\n" + &self.context.join(" ");
+        }
+        if let Some(message) = &self.message {
+            text = text + "\n" + message;
+        }
+        format!(
+            "{}\nexpected: {}\n  actual: {}\n",
+            text, self.expected, self.actual
+        )
+    }
+}
+
+pub struct Summary {
+    pub tests: usize,
+    pub assertions: usize,
+    pub failures: usize,
+    pub errors: usize,
+}
+
+pub fn summarize(outcomes: &[Outcome]) -> Summary {
+    let failures = outcomes.iter().flat_map(|outcome| outcome.failures.iter());
+    Summary {
+        tests: outcomes.len(),
+        assertions: outcomes.iter().map(|outcome| outcome.assertions).sum(),
+        failures: failures.clone().filter(|failure| !failure.error).count(),
+        errors: failures.filter(|failure| failure.error).count(),
+    }
+}
+
+// What run-tests prints after the tests ran
+pub fn report(outcomes: &[Outcome]) -> String {
+    let mut text = String::new();
+    for outcome in outcomes {
+        for failure in outcome.failures.iter() {
+            text += &failure.describe(&outcome.name);
+        }
+    }
+    let summary = summarize(outcomes);
+    text + &format!(
+        "\nRan {} tests containing {} assertions.\n{} failures, {} errors.\n",
+        summary.tests, summary.assertions, summary.failures, summary.errors
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// The run as a JUnit XML report, one testsuite named name
+pub fn junit(name: &str, outcomes: &[Outcome]) -> String {
+    let summary = summarize(outcomes);
+    let time: Duration = outcomes.iter().map(|outcome| outcome.time).sum();
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">\n",
+        escape(name),
+        summary.tests,
+        summary.failures,
+        summary.errors,
+        time.as_secs_f64()
+    );
+    for outcome in outcomes {
+        xml += &format!(
+            "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+            escape(&outcome.name),
+            escape(name),
+            outcome.time.as_secs_f64()
+        );
+        if outcome.failures.is_empty() {
+            xml += "/>\n";
+            continue;
+        }
+        xml += ">\n";
+        for failure in outcome.failures.iter() {
+            let kind = if failure.error { "error" } else { "failure" };
+            let message = match (&failure.message, failure.context.is_empty()) {
+                (Some(message), _) => message.clone(),
+                (None, false) => failure.context.join(" "),
+                (None, true) => format!("expected: {}", failure.expected),
+            };
+            xml += &format!(
+                "      <{} message=\"{}\">{}</{}>\n",
+                kind,
+                escape(&message),
+                escape(failure.describe(&outcome.name).trim()),
+                kind
+            );
+        }
+        xml += "    </testcase>\n";
+    }
+    xml + "  </testsuite>\n</testsuites>\n"
+}
@@ -1,23 +1,92 @@
+use crate::channel::Channel;
 use crate::env::Env;
-use std::cell::RefCell;
-use std::rc::Rc;
+use crate::file::FileHandle;
+use crate::gc::Trace;
+use crate::promise::Promise;
+use crate::shared::{Rc, RefCell};
+use crate::symbol::{Symbol, AMPERSAND, AS};
 
 pub type KV = (Rc<MalType>, Rc<MalType>);
 
+#[cfg(not(feature = "threads"))]
 pub type FuncType = dyn Fn(&[Rc<MalType>]) -> Option<Rc<MalType>>;
-pub struct ClosureType {
+#[cfg(feature = "threads")]
+pub type FuncType = dyn Fn(&[Rc<MalType>]) -> Option<Rc<MalType>> + Send + Sync;
+
+// One `([params] body)` clause of a fn*
+#[derive(Clone)]
+pub struct Arity {
+    pub params: Vec<Rc<MalType>>,
     pub ast: Rc<MalType>,
-    pub params: Vec<String>,
+}
+
+impl Arity {
+    // Amount of arguments bound before `&`, `:as all` binds no argument
+    pub fn required(&self) -> usize {
+        let mut required = 0;
+        let mut i = 0;
+        while i < self.params.len() {
+            match &*self.params[i] {
+                MalType::Symbol(symbol) if *symbol == AMPERSAND => break,
+                MalType::Keyword(keyword) if *keyword == AS => i += 2,
+                _ => {
+                    required += 1;
+                    i += 1;
+                }
+            }
+        }
+        required
+    }
+
+    pub fn is_variadic(&self) -> bool {
+        self.params
+            .iter()
+            .any(|param| matches!(&**param, MalType::Symbol(symbol) if *symbol == AMPERSAND))
+    }
+
+    pub fn accepts(&self, argc: usize) -> bool {
+        if self.is_variadic() {
+            argc >= self.required()
+        } else {
+            argc == self.required()
+        }
+    }
+}
+
+// Picks the clause for argc arguments, a fixed arity wins over a variadic one
+pub fn select_arity(arities: &[Arity], argc: usize) -> Option<&Arity> {
+    arities
+        .iter()
+        .find(|arity| !arity.is_variadic() && arity.accepts(argc))
+        .or_else(|| arities.iter().find(|arity| arity.accepts(argc)))
+}
+
+// The parameter vectors of all clauses, as in `(meta f)`
+pub fn arglists(arities: &[Arity]) -> Rc<MalType> {
+    Rc::new(MalType::List(
+        arities
+            .iter()
+            .map(|arity| Rc::new(MalType::Vector(arity.params.clone())))
+            .collect(),
+    ))
+}
+
+pub struct ClosureType {
+    pub name: Option<String>,
+    pub arities: Vec<Arity>,
     pub env: Rc<RefCell<Env>>,
+    // What the analyzer or the VM compiled the function to, see analyzer.rs and vm.rs
+    pub compiled: Option<Rc<dyn Trace>>,
     pub func: Rc<FuncType>,
 }
 
 impl Clone for ClosureType {
     fn clone(&self) -> Self {
         Self {
-            ast: self.ast.clone(),
-            params: self.params.clone(),
+            name: self.name.clone(),
+            arities: self.arities.clone(),
             env: self.env.clone(),
+            compiled: self.compiled.clone(),
             func: self.func.clone(),
         }
     }
@@ -26,40 +95,102 @@ impl Clone for ClosureType {
 #[derive(Clone)]
 pub enum MalType {
     Int(i32),
-    Symbol(String),
+    Symbol(Symbol),
     List(Vec<Rc<MalType>>),
     HashMap(Vec<KV>),
-    Keyword(String),
+    Keyword(Symbol),
     Str(String),
     Vector(Vec<Rc<MalType>>),
     Bool(bool),
-    BuiltinFunc(Rc<FuncType>),
+    BuiltinFunc(String, Rc<FuncType>),
     Atom(RefCell<Rc<MalType>>),
     Func(ClosureType),
+    // A fn* defmacro! made a macro of, called on the forms of a call instead of
+    // their values, see analyzer::macroexpand
+    Macro(Rc<MalType>),
+    Promise(Rc<Promise>),
+    Chan(Rc<Channel>),
+    File(Rc<FileHandle>),
     Nil,
 }
 
+// Compares pairs of items one after the other instead of recursing, so values of any
+// depth can be compared
 impl PartialEq for MalType {
     fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (MalType::Int(i1), MalType::Int(i2)) => i1 == i2,
-            (MalType::Symbol(s1), MalType::Symbol(s2)) => s1 == s2,
-            (MalType::Str(s1), MalType::Str(s2)) => s1 == s2,
-            (MalType::List(l1) | MalType::Vector(l1), MalType::List(l2) | MalType::Vector(l2)) => {
-                if l1.len() != l2.len() {
-                    return false;
+        let mut pairs = vec![(self, other)];
+        while let Some(pair) = pairs.pop() {
+            let equal = match pair {
+                (
+                    MalType::List(l1) | MalType::Vector(l1),
+                    MalType::List(l2) | MalType::Vector(l2),
+                ) => {
+                    if l1.len() != l2.len() {
+                        return false;
+                    }
+                    pairs.extend(
+                        l1.iter()
+                            .rev()
+                            .zip(l2.iter().rev())
+                            .map(|(a, b)| (&**a, &**b)),
+                    );
+                    true
                 }
-                for (item1, item2) in l1.iter().zip(l2.iter()) {
-                    if **item1 != **item2 {
+                // The same keys with equal values, in any order
+                (MalType::HashMap(m1), MalType::HashMap(m2)) => {
+                    if m1.len() != m2.len() {
                         return false;
                     }
+                    for (key, value) in m1.iter() {
+                        match m2.iter().find(|(other, _)| other == key) {
+                            Some((_, other)) => pairs.push((&**value, &**other)),
+                            None => return false,
+                        }
+                    }
+                    true
                 }
-                true
+                (MalType::Int(i1), MalType::Int(i2)) => i1 == i2,
+                (MalType::Symbol(s1), MalType::Symbol(s2)) => s1 == s2,
+                (MalType::Str(s1), MalType::Str(s2)) => s1 == s2,
+                (MalType::Keyword(k1), MalType::Keyword(k2)) => k1 == k2,
+                (MalType::Bool(b1), MalType::Bool(b2)) => b1 == b2,
+                (MalType::Nil, MalType::Nil) => true,
+                (MalType::Promise(p1), MalType::Promise(p2)) => Rc::ptr_eq(p1, p2),
+                (MalType::Chan(c1), MalType::Chan(c2)) => Rc::ptr_eq(c1, c2),
+                (MalType::File(f1), MalType::File(f2)) => Rc::ptr_eq(f1, f2),
+                (_, _) => false,
+            };
+            if !equal {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl MalType {
+    // The items of a collection, taken out of it
+    fn take_items(&mut self) -> Vec<Rc<MalType>> {
+        match self {
+            MalType::List(items) | MalType::Vector(items) => std::mem::take(items),
+            MalType::HashMap(kvs) => std::mem::take(kvs)
+                .into_iter()
+                .flat_map(|(key, value)| [key, value])
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+// Collections nested deeper than the stack could recurse are dropped item by item,
+// the ones only they hold taken apart in turn
+impl Drop for MalType {
+    fn drop(&mut self) {
+        let mut items = self.take_items();
+        while let Some(item) = items.pop() {
+            if let Some(mut item) = Rc::into_inner(item) {
+                items.append(&mut item.take_items());
             }
-            (MalType::Keyword(k1), MalType::Keyword(k2)) => k1 == k2,
-            (MalType::Bool(b1), MalType::Bool(b2)) => b1 == b2,
-            (MalType::Nil, MalType::Nil) => true,
-            (_, _) => false,
         }
     }
 }
@@ -0,0 +1,380 @@
+// Runs the bytecode made by compiler.rs on a value stack. Calls push a frame instead
+// of recursing, tail calls and recur reuse the frame they are made from.
+
+use crate::budget;
+use crate::compiler::{compile, Capture, Chunk, Lambda, Op, Pack};
+use crate::depth::DepthGuard;
+use crate::env::Env;
+use crate::gc::{self, Trace, Tracer};
+use crate::printer::print_str;
+use crate::shared::{Rc, RefCell};
+use crate::stream::report;
+use crate::types::{arglists, select_arity, ClosureType, MalType, KV};
+
+// A fn* made by the VM, kept behind the `compiled` field of its ClosureType
+pub struct Closure {
+    lambda: Rc<Lambda>,
+    upvalues: Vec<Rc<MalType>>,
+    globals: Rc<RefCell<Env>>,
+}
+
+impl Trace for Closure {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.upvalues.iter().for_each(|value| tracer.value(value));
+        tracer.env(&self.globals);
+    }
+}
+
+struct Frame {
+    chunk: Rc<Chunk>,
+    closure: Option<Rc<Closure>>,
+    ip: usize,
+    // Slot 0 of the frame, the function called sits just under it
+    base: usize,
+    _depth: DepthGuard,
+}
+
+pub fn eval(ast: Rc<MalType>, env: Rc<RefCell<Env>>) -> Option<Rc<MalType>> {
+    let _depth = DepthGuard::enter()?;
+    let chunk = compile(&ast)?;
+    let frame = Frame {
+        chunk,
+        closure: None,
+        ip: 0,
+        base: 1,
+        _depth: DepthGuard::enter()?,
+    };
+    run(env, vec![Rc::new(MalType::Nil)], frame)
+}
+
+// Calls a VM closure from outside the VM, for builtins like swap!
+fn call(closure: &Rc<Closure>, func: Rc<MalType>, args: &[Rc<MalType>]) -> Option<Rc<MalType>> {
+    let mut stack = Vec::with_capacity(args.len() + 1);
+    stack.push(func);
+    stack.extend(args.iter().cloned());
+    let frame = enter(closure, &stack, 1)?;
+    run(closure.globals.clone(), stack, frame)
+}
+
+fn compiled(func: &MalType) -> Option<Rc<Closure>> {
+    match func {
+        MalType::Func(closure) => gc::downcast(closure.compiled.clone()?),
+        _ => None,
+    }
+}
+
+// Picks the clause of closure for the arguments from base on
+fn enter(closure: &Rc<Closure>, stack: &[Rc<MalType>], base: usize) -> Option<Frame> {
+    let lambda = &closure.lambda;
+    let argc = stack.len() - base;
+    let arity = match select_arity(&lambda.arities, argc) {
+        Some(arity) => arity,
+        None => {
+            report!(
+                "Wrong amount of arguments ({}) passed to {} with arglists {}",
+                argc,
+                lambda.name.as_deref().unwrap_or("fn*"),
+                print_str(arglists(&lambda.arities), false, true)
+            );
+            return None;
+        }
+    };
+    let index = lambda
+        .arities
+        .iter()
+        .position(|other| std::ptr::eq(other, arity))
+        .unwrap();
+    Some(Frame {
+        chunk: lambda.chunks[index].clone(),
+        closure: Some(closure.clone()),
+        ip: 0,
+        base,
+        _depth: DepthGuard::enter()?,
+    })
+}
+
+fn make_closure(
+    lambda: &Rc<Lambda>,
+    frame: &Frame,
+    stack: &[Rc<MalType>],
+    globals: &Rc<RefCell<Env>>,
+) -> Rc<MalType> {
+    let upvalues = lambda
+        .captures
+        .iter()
+        .map(|capture| match capture {
+            Capture::Local(slot) => stack[frame.base + *slot as usize].clone(),
+            Capture::Upvalue(index) => {
+                frame.closure.as_ref().unwrap().upvalues[*index as usize].clone()
+            }
+            Capture::SelfFn => stack[frame.base - 1].clone(),
+        })
+        .collect();
+    let closure = Rc::new(Closure {
+        lambda: lambda.clone(),
+        upvalues,
+        globals: globals.clone(),
+    });
+    // The function is handed to itself through a weak reference, for SelfFn in call.
+    // It holds its Closure only through `compiled`, where the cycle collector sees it.
+    let func = Rc::new_cyclic(|this| {
+        let this = this.clone();
+        MalType::Func(ClosureType {
+            name: lambda.name.clone(),
+            arities: lambda.arities.clone(),
+            env: globals.clone(),
+            compiled: Some(closure),
+            func: Rc::new(move |args| {
+                let func = this.upgrade()?;
+                call(&compiled(&func)?, func, args)
+            }),
+        })
+    });
+    gc::track(&func);
+    func
+}
+
+fn is_truthy(value: &MalType) -> bool {
+    !matches!(value, MalType::Bool(false) | MalType::Nil)
+}
+
+fn run(
+    globals: Rc<RefCell<Env>>,
+    mut stack: Vec<Rc<MalType>>,
+    frame: Frame,
+) -> Option<Rc<MalType>> {
+    let mut frames: Vec<Frame> = vec![];
+    let mut frame = frame;
+    loop {
+        let op = frame.chunk.code[frame.ip];
+        frame.ip += 1;
+        match op {
+            Op::Const(index) => stack.push(frame.chunk.consts[index as usize].clone()),
+            Op::Local(slot) => stack.push(stack[frame.base + slot as usize].clone()),
+            Op::Upvalue(index) => {
+                let closure = frame.closure.as_ref().unwrap();
+                stack.push(closure.upvalues[index as usize].clone());
+            }
+            Op::Global(index, public) => {
+                let symbol = &frame.chunk.consts[index as usize];
+                let name = match &**symbol {
+                    MalType::Symbol(name) => name,
+                    _ => unreachable!(),
+                };
+                if public && globals.borrow().is_private(*name) {
+                    report!("{} is private", name);
+                    return None;
+                }
+                let value = globals.borrow().get(*name);
+                match value {
+                    Some(value) => stack.push(value),
+                    None => {
+                        report!("'{}' not found", name);
+                        return None;
+                    }
+                }
+            }
+            Op::Def(index) => {
+                if let MalType::Symbol(name) = &*frame.chunk.consts[index as usize] {
+                    let value = stack.last().unwrap().clone();
+                    globals.borrow_mut().set(*name, value);
+                }
+            }
+            Op::SelfFn => stack.push(stack[frame.base - 1].clone()),
+            Op::Pop => {
+                stack.pop();
+            }
+            Op::Slide(n) => {
+                let value = stack.pop().unwrap();
+                stack.truncate(stack.len() - n as usize);
+                stack.push(value);
+            }
+            Op::Jump(target) => frame.ip = target as usize,
+            Op::JumpIfFalse(target) => {
+                if !is_truthy(&stack.pop().unwrap()) {
+                    frame.ip = target as usize;
+                }
+            }
+            Op::Call(argc) | Op::TailCall(argc) => {
+                budget::step()?;
+                let at = stack.len() - argc as usize - 1;
+                let func = stack[at].clone();
+                if let Some(closure) = compiled(&func) {
+                    if let Op::TailCall(_) = op {
+                        // Slides the function and its arguments down over the frame
+                        let from = frame.base - 1;
+                        stack.drain(from..at);
+                        frame = enter(&closure, &stack, from + 1)?;
+                    } else {
+                        let callee = enter(&closure, &stack, at + 1)?;
+                        frames.push(std::mem::replace(&mut frame, callee));
+                    }
+                    continue;
+                }
+                let value = match &*func {
+                    MalType::BuiltinFunc(_, func) => func(&stack[at + 1..])?,
+                    MalType::Func(closure) => (closure.func)(&stack[at + 1..])?,
+                    _ => Rc::new(MalType::List(stack[at..].to_vec())),
+                };
+                stack.truncate(at);
+                stack.push(value);
+                if let Op::TailCall(_) = op {
+                    match ret(&mut stack, &mut frame, &mut frames) {
+                        Some(value) => return Some(value),
+                        None => continue,
+                    }
+                }
+            }
+            Op::Return => match ret(&mut stack, &mut frame, &mut frames) {
+                Some(value) => return Some(value),
+                None => continue,
+            },
+            Op::Args(required, pack) => {
+                let required = required as usize;
+                let first = match pack {
+                    Pack::Fixed => None,
+                    Pack::Rest => Some(frame.base + required),
+                    Pack::All => Some(frame.base),
+                };
+                if let Some(first) = first {
+                    budget::allocate(stack.len() - first)?;
+                    let rest: Vec<Rc<MalType>> = stack.drain(first..).collect();
+                    stack.push(Rc::new(MalType::List(rest)));
+                }
+            }
+            Op::RecurFn(argc) => {
+                budget::step()?;
+                let from = stack.len() - argc as usize;
+                stack.drain(frame.base..from);
+                frame.ip = 0;
+            }
+            Op::RecurLoop(slot, count, target) => {
+                budget::step()?;
+                let to = frame.base + slot as usize;
+                let from = stack.len() - count as usize;
+                stack.drain(to..from);
+                frame.ip = target as usize;
+            }
+            Op::Closure(index) => {
+                budget::allocate(0)?;
+                let lambda = frame.chunk.lambdas[index as usize].clone();
+                let func = make_closure(&lambda, &frame, &stack, &globals);
+                stack.push(func);
+            }
+            Op::Vector(n) => {
+                budget::allocate(n as usize)?;
+                let items = stack.split_off(stack.len() - n as usize);
+                stack.push(Rc::new(MalType::Vector(items)));
+            }
+            Op::Map(n) => {
+                budget::allocate(n as usize)?;
+                let items = stack.split_off(stack.len() - 2 * n as usize);
+                let kvs: Vec<KV> = items
+                    .chunks(2)
+                    .map(|kv| (kv[0].clone(), kv[1].clone()))
+                    .collect();
+                stack.push(Rc::new(MalType::HashMap(kvs)));
+            }
+            Op::SeqCheck => {
+                let value = stack.last().unwrap();
+                if !matches!(
+                    &**value,
+                    MalType::List(_) | MalType::Vector(_) | MalType::Nil
+                ) {
+                    report!(
+                        "Can not destructure {} as a sequence",
+                        print_str(value.clone(), false, true)
+                    );
+                    return None;
+                }
+            }
+            Op::MapCheck => {
+                let value = stack.last().unwrap();
+                if !matches!(&**value, MalType::HashMap(_) | MalType::Nil) {
+                    report!(
+                        "Can not destructure {} as a map",
+                        print_str(value.clone(), false, true)
+                    );
+                    return None;
+                }
+            }
+            Op::Nth(n) => {
+                let value = stack.pop().unwrap();
+                let item = match &*value {
+                    MalType::List(items) | MalType::Vector(items) => items.get(n as usize).cloned(),
+                    _ => None,
+                };
+                stack.push(item.unwrap_or_else(|| Rc::new(MalType::Nil)));
+            }
+            Op::NthRest(n) => {
+                let value = stack.pop().unwrap();
+                let rest = match &*value {
+                    MalType::List(items) | MalType::Vector(items) => {
+                        items.iter().skip(n as usize).cloned().collect()
+                    }
+                    _ => vec![],
+                };
+                stack.push(Rc::new(MalType::List(rest)));
+            }
+            Op::Get(key) => {
+                let value = stack.pop().unwrap();
+                let found = get(&value, &frame.chunk.consts[key as usize]);
+                stack.push(found.unwrap_or_else(|| Rc::new(MalType::Nil)));
+            }
+            Op::NewBox => {
+                budget::allocate(0)?;
+                let boxed = Rc::new(MalType::Atom(RefCell::new(Rc::new(MalType::Nil))));
+                gc::track(&boxed);
+                stack.push(boxed);
+            }
+            Op::SetBox(slot) => {
+                let value = stack.pop().unwrap();
+                if let MalType::Atom(cell) = &*stack[frame.base + slot as usize] {
+                    cell.replace(value);
+                }
+            }
+            Op::Unbox => {
+                let boxed = stack.pop().unwrap();
+                if let MalType::Atom(cell) = &*boxed {
+                    stack.push(cell.borrow().clone());
+                }
+            }
+            Op::GetOr(key, target) => {
+                let value = stack.pop().unwrap();
+                if let Some(found) = get(&value, &frame.chunk.consts[key as usize]) {
+                    stack.push(found);
+                    frame.ip = target as usize;
+                }
+            }
+        }
+    }
+}
+
+// Leaves the frame with the value on top of the stack, handing it back once the
+// outermost frame is done
+fn ret(
+    stack: &mut Vec<Rc<MalType>>,
+    frame: &mut Frame,
+    frames: &mut Vec<Frame>,
+) -> Option<Rc<MalType>> {
+    let value = stack.pop().unwrap();
+    match frames.pop() {
+        Some(caller) => {
+            stack.truncate(frame.base - 1);
+            stack.push(value);
+            *frame = caller;
+            None
+        }
+        None => Some(value),
+    }
+}
+
+fn get(map: &MalType, key: &MalType) -> Option<Rc<MalType>> {
+    match map {
+        MalType::HashMap(entries) => entries
+            .iter()
+            .find(|(k, _)| **k == *key)
+            .map(|(_, v)| v.clone()),
+        _ => None,
+    }
+}
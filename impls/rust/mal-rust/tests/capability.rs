@@ -0,0 +1,186 @@
+// Interpreters embedded with less than all capabilities: what isn't granted is left
+// out or refused, and errors only reach the writers the host gave
+use mal_rust::capability::Capabilities;
+use mal_rust::interpreter::{Backend, Interpreter};
+use mal_rust::printer::print_str;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+// What the interpreter wrote, for the host to look at
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Buffer {
+    fn take(&self) -> String {
+        String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+    }
+}
+
+fn sandbox(capabilities: Capabilities) -> (Interpreter, Buffer) {
+    let interpreter = Interpreter::with_capabilities(Backend::Closures, capabilities);
+    let err = Buffer::default();
+    interpreter.set_err(Some(Box::new(err.clone())));
+    (interpreter, err)
+}
+
+fn eval(interpreter: &Interpreter, input: &str) -> Option<String> {
+    let value = interpreter.eval_str(input)?;
+    Some(print_str(value, false, true))
+}
+
+// A directory of its own for each test, with allowed and outside in it
+fn dirs(test: &str) -> (PathBuf, PathBuf) {
+    let root = std::env::temp_dir().join(format!("mal-capability-{}-{}", test, std::process::id()));
+    let (allowed, outside) = (root.join("allowed"), root.join("outside"));
+    std::fs::create_dir_all(&allowed).unwrap();
+    std::fs::create_dir_all(&outside).unwrap();
+    (allowed, outside)
+}
+
+#[test]
+fn none_granted() {
+    let (interpreter, _) = sandbox(Capabilities::none());
+    let denied = [
+        "prn", "println", "*out*", "*err*", "readline", "*in*", "slurp", "spit", "open", "getenv",
+        "setenv", "time-ms", "sh", "process", "exit",
+    ];
+    for name in denied {
+        assert!(interpreter.get(name).is_none(), "{} is defined", name);
+    }
+    assert_eq!(eval(&interpreter, "(+ 1 2)").as_deref(), Some("3"));
+}
+
+#[test]
+fn stdout() {
+    let capabilities = Capabilities {
+        stdout: true,
+        ..Capabilities::none()
+    };
+    let (interpreter, err) = sandbox(capabilities);
+    let out = Buffer::default();
+    interpreter.set_out(Some(Box::new(out.clone())));
+    eval(&interpreter, "(prn \"out\")").unwrap();
+    assert_eq!(out.take(), "\"out\"\n");
+    assert!(interpreter.get("readline").is_none());
+    assert_eq!(eval(&interpreter, "(slurp \"/etc/hostname\")"), None);
+    assert_eq!(err.take(), "'slurp' not found\n");
+}
+
+#[test]
+fn stdin() {
+    let capabilities = Capabilities {
+        stdin: true,
+        ..Capabilities::none()
+    };
+    let (interpreter, _) = sandbox(capabilities);
+    interpreter.set_in(Some(Box::new(io::Cursor::new("line\n"))));
+    assert_eq!(
+        eval(&interpreter, "(read-line)").as_deref(),
+        Some("\"line\"")
+    );
+    assert!(interpreter.get("prn").is_none());
+}
+
+#[test]
+fn errors_go_to_the_host() {
+    let (interpreter, err) = sandbox(Capabilities::none());
+    assert_eq!(eval(&interpreter, "(undefined)"), None);
+    assert_eq!(err.take(), "'undefined' not found\n");
+    // Without stdout nor a writer of the host's they go nowhere
+    interpreter.set_err(None);
+    assert_eq!(eval(&interpreter, "(undefined)"), None);
+    assert_eq!(err.take(), "");
+}
+
+#[test]
+fn read_roots() {
+    let (allowed, outside) = dirs("read");
+    std::fs::write(allowed.join("inside.mal"), "(def! found 1)").unwrap();
+    std::fs::write(outside.join("secret.mal"), "(def! found 2)").unwrap();
+    let capabilities = Capabilities {
+        read: vec![allowed.clone()],
+        ..Capabilities::none()
+    };
+    let (interpreter, err) = sandbox(capabilities);
+    interpreter.set_load_path(vec![outside.clone(), allowed.clone()]);
+
+    let slurp = format!("(slurp {:?})", outside.join("secret.mal"));
+    assert_eq!(eval(&interpreter, &slurp), None);
+    assert!(err.take().starts_with("Permission denied reading"));
+    // Modules outside the roots aren't even looked at
+    assert_eq!(eval(&interpreter, "(require 'secret)"), None);
+    assert_eq!(
+        err.take(),
+        "Could not find module secret on the load path\n"
+    );
+    assert_eq!(
+        eval(&interpreter, "(require 'inside)").as_deref(),
+        Some("nil")
+    );
+    assert_eq!(eval(&interpreter, "inside/found").as_deref(), Some("1"));
+    assert!(interpreter.get("spit").is_none());
+}
+
+#[test]
+fn write_roots() {
+    let (allowed, outside) = dirs("write");
+    let capabilities = Capabilities {
+        write: vec![allowed.clone()],
+        ..Capabilities::none()
+    };
+    let (interpreter, err) = sandbox(capabilities);
+    let spit = |dir: &PathBuf| format!("(spit {:?} \"text\")", dir.join("file"));
+    assert_eq!(eval(&interpreter, &spit(&outside)), None);
+    assert!(err.take().starts_with("Permission denied writing"));
+    assert!(!outside.join("file").exists());
+    assert_eq!(eval(&interpreter, &spit(&allowed)).as_deref(), Some("nil"));
+    assert_eq!(
+        std::fs::read_to_string(allowed.join("file")).unwrap(),
+        "text"
+    );
+    assert!(interpreter.get("slurp").is_none());
+}
+
+#[test]
+fn env_time_and_exec() {
+    let granted = [
+        (
+            Capabilities {
+                env: true,
+                ..Capabilities::none()
+            },
+            "getenv",
+        ),
+        (
+            Capabilities {
+                time: true,
+                ..Capabilities::none()
+            },
+            "time-ms",
+        ),
+        (
+            Capabilities {
+                exec: true,
+                ..Capabilities::none()
+            },
+            "sh",
+        ),
+    ];
+    for (capabilities, name) in granted {
+        let (interpreter, _) = sandbox(capabilities);
+        for other in ["getenv", "time-ms", "sh"] {
+            assert_eq!(interpreter.get(other).is_some(), other == name, "{}", other);
+        }
+    }
+}
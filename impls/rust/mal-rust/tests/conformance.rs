@@ -0,0 +1,105 @@
+// The tests/step*.mal files mal passes, run by `mal test` on both backends, the tests/lib
+// ones of the libraries built into it, and the impls/rust/tests ones of what was added
+// to mal
+use std::path::Path;
+use std::process::Command;
+
+// file is relative to this crate, vars are set for mal. It is run on each backend.
+fn conformance(file: &str, vars: &[(&str, &str)]) {
+    let file = Path::new(env!("CARGO_MANIFEST_DIR")).join(file);
+    for backend in ["closures", "bytecode"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_mal"))
+            .arg("test")
+            .arg(&file)
+            .env("MAL_BACKEND", backend)
+            .envs(vars.iter().copied())
+            .output()
+            .unwrap();
+        let report = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{} backend:\n{}", backend, report);
+    }
+}
+
+macro_rules! steps {
+    ($($step:ident)*) => {
+        $(
+            #[test]
+            fn $step() {
+                conformance(concat!("../../tests/", stringify!($step), ".mal"), &[]);
+            }
+        )*
+    };
+}
+
+// Not step9_try: of its core functions, only the ones the bundled libraries use are there
+steps!(step2_eval step3_env step4_if_fn_do step5_tco step6_file step7_quote step8_macros);
+
+macro_rules! libs {
+    ($($lib:ident $file:literal)*) => {
+        $(
+            #[test]
+            fn $lib() {
+                conformance(concat!("../../tests/lib/", $file, ".mal"), &[]);
+            }
+        )*
+    };
+}
+
+// They all start by loading load-file-once.mal, which is written with try*
+mod lib {
+    use super::conformance;
+
+    libs!(
+        alias_hacks "alias-hacks"
+        equality "equality"
+        memoize "memoize"
+        pprint "pprint"
+        reducers "reducers"
+        test_cascade "test_cascade"
+        threading "threading"
+        trivial "trivial"
+    );
+}
+
+macro_rules! features {
+    ($($(#[$attr:meta])* $feature:ident $($var:literal = $value:expr),*;)*) => {
+        $(
+            #[test]
+            $(#[$attr])*
+            fn $feature() {
+                conformance(
+                    concat!("../tests/", stringify!($feature), ".mal"),
+                    &[$(($var, $value)),*],
+                );
+            }
+        )*
+    };
+}
+
+mod features {
+    use super::conformance;
+
+    features!(
+        destructuring;
+        multi_arity;
+        loop_recur;
+        named_fn;
+        depth "MAL_MAX_DEPTH" = "200";
+        backends;
+        // Cycles are left to reference counting with threads, see gc.rs
+        #[cfg(not(feature = "threads"))]
+        gc;
+        futures;
+        channels;
+        budgets "MAL_MAX_STEPS" = "100000", "MAL_MAX_ALLOCATIONS" = "100000";
+        capabilities "MAL_CAPABILITIES" = "stdout,read=.,write=/tmp";
+        interrupts "MAL_BIN" = env!("CARGO_BIN_EXE_mal");
+        file_io;
+        streams;
+        process;
+        env "MAL_BIN" = env!("CARGO_BIN_EXE_mal");
+        cli "MAL_BIN" = env!("CARGO_BIN_EXE_mal");
+        namespaces;
+        testing "MAL_BIN" = env!("CARGO_BIN_EXE_mal");
+    );
+}
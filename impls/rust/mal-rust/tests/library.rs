@@ -0,0 +1,73 @@
+// The impls/lib libraries built into mal, required by name from a directory without
+// them, on both backends
+use std::process::{Command, Output};
+
+fn run(backend: &str, exprs: &[&str]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_mal"));
+    for expr in exprs {
+        command.arg("-e").arg(expr);
+    }
+    command
+        .env("MAL_BACKEND", backend)
+        .current_dir(std::env::temp_dir())
+        .output()
+        .unwrap()
+}
+
+fn mal(exprs: &[&str]) -> String {
+    let mut printed = vec![];
+    for backend in ["closures", "bytecode"] {
+        let output = run(backend, exprs);
+        printed.push(String::from_utf8_lossy(&output.stdout).into_owned());
+        assert!(output.status.success(), "{}", printed[printed.len() - 1]);
+    }
+    assert_eq!(printed[0], printed[1]);
+    printed.pop().unwrap()
+}
+
+#[test]
+fn bundled() {
+    assert_eq!(
+        mal(&[
+            "(require 'alias-hacks 'benchmark 'equality 'memoize 'perf 'pprint \
+               'reducers 'test_cascade 'threading 'trivial)"
+        ]),
+        "nil\n"
+    );
+}
+
+#[test]
+fn referred_and_aliased() {
+    let printed = mal(&[
+        "(require '[trivial :refer [inc zero?]] '[reducers :as r])",
+        "(zero? (inc -1))",
+        "(trivial/dec 12)",
+        "(r/foldr + 7 [])",
+    ]);
+    assert_eq!(printed, "nil\ntrue\n11\n7\n");
+}
+
+#[test]
+fn macros() {
+    let printed = mal(&[
+        "(require '[threading :refer [-> ->>]] '[equality :as eq])",
+        "(-> 5 (- 3) (list 1))",
+        "(->> 5 (- 3) (list 1))",
+        "(eq/mal-equal? {:a [1 2]} {:a '(1 2)})",
+    ]);
+    assert_eq!(printed, "nil\n(2 1)\n(1 -2)\ntrue\n");
+}
+
+#[test]
+fn unsupported() {
+    for backend in ["closures", "bytecode"] {
+        let output = run(backend, &["(require 'protocols)"]);
+        assert!(!output.status.success());
+        let reported = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            reported.starts_with("The protocols library can't be loaded: "),
+            "{}",
+            reported
+        );
+    }
+}
@@ -0,0 +1,113 @@
+// Where an embedded interpreter prints, reports errors and reads from: the writers
+// and reader of the host, or the files *out*, *err* and *in* are bound to
+use mal_rust::interpreter::{Backend, Interpreter};
+use mal_rust::printer::print_str;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Buffer {
+    fn take(&self) -> String {
+        String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+    }
+}
+
+// An interpreter on each backend, printing to out and reporting to err
+fn interpreters() -> Vec<(Interpreter, Buffer, Buffer)> {
+    vec![Backend::Closures, Backend::Bytecode]
+        .into_iter()
+        .map(|backend| {
+            let interpreter = Interpreter::new(backend);
+            let (out, err) = (Buffer::default(), Buffer::default());
+            interpreter.set_out(Some(Box::new(out.clone())));
+            interpreter.set_err(Some(Box::new(err.clone())));
+            (interpreter, out, err)
+        })
+        .collect()
+}
+
+fn eval(interpreter: &Interpreter, input: &str) -> Option<String> {
+    let value = interpreter.eval_str(input)?;
+    Some(print_str(value, false, true))
+}
+
+#[test]
+fn host_writers() {
+    for (interpreter, out, err) in interpreters() {
+        eval(&interpreter, "(prn 1 \"two\")").unwrap();
+        eval(&interpreter, "(println 1 \"two\")").unwrap();
+        eval(&interpreter, "(write *err* \"written\")").unwrap();
+        assert_eq!(out.take(), "1 \"two\"\n1 two\n");
+        assert_eq!(eval(&interpreter, "(undefined)"), None);
+        assert_eq!(err.take(), "written'undefined' not found\n");
+        // with-out-str keeps what is printed from the writer
+        let printed = eval(&interpreter, "(with-out-str (println \"inner\"))");
+        assert_eq!(printed.as_deref(), Some("\"inner\\n\""));
+        assert_eq!(out.take(), "");
+    }
+}
+
+#[test]
+fn host_reader() {
+    for (interpreter, out, _) in interpreters() {
+        interpreter.set_in(Some(Box::new(io::Cursor::new("first\nsecond\n"))));
+        let read = eval(&interpreter, "(readline \"> \")");
+        assert_eq!(read.as_deref(), Some("\"first\""));
+        assert_eq!(out.take(), "> ");
+        assert_eq!(
+            eval(&interpreter, "(read-line)").as_deref(),
+            Some("\"second\"")
+        );
+        assert_eq!(
+            eval(&interpreter, "(read-line *in*)").as_deref(),
+            Some("nil")
+        );
+    }
+}
+
+#[test]
+fn rebound() {
+    let dir = std::env::temp_dir().join(format!("mal-streams-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (i, (interpreter, out, err)) in interpreters().into_iter().enumerate() {
+        let (printed, reported) = (dir.join(format!("out{}", i)), dir.join(format!("err{}", i)));
+        let open = |name, path: &std::path::Path, mode| {
+            eval(
+                &interpreter,
+                &format!("(def! {} (open {:?} {}))", name, path, mode),
+            )
+            .unwrap();
+        };
+        open("*out*", &printed, ":write");
+        open("*err*", &reported, ":write");
+        eval(&interpreter, "(prn :printed)").unwrap();
+        assert_eq!(eval(&interpreter, "(undefined)"), None);
+        eval(&interpreter, "(close *out*)").unwrap();
+        eval(&interpreter, "(close *err*)").unwrap();
+        assert_eq!(std::fs::read_to_string(&printed).unwrap(), ":printed\n");
+        assert_eq!(
+            std::fs::read_to_string(&reported).unwrap(),
+            "'undefined' not found\n"
+        );
+        assert_eq!((out.take(), err.take()), (String::new(), String::new()));
+
+        open("*in*", &printed, ":read");
+        assert_eq!(
+            eval(&interpreter, "(readline)").as_deref(),
+            Some("\":printed\"")
+        );
+        assert_eq!(eval(&interpreter, "(read-line)").as_deref(), Some("nil"));
+    }
+}
@@ -0,0 +1,96 @@
+// Interpreters embedded in a host, as the `threads` feature lets them be used from
+// other threads, and what becomes of the cycle collector with it
+use mal_rust::gc;
+use mal_rust::interpreter::{Backend, Interpreter};
+use mal_rust::printer::print_str;
+use mal_rust::shared::Rc;
+
+fn eval(interpreter: &Interpreter, input: &str) -> String {
+    print_str(interpreter.eval_str(input).unwrap(), false, true)
+}
+
+#[cfg(feature = "threads")]
+#[test]
+fn second_thread() {
+    for backend in [Backend::Closures, Backend::Bytecode] {
+        let interpreter = std::sync::Arc::new(Interpreter::new(backend));
+        eval(&interpreter, "(def! counter (atom 0))");
+        let shared = interpreter.clone();
+        let worker = std::thread::spawn(move || {
+            eval(&shared, "(def! add (fn* [n] (swap! counter + n)))");
+            eval(&shared, "(add 40)")
+        });
+        assert_eq!(worker.join().unwrap(), "40");
+        assert_eq!(eval(&interpreter, "(add 2)"), "42");
+        // Values come back from the other thread as they went
+        let value = std::thread::spawn(move || interpreter.get("counter").unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(print_str(value, false, true), "(atom 42)");
+    }
+}
+
+// Deep nesting on a thread with the default stack is an error, not an overflow
+#[test]
+fn default_stack() {
+    let deep = std::thread::spawn(|| {
+        for backend in [Backend::Closures, Backend::Bytecode] {
+            let interpreter = Interpreter::new(backend);
+            eval(
+                &interpreter,
+                "(def! f (fn* [n] (if (= n 0) 0 (+ 1 (f (- n 1))))))",
+            );
+            assert!(interpreter.eval_str("(f 100000)").is_none());
+            let nested = format!("{}1{}", "(list ".repeat(100000), ")".repeat(100000));
+            // read-string gives nil for what it can't read
+            let read = format!("(read-string {:?})", nested);
+            assert_eq!(eval(&interpreter, &read), "nil");
+            assert_eq!(eval(&interpreter, "(f 10)"), "10");
+        }
+    });
+    deep.join().unwrap();
+}
+
+// Interrupting one interpreter leaves the others running
+#[cfg(feature = "threads")]
+#[test]
+fn interrupts() {
+    let spinning = std::sync::Arc::new(Interpreter::new(Backend::Closures));
+    let counting = std::sync::Arc::new(Interpreter::new(Backend::Bytecode));
+    let interrupt = spinning.interrupter();
+    let shared = counting.clone();
+    let counter = std::thread::spawn(move || {
+        eval(
+            &shared,
+            "(loop* [i 0] (if (< i 1000000) (recur (+ i 1)) i))",
+        )
+    });
+    let shared = spinning.clone();
+    let spinner = std::thread::spawn(move || shared.eval_str("(loop* [] (recur))"));
+    // An eval clears interrupts from before it started, so keep at it until it stops
+    while !spinner.is_finished() {
+        interrupt.interrupt();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert!(spinner.join().unwrap().is_none());
+    assert_eq!(counter.join().unwrap(), "1000000");
+    assert_eq!(eval(&spinning, "(+ 1 2)"), "3");
+}
+
+// A closure kept in the env it closes over is only freed by the collector, which the
+// `threads` feature turns off: values shared between threads could change under it
+#[test]
+fn cycles() {
+    let interpreter = Interpreter::new(Backend::Closures);
+    eval(&interpreter, "(def! f (let* [g (fn* [] g)] g))");
+    let cycle = Rc::downgrade(&interpreter.get("f").unwrap());
+    eval(&interpreter, "(def! f nil)");
+    let stats = gc::collect();
+    if cfg!(feature = "threads") {
+        assert_eq!(stats.collections, 0);
+        assert!(cycle.upgrade().is_some());
+    } else {
+        assert!(stats.freed > 0);
+        assert!(cycle.upgrade().is_none());
+    }
+}